@@ -26,7 +26,8 @@ extern crate log;
 
 extern crate chrono;
 use chrono::{DateTime, Utc};
-use std::time::{Duration, UNIX_EPOCH};
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use app_crypto::{ed25519, sr25519};
 use keyring::AccountKeyring;
@@ -49,6 +50,8 @@ use sr_primitives::{
 use std::sync::mpsc::channel;
 use std::thread;
 
+use rand::RngCore;
+use sgx_crypto_helper::rsa3072::Rsa3072PubKey;
 use substrate_api_client::{
     compose_extrinsic,
     extrinsic::xt_primitives::GenericAddress,
@@ -60,15 +63,20 @@ use substratee_node_runtime::{
     substratee_registry::{Enclave, Request},
     AccountId, Event, Hash, Signature,
 };
+use substratee_ra_verify as ra_verify;
+use substratee_ra_verify::attestation_policy::{
+    AttestationPolicy, QuoteStatus, DEFAULT_MAX_REPORT_AGE,
+};
 use substratee_stf::{
-    cli::get_identifiers, ShardIdentifier, TrustedCallSigned, TrustedGetterSigned,
-    TrustedOperationSigned,
+    cli::get_identifiers, enclave_keys_hash, envelope, envelope::X25519Key, ShardIdentifier,
+    TrustedCallSigned, TrustedGetterSigned, TrustedOperationSigned,
 };
 use substratee_worker_api::Api as WorkerApi;
 
 type AccountPublic = <Signature as Verify>::Signer;
 const KEYSTORE_PATH: &str = "my_keystore";
 const PREFUNDING_AMOUNT: u128 = 1_000_000_000;
+const IAS_ROOT_CA: &[u8] = include_bytes!("../../enclave/AttestationReportSigningCACert.pem");
 
 fn main() {
     env_logger::init();
@@ -333,25 +341,29 @@ fn get_worker_api(matches: &ArgMatches<'_>) -> WorkerApi {
 fn perform_trusted_operation(matches: &ArgMatches<'_>, top: &TrustedOperationSigned) {
     match top {
         TrustedOperationSigned::call(call) => send_request(matches, call.clone()),
-        TrustedOperationSigned::get(getter) => get_state(matches, getter.clone()),
+        TrustedOperationSigned::get(getter, response_secret) => {
+            get_state(matches, getter.clone(), response_secret)
+        }
     };
 }
 
 //FIXME: even better would be if the interpretation of the getter result is left to the stf crate
 // here we assume that the getter result is a u128, but how should we now here in this crate?
-fn get_state(matches: &ArgMatches<'_>, getter: TrustedGetterSigned) {
+fn get_state(matches: &ArgMatches<'_>, getter: TrustedGetterSigned, response_secret: &X25519Key) {
     let worker_api = get_worker_api(matches);
     let (_mrenclave, shard) = get_identifiers(matches);
+    verify_worker_keys(
+        matches,
+        &worker_api,
+        &worker_api.get_rsa_pubkey().unwrap(),
+        &worker_api.get_x25519_pubkey().unwrap(),
+    );
     debug!("calling workerapi to get state value");
     let ret = worker_api
-        .get_stf_state(getter, &shard)
+        .get_stf_state(getter, response_secret, &shard)
         .expect("getting value failed");
-    let ret_cropped = &ret[..9 * 2];
-    debug!(
-        "got getter response from worker: {:?}\ncropping to {:?}",
-        ret, ret_cropped
-    );
-    let valopt: Option<Vec<u8>> = Decode::decode(&mut &ret_cropped[..]).unwrap();
+    debug!("got getter response from worker: {:?}", ret);
+    let valopt: Option<Vec<u8>> = Decode::decode(&mut ret.as_slice()).unwrap();
     match valopt {
         Some(v) => {
             let value = U256::from_little_endian(&v);
@@ -360,16 +372,127 @@ fn get_state(matches: &ArgMatches<'_>, getter: TrustedGetterSigned) {
         _ => error!("error getting value"),
     };
 }
+fn shield_call(matches: &ArgMatches<'_>, worker_api: &WorkerApi, call_encoded: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let rsa_pubkey = worker_api.get_rsa_pubkey().unwrap();
+    let x25519_pubkey = worker_api.get_x25519_pubkey().unwrap();
+    verify_worker_keys(matches, worker_api, &rsa_pubkey, &x25519_pubkey);
+
+    match matches.value_of("shielding-key") {
+        Some("rsa") => {
+            let mut key = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut key);
+            envelope::encrypt(call_encoded, key, nonce, |key| {
+                let mut wrapped = Vec::new();
+                rsa_pubkey
+                    .encrypt_buffer(key, &mut wrapped)
+                    .map(|_| wrapped)
+                    .map_err(|_| ())
+            })
+            .unwrap()
+        }
+        _ => {
+            let mut ephemeral_secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut ephemeral_secret);
+            envelope::encrypt_x25519(call_encoded, &x25519_pubkey, ephemeral_secret, nonce).unwrap()
+        }
+    }
+}
+
+/// Checks the attestation of the worker and that it hands out the keys its enclave has been
+/// attested with, unless `--insecure` is given. The chain signer is taken from the worker's
+/// entry in the enclave registry.
+fn verify_worker_keys(
+    matches: &ArgMatches<'_>,
+    worker_api: &WorkerApi,
+    rsa_pubkey: &Rsa3072PubKey,
+    x25519_pubkey: &X25519Key,
+) {
+    if matches.is_present("insecure") {
+        warn!("[!] --insecure: the attestation of the worker is not verified");
+        return;
+    }
+    let (mrenclave, _shard) = get_identifiers(matches);
+    let worker_url = format!(
+        "{}:{}",
+        matches.value_of("worker-url").unwrap(),
+        matches.value_of("worker-port").unwrap()
+    );
+    let api = get_chain_api(matches);
+    let enclave = (1..=get_enclave_count(&api))
+        .filter_map(|i| get_enclave(&api, i))
+        .find(|e| e.url == worker_url.as_bytes())
+        .unwrap_or_else(|| panic!("worker {} is not registered on chain", worker_url));
+
+    let cert = worker_api
+        .get_ra_cert()
+        .expect("could not get the RA certificate of the worker");
+    let ias_root_ca = match matches.value_of("ias-root-ca") {
+        Some(path) => fs::read(path).expect("can't read the IAS root CA"),
+        None => IAS_ROOT_CA.to_vec(),
+    };
+    let ias_root_ca = ra_verify::dcap::pem_certificates(&ias_root_ca)
+        .into_iter()
+        .next()
+        .expect("the IAS root CA is not a PEM certificate");
+    let dcap_root_ca = matches
+        .value_of("dcap-root-ca")
+        .map(|path| fs::read(path).expect("can't read the DCAP root CA"));
+    let roots = ra_verify::RootCerts {
+        ias: &ias_root_ca,
+        dcap: dcap_root_ca.as_ref().map(|c| c.as_slice()),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let policy = AttestationPolicy {
+        accepted_statuses: matches
+            .values_of("accept-status")
+            .map(|values| {
+                values
+                    .flat_map(|s| QuoteStatus::from_ias(s).unwrap())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        max_report_age: matches
+            .value_of("max-report-age")
+            .map(|secs| {
+                secs.parse()
+                    .expect("report age must be a number of seconds")
+            })
+            .unwrap_or(DEFAULT_MAX_REPORT_AGE),
+        allow_debug: matches.is_present("allow-debug"),
+    };
+    let attested = ra_verify::verify(&cert, &roots, &policy, now)
+        .unwrap_or_else(|e| panic!("worker is not attested: {:?}", e));
+    if attested.mr_enclave != mrenclave {
+        panic!(
+            "worker runs enclave {}, not the expected {}",
+            attested.mr_enclave.to_base58(),
+            mrenclave.to_base58()
+        );
+    }
+    if attested.mr_enclave[..] != enclave.mr_enclave[..] {
+        panic!("RA certificate of the worker is not from the registered enclave");
+    }
+
+    let keys_hash = enclave_keys_hash(
+        enclave.pubkey.as_ref(),
+        &serde_json::to_vec(rsa_pubkey).unwrap(),
+        x25519_pubkey,
+    );
+    ra_verify::verify_key_binding(&cert, keys_hash.as_fixed_bytes())
+        .unwrap_or_else(|e| panic!("worker keys don't match its attestation: {:?}", e));
+    info!("[+] worker is attested and its keys are bound to the attestation report");
+}
+
 fn send_request(matches: &ArgMatches<'_>, call: TrustedCallSigned) {
     let chain_api = get_chain_api(matches);
     let worker_api = get_worker_api(matches);
-    let shielding_pubkey = worker_api.get_rsa_pubkey().unwrap();
-
-    let call_encoded = call.encode();
-    let mut call_encrypted: Vec<u8> = Vec::new();
-    shielding_pubkey
-        .encrypt_buffer(&call_encoded, &mut call_encrypted)
-        .unwrap();
+    let call_encrypted = shield_call(matches, &worker_api, &call.encode());
 
     let arg_signer = matches.value_of("xt-signer").unwrap();
     let signer = get_pair_from_str(arg_signer);
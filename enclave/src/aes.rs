@@ -15,16 +15,30 @@
 
 */
 
+use std::fs;
+use std::path::Path;
+use std::string::String;
 use std::vec::Vec;
 
 use sgx_rand::{Rng, StdRng};
+use sgx_tcrypto::{
+    rsgx_hmac_sha256_slice, rsgx_rijndael128GCM_decrypt, rsgx_rijndael128GCM_encrypt,
+    rsgx_sha256_slice,
+};
 use sgx_types::*;
 
 use aes::Aes128;
+use base58::ToBase58;
+use codec::Encode;
+use log::*;
 use ofb::stream_cipher::{NewStreamCipher, SyncStreamCipher};
 use ofb::Ofb;
+use substratee_stf::ShardIdentifier;
 
-use crate::constants::AES_KEY_FILE_AND_INIT_V;
+use crate::constants::{
+    AES_KEY_FILE_AND_INIT_V, AES_KEY_NEXT_FILE, AES_ROTATION_COMMIT_FILE, SEALED_SHARD_KEY_FILE,
+    SHARDS_PATH,
+};
 use crate::io;
 use crate::utils::UnwrapOrSgxErrorUnexpected;
 
@@ -32,9 +46,23 @@ type AesOfb = Ofb<Aes128>;
 
 pub type Aes = (Vec<u8>, Vec<u8>);
 
+/// Prefixes every cyphertext such that it can be told apart from the AES-OFB cyphertexts of
+/// earlier releases, which have no header.
+const CYPHERTEXT_MAGIC: &[u8] = b"sTEE";
+/// AES-128-GCM, `nonce || cyphertext || mac`
+const CYPHERTEXT_VERSION: u8 = 1;
+
+// domain separation for the shard keys derived from the master secret
+const SHARD_KEY_DERIVATION_SALT: &[u8] = b"substraTEE-shard-state-key";
+
 pub fn read_or_create_sealed() -> SgxResult<Aes> {
     match read_sealed() {
         Ok((k, i)) => Ok((k, i)),
+        Err(e) if Path::new(AES_KEY_NEXT_FILE).exists() => {
+            // never replace the master secret while a rotation still depends on it
+            error!("[Enclave] Master secret unreadable during a pending key rotation");
+            Err(e)
+        }
         Err(_) => {
             create_sealed()?;
             read_sealed()
@@ -43,16 +71,24 @@ pub fn read_or_create_sealed() -> SgxResult<Aes> {
 }
 
 pub fn read_sealed() -> SgxResult<Aes> {
-    io::unseal(AES_KEY_FILE_AND_INIT_V).map(|aes| (aes[..16].to_vec(), aes[16..].to_vec()))
+    io::unseal(AES_KEY_FILE_AND_INIT_V).map(split_key_iv)
 }
 
-pub fn seal(key: [u8; 16], iv: [u8; 16]) -> SgxResult<sgx_status_t> {
-    let mut key_iv = key.to_vec();
-    key_iv.extend_from_slice(&iv);
-    io::seal(&key_iv, AES_KEY_FILE_AND_INIT_V)
+pub fn split_key_iv(key_iv: Vec<u8>) -> Aes {
+    (key_iv[..16].to_vec(), key_iv[16..].to_vec())
+}
+
+pub fn join_key_iv(aes: &Aes) -> Vec<u8> {
+    let mut key_iv = aes.0.clone();
+    key_iv.extend_from_slice(&aes.1);
+    key_iv
 }
 
 pub fn create_sealed() -> SgxResult<sgx_status_t> {
+    create_sealed_at(AES_KEY_FILE_AND_INIT_V)
+}
+
+fn create_sealed_at(filepath: &str) -> SgxResult<sgx_status_t> {
     let mut key_iv = [0u8; 32];
 
     let mut rand = match StdRng::new() {
@@ -63,14 +99,202 @@ pub fn create_sealed() -> SgxResult<sgx_status_t> {
     };
 
     rand.fill_bytes(&mut key_iv);
-    io::seal(&key_iv, AES_KEY_FILE_AND_INIT_V)
+    io::seal(&key_iv, filepath)
+}
+
+/// The master secret a running key rotation migrates to. Created on first use, such that an
+/// interrupted rotation continues with the same secret.
+pub fn read_or_create_sealed_next() -> SgxResult<Aes> {
+    if !Path::new(AES_KEY_NEXT_FILE).exists() {
+        info!("[Enclave] Generating new master secret for state key rotation");
+        create_sealed_at(AES_KEY_NEXT_FILE)?;
+    }
+    io::unseal(AES_KEY_NEXT_FILE).map(split_key_iv)
+}
+
+/// From here on, all shards have been re-encrypted and the rotation can only be completed,
+/// not aborted. The commit names the hash of the next master secret it is for.
+pub fn commit_next() -> SgxResult<sgx_status_t> {
+    let next = io::unseal(AES_KEY_NEXT_FILE)?;
+    io::seal(&rsgx_sha256_slice(&next)?, AES_ROTATION_COMMIT_FILE)
+}
+
+/// Whether the rotation to the sealed next master secret has been committed. A commit the
+/// enclave can't unseal, or one for another secret, doesn't count.
+pub fn rotation_committed() -> bool {
+    if !Path::new(AES_ROTATION_COMMIT_FILE).exists() || !Path::new(AES_KEY_NEXT_FILE).exists() {
+        return false;
+    }
+    match (
+        io::unseal(AES_ROTATION_COMMIT_FILE),
+        io::unseal(AES_KEY_NEXT_FILE).and_then(|next| rsgx_sha256_slice(&next)),
+    ) {
+        (Ok(committed), Ok(next)) if committed[..] == next[..] => true,
+        _ => {
+            warn!("[Enclave] Ignoring a state key rotation commit that doesn't match");
+            false
+        }
+    }
+}
+
+/// Installs the next master secret as the current one. Idempotent, so it may be repeated
+/// after a crash.
+pub fn seal_next_as_current() -> SgxResult<sgx_status_t> {
+    let next = io::unseal(AES_KEY_NEXT_FILE)?;
+    io::seal(&next, AES_KEY_FILE_AND_INIT_V)
+}
+
+/// Ends a rotation once every shard has been migrated.
+pub fn remove_rotation_keys() -> SgxResult<()> {
+    // the commit has to go first. A leftover next key alone is harmless
+    fs::remove_file(AES_ROTATION_COMMIT_FILE)
+        .sgx_error_with_log("[Enclave] Removing the state key rotation commit failed")?;
+    fs::remove_file(AES_KEY_NEXT_FILE)
+        .sgx_error_with_log("[Enclave] Removing the next master secret failed")
+}
+
+/// Returns the state encryption key of `shard`.
+///
+/// A key that has been provisioned by another worker takes precedence. Otherwise the key is
+/// derived from the sealed master secret, which never leaves the enclave.
+pub fn read_or_derive_shard_key(shard: &ShardIdentifier) -> SgxResult<Aes> {
+    let path = shard_key_path(shard);
+    if Path::new(&path).exists() {
+        return io::unseal(&path).map(split_key_iv);
+    }
+    derive_shard_key(shard)
+}
+
+pub fn derive_shard_key(shard: &ShardIdentifier) -> SgxResult<Aes> {
+    derive_shard_key_from(&read_or_create_sealed()?, shard)
+}
+
+/// HKDF-SHA256 over the master secret with the shard identifier as info
+pub fn derive_shard_key_from(master: &Aes, shard: &ShardIdentifier) -> SgxResult<Aes> {
+    let okm = hkdf_sha256(
+        SHARD_KEY_DERIVATION_SALT,
+        &join_key_iv(master),
+        shard.as_bytes(),
+        32,
+    )?;
+    Ok(split_key_iv(okm))
 }
 
-/// If AES acts on the encrypted data it decrypts and vice versa
-pub fn de_or_encrypt(bytes: &mut Vec<u8>) -> SgxResult<()> {
-    read_or_create_sealed()
-        .map(|(key, iv)| AesOfb::new_var(&key, &iv))
-        .sgx_error_with_log("    [Enclave]  Failed to Initialize AES")?
-        .map(|mut ofb| ofb.apply_keystream(bytes))
-        .sgx_error_with_log("    [Enclave] Failed to AES en-/decrypt")
+/// Seals a key that has been provisioned for `shard`.
+pub fn seal_shard_key(shard: &ShardIdentifier, aes: &Aes) -> SgxResult<sgx_status_t> {
+    let path = shard_key_path(shard);
+    fs::create_dir_all(shard_dir(shard))
+        .sgx_error_with_log(&format!("[Enclave] Creating dir for '{}' failed", path))?;
+
+    debug!("sealing provisioned key for shard at {}", path);
+    io::seal(&join_key_iv(aes), &path)
+}
+
+/// Falls back to the key derived from the master secret.
+pub fn remove_shard_key(shard: &ShardIdentifier) -> SgxResult<()> {
+    let path = shard_key_path(shard);
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    fs::remove_file(&path).sgx_error_with_log(&format!("[Enclave] Removing '{}' failed", path))
+}
+
+fn shard_dir(shard: &ShardIdentifier) -> String {
+    format!("{}/{}", SHARDS_PATH, shard.encode().to_base58())
+}
+
+fn shard_key_path(shard: &ShardIdentifier) -> String {
+    format!("{}/{}", shard_dir(shard), SEALED_SHARD_KEY_FILE)
+}
+
+/// RFC 5869 extract-and-expand
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> SgxResult<Vec<u8>> {
+    let prk = rsgx_hmac_sha256_slice(salt, ikm)?;
+
+    let mut okm = Vec::with_capacity(len);
+    let mut t: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while okm.len() < len {
+        t.extend_from_slice(info);
+        t.push(counter);
+        t = rsgx_hmac_sha256_slice(&prk, &t)?.to_vec();
+        okm.extend_from_slice(&t);
+        counter += 1;
+    }
+    okm.truncate(len);
+    Ok(okm)
+}
+
+/// Whether `bytes` carry the header of the current cyphertext format
+pub fn has_header(bytes: &[u8]) -> bool {
+    bytes.len() > CYPHERTEXT_MAGIC.len()
+        && bytes.starts_with(CYPHERTEXT_MAGIC)
+        && bytes[CYPHERTEXT_MAGIC.len()] == CYPHERTEXT_VERSION
+}
+
+/// Decrypts a state of an earlier release, which has been encrypted with AES-128-OFB under
+/// the master secret itself. Unauthenticated, so only use it to migrate.
+pub fn decrypt_legacy(master: &Aes, bytes: &mut Vec<u8>) -> SgxResult<()> {
+    AesOfb::new_var(&master.0, &master.1)
+        .sgx_error_with_log("[Enclave] Failed to initialize AES-OFB")?
+        .apply_keystream(bytes);
+    Ok(())
+}
+
+/// AES-128-GCM under a fresh random nonce. Returns `header || nonce || cyphertext || mac`.
+pub fn encrypt(aes: &Aes, plaintext: &[u8]) -> SgxResult<Vec<u8>> {
+    let mut nonce = [0u8; SGX_AESGCM_IV_SIZE];
+    StdRng::new()
+        .sgx_error_with_log("[Enclave] Failed to initialize RNG")?
+        .fill_bytes(&mut nonce);
+
+    let mut cyphertext = vec![0u8; plaintext.len()];
+    let mut mac = [0u8; SGX_AESGCM_MAC_SIZE];
+    rsgx_rijndael128GCM_encrypt(
+        &gcm_key(aes)?,
+        plaintext,
+        &nonce,
+        &[],
+        &mut cyphertext,
+        &mut mac,
+    )?;
+
+    let mut bytes = CYPHERTEXT_MAGIC.to_vec();
+    bytes.push(CYPHERTEXT_VERSION);
+    bytes.extend_from_slice(&nonce);
+    bytes.append(&mut cyphertext);
+    bytes.extend_from_slice(&mac);
+    Ok(bytes)
+}
+
+/// Inverse of [`encrypt`]. Fails if the data has been tampered with or was encrypted under
+/// another key.
+pub fn decrypt(aes: &Aes, bytes: &[u8]) -> SgxResult<Vec<u8>> {
+    if !has_header(bytes) {
+        error!("[Enclave] Cyphertext of an unknown format");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let bytes = &bytes[CYPHERTEXT_MAGIC.len() + 1..];
+    if bytes.len() < SGX_AESGCM_IV_SIZE + SGX_AESGCM_MAC_SIZE {
+        error!("[Enclave] Cyphertext too short: {}B", bytes.len());
+        return Err(sgx_status_t::SGX_ERROR_MAC_MISMATCH);
+    }
+    let (nonce, rest) = bytes.split_at(SGX_AESGCM_IV_SIZE);
+    let (cyphertext, mac) = rest.split_at(rest.len() - SGX_AESGCM_MAC_SIZE);
+    let mut tag = [0u8; SGX_AESGCM_MAC_SIZE];
+    tag.copy_from_slice(mac);
+
+    let mut plaintext = vec![0u8; cyphertext.len()];
+    rsgx_rijndael128GCM_decrypt(&gcm_key(aes)?, cyphertext, nonce, &[], &tag, &mut plaintext)?;
+    Ok(plaintext)
+}
+
+fn gcm_key(aes: &Aes) -> SgxResult<sgx_aes_gcm_128bit_key_t> {
+    if aes.0.len() != SGX_AESGCM_KEY_SIZE {
+        error!("[Enclave] Invalid AES key length: {}B", aes.0.len());
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let mut key = [0u8; SGX_AESGCM_KEY_SIZE];
+    key.copy_from_slice(&aes.0);
+    Ok(key)
 }
@@ -41,20 +41,28 @@ use sgx_tcrypto::*;
 use sgx_tse::*;
 use sgx_types::*;
 
+#[cfg(feature = "dcap")]
+use codec::Decode;
 use codec::Encode;
 use core::default::Default;
 use itertools::Itertools;
 use log::*;
 use primitives::Pair;
 use substrate_api_client::compose_extrinsic_offline;
+use substratee_stf::{enclave_keys_hash, EnclaveCredentials};
 
 use crate::constants::{
-    RA_API_KEY_FILE, RA_DUMP_CERT_DER_FILE, RA_DUMP_SIGNER_ATTN_FILE, RA_SPID_FILE,
+    RA_API_KEY_FILE, RA_CERT_FILE, RA_DUMP_CERT_DER_FILE, RA_DUMP_SIGNER_ATTN_FILE, RA_SPID_FILE,
     REGISTER_ENCLAVE, RUNTIME_SPEC_VERSION, SUBSRATEE_REGISTRY_MODULE,
 };
+#[cfg(feature = "dcap")]
+use crate::dcap;
 use crate::ed25519;
 use crate::io;
+use crate::ra_cache::{self, CachedRa};
+use crate::rsa3072;
 use crate::utils::{hash_from_slice, write_slice_and_whitespace_pad, UnwrapOrSgxErrorUnexpected};
+use crate::x25519;
 use crate::{cert, hex};
 
 pub const DEV_HOSTNAME: &str = "api.trustedservices.intel.com";
@@ -91,6 +99,29 @@ extern "C" {
     ) -> sgx_status_t;
 }
 
+#[cfg(feature = "dcap")]
+extern "C" {
+    pub fn ocall_get_qe_target_info(
+        ret_val: *mut sgx_status_t,
+        ret_ti: *mut sgx_target_info_t,
+    ) -> sgx_status_t;
+    pub fn ocall_get_dcap_quote(
+        ret_val: *mut sgx_status_t,
+        p_report: *const sgx_report_t,
+        p_quote: *mut u8,
+        maxlen: u32,
+        p_quote_len: *mut u32,
+    ) -> sgx_status_t;
+    pub fn ocall_get_dcap_collateral(
+        ret_val: *mut sgx_status_t,
+        p_quote: *const u8,
+        quote_len: u32,
+        p_collateral: *mut u8,
+        maxlen: u32,
+        p_collateral_len: *mut u32,
+    ) -> sgx_status_t;
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn get_mrenclave(mrenclave: *mut u8, mrenclave_size: u32) -> sgx_status_t {
     let mrenclave_slice = slice::from_raw_parts_mut(mrenclave, mrenclave_size as usize);
@@ -256,6 +287,7 @@ fn parse_response_sigrl(resp: &[u8]) -> SgxResult<Vec<u8>> {
     Ok(Vec::new())
 }
 
+#[cfg(not(feature = "mock-ra"))]
 pub fn make_ias_client_config() -> rustls::ClientConfig {
     let mut config = rustls::ClientConfig::new();
 
@@ -265,6 +297,18 @@ pub fn make_ias_client_config() -> rustls::ClientConfig {
     config
 }
 
+/// The mock IAS presents a certificate of its test CA for the IAS hostname.
+#[cfg(feature = "mock-ra")]
+pub fn make_ias_client_config() -> rustls::ClientConfig {
+    let mut config = rustls::ClientConfig::new();
+
+    config
+        .root_store
+        .add_pem_file(&mut std::io::BufReader::new(cert::IAS_REPORT_CA))
+        .expect("Failed to add the mock IAS CA");
+    config
+}
+
 pub fn get_sigrl_from_intel(fd: c_int, gid: u32) -> SgxResult<Vec<u8>> {
     debug!("    [Enclave] Entering get_sigrl_from_intel. fd = {:?}", fd);
     let config = make_ias_client_config();
@@ -347,6 +391,21 @@ fn as_u32_le(array: [u8; 4]) -> u32 {
 }
 
 #[allow(const_err)]
+/// Binds the TLS key of the RA certificate, the chain signer and the shielding keys to the quote.
+fn report_data(pub_k: &sgx_ec256_public_t) -> SgxResult<sgx_report_data_t> {
+    let mut report_data: sgx_report_data_t = sgx_report_data_t::default();
+    report_data.d[..32].clone_from_slice(&cert::report_data_key_hash(pub_k)?);
+    report_data.d[32..].clone_from_slice(&keys_hash()?);
+    Ok(report_data)
+}
+
+fn keys_hash() -> SgxResult<[u8; 32]> {
+    let signer = ed25519::unseal_pair()?.public();
+    let rsa_pubkey_json = serde_json::to_vec(&rsa3072::unseal_pubkey()?).sgx_error()?;
+    let keys_hash = enclave_keys_hash(&signer.0, &rsa_pubkey_json, &x25519::unseal_pubkey()?);
+    Ok(keys_hash.into())
+}
+
 pub fn create_attestation_report(
     pub_k: &sgx_ec256_public_t,
     sign_type: sgx_quote_sign_type_t,
@@ -402,16 +461,8 @@ pub fn create_attestation_report(
     let sigrl_vec: Vec<u8> = get_sigrl_from_intel(ias_sock, eg_num)?;
 
     // (2) Generate the report
-    // Fill ecc256 public key into report_data
-
-    /* Code von Baidu */
-    let mut report_data: sgx_report_data_t = sgx_report_data_t::default();
-    let mut pub_k_gx = pub_k.gx;
-    pub_k_gx.reverse();
-    let mut pub_k_gy = pub_k.gy;
-    pub_k_gy.reverse();
-    report_data.d[..32].clone_from_slice(&pub_k_gx);
-    report_data.d[32..].clone_from_slice(&pub_k_gy);
+    // report_data = sha256(ecc256 public key of the RA certificate) || enclave keys hash
+    let report_data = report_data(pub_k)?;
 
     let rep = match rsgx_create_report(&ti, &report_data) {
         Ok(r) => {
@@ -586,18 +637,180 @@ pub fn create_attestation_report(
     //TODO: return context as well
 }
 
+/// Creates a DCAP quote and fetches the collateral to verify it, encoded as RA certificate
+/// payload.
+#[cfg(feature = "dcap")]
+pub fn create_dcap_evidence(pub_k: &sgx_ec256_public_t) -> SgxResult<String> {
+    const RET_QUOTE_BUF_LEN: u32 = 8192;
+    const RET_COLLATERAL_BUF_LEN: u32 = 65536;
+
+    let mut ti: sgx_target_info_t = sgx_target_info_t::default();
+    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
+    let res = unsafe { ocall_get_qe_target_info(&mut rt as *mut sgx_status_t, &mut ti as *mut _) };
+    if res != sgx_status_t::SGX_SUCCESS {
+        return Err(res);
+    }
+    if rt != sgx_status_t::SGX_SUCCESS {
+        return Err(rt);
+    }
+
+    let rep = rsgx_create_report(&ti, &report_data(pub_k)?)
+        .sgx_error_with_log("    [Enclave] Report creation failed")?;
+
+    let mut quote = vec![0u8; RET_QUOTE_BUF_LEN as usize];
+    let mut quote_len: u32 = 0;
+    let res = unsafe {
+        ocall_get_dcap_quote(
+            &mut rt as *mut sgx_status_t,
+            &rep as *const sgx_report_t,
+            quote.as_mut_ptr(),
+            RET_QUOTE_BUF_LEN,
+            &mut quote_len as *mut u32,
+        )
+    };
+    if res != sgx_status_t::SGX_SUCCESS {
+        return Err(res);
+    }
+    if rt != sgx_status_t::SGX_SUCCESS {
+        error!("    [Enclave] ocall_get_dcap_quote failed. {}", rt);
+        return Err(rt);
+    }
+    quote.truncate(quote_len as usize);
+
+    let mut collateral = vec![0u8; RET_COLLATERAL_BUF_LEN as usize];
+    let mut collateral_len: u32 = 0;
+    let res = unsafe {
+        ocall_get_dcap_collateral(
+            &mut rt as *mut sgx_status_t,
+            quote.as_ptr(),
+            quote.len() as u32,
+            collateral.as_mut_ptr(),
+            RET_COLLATERAL_BUF_LEN,
+            &mut collateral_len as *mut u32,
+        )
+    };
+    if res != sgx_status_t::SGX_SUCCESS {
+        return Err(res);
+    }
+    if rt != sgx_status_t::SGX_SUCCESS {
+        error!("    [Enclave] ocall_get_dcap_collateral failed. {}", rt);
+        return Err(rt);
+    }
+    let (
+        tcb_info,
+        tcb_info_issuer_chain,
+        qe_identity,
+        qe_identity_issuer_chain,
+        pck_crl,
+        root_ca_crl,
+    ) = <(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)>::decode(
+        &mut collateral.get(..collateral_len as usize).sgx_error()?,
+    )
+    .sgx_error_with_log("    [Enclave] Malformed DCAP collateral")?;
+
+    let collateral = dcap::Collateral {
+        tcb_info,
+        tcb_info_issuer_chain,
+        qe_identity,
+        qe_identity_issuer_chain,
+        pck_crl,
+        root_ca_crl,
+    };
+    Ok(dcap::encode_payload(&quote, &collateral))
+}
+
+/// The attestation evidence for the RA certificate of `pub_k`.
+#[cfg(not(feature = "dcap"))]
+fn attestation_payload(
+    pub_k: &sgx_ec256_public_t,
+    sign_type: sgx_quote_sign_type_t,
+) -> SgxResult<String> {
+    let (attn_report, sig, cert) = create_attestation_report(pub_k, sign_type)?;
+    debug!("              attn_report = {:?}", attn_report);
+    debug!("              sig         = {:?}", sig);
+    debug!("              cert        = {:?}", cert);
+    Ok(attn_report + "|" + &sig + "|" + &cert)
+}
+
+/// The attestation evidence for the RA certificate of `pub_k`.
+#[cfg(feature = "dcap")]
+fn attestation_payload(
+    pub_k: &sgx_ec256_public_t,
+    _sign_type: sgx_quote_sign_type_t,
+) -> SgxResult<String> {
+    create_dcap_evidence(pub_k)
+}
+
 fn load_spid(filename: &str) -> SgxResult<sgx_spid_t> {
+    // the mock IAS needs no subscription
+    if cfg!(feature = "mock-ra") {
+        return Ok(sgx_spid_t::default());
+    }
     io::read_to_string(filename)
         .map(|contents| hex::decode_spid(&contents))
         .sgx_error()?
 }
 
 fn get_ias_api_key() -> SgxResult<String> {
+    if cfg!(feature = "mock-ra") {
+        return Ok(String::new());
+    }
     io::read_to_string(RA_API_KEY_FILE).map(|key| key.trim_end().to_owned())
 }
 
+/// The RA certificate with its key and the chain signer attestation, reused from the sealed cache
+/// while it is recent enough, see `ra_cache`.
 pub fn create_ra_report_and_signature(
     sign_type: sgx_quote_sign_type_t,
+) -> SgxResult<(Vec<u8>, Vec<u8>, [u32; 16])> {
+    let keys_hash = keys_hash()?;
+    let max_report_age = cert::attestation_policy()?.max_report_age;
+    let _lock = ra_cache::lock();
+    if let Some(cached) = ra_cache::unseal() {
+        if cached.is_reusable(&keys_hash, sign_type, max_report_age, ra_cache::now()) {
+            info!("    [Enclave] Reusing the cached RA certificate");
+            return Ok((
+                cached.key_der,
+                cached.cert_der,
+                cached.chain_signer_attestation,
+            ));
+        }
+    }
+    refresh_ra_report_and_signature(sign_type, keys_hash)
+}
+
+/// Our RA certificate together with the keys it attests, for peers that can't run MU-RA with us.
+pub fn own_credentials(sign_type: sgx_quote_sign_type_t) -> SgxResult<EnclaveCredentials> {
+    let (_key_der, ra_cert, _) = create_ra_report_and_signature(sign_type)?;
+    Ok(EnclaveCredentials {
+        ra_cert,
+        signer: ed25519::unseal_pair()?.public(),
+        rsa_pubkey_json: serde_json::to_vec(&rsa3072::unseal_pubkey()?).sgx_error()?,
+        x25519_pubkey: x25519::unseal_pubkey()?,
+    })
+}
+
+/// Attests anew and caches the result.
+fn refresh_ra_report_and_signature(
+    sign_type: sgx_quote_sign_type_t,
+    keys_hash: [u8; 32],
+) -> SgxResult<(Vec<u8>, Vec<u8>, [u32; 16])> {
+    let created_at = ra_cache::now();
+    let (key_der, cert_der, chain_signer_attestation) =
+        generate_ra_report_and_signature(sign_type)?;
+    ra_cache::seal(&CachedRa {
+        key_der: key_der.clone(),
+        cert_der: cert_der.clone(),
+        chain_signer_attestation,
+        keys_hash,
+        sign_type: sign_type as u32,
+        created_at,
+    })?;
+    Ok((key_der, cert_der, chain_signer_attestation))
+}
+
+fn generate_ra_report_and_signature(
+    sign_type: sgx_quote_sign_type_t,
 ) -> SgxResult<(Vec<u8>, Vec<u8>, [u32; 16])> {
     let chain_signer = ed25519::unseal_pair()?;
     info!(
@@ -612,7 +825,7 @@ pub fn create_ra_report_and_signature(
     info!("    [Enclave] Generate keypair successful");
 
     info!("    [Enclave] Create attestation report");
-    let (attn_report, sig, cert) = match create_attestation_report(&pub_k, sign_type) {
+    let payload = match attestation_payload(&pub_k, sign_type) {
         Ok(r) => r,
         Err(e) => {
             error!("    [Enclave] Error in create_attestation_report: {:?}", e);
@@ -620,12 +833,6 @@ pub fn create_ra_report_and_signature(
         }
     };
     println!("    [Enclave] Create attestation report successful");
-    debug!("              attn_report = {:?}", attn_report);
-    debug!("              sig         = {:?}", sig);
-    debug!("              cert        = {:?}", cert);
-
-    // concat the information
-    let payload = attn_report + "|" + &sig + "|" + &cert;
 
     // generate an ECC certificate
     info!("    [Enclave] Generate ECC Certificate");
@@ -667,6 +874,11 @@ pub unsafe extern "C" fn perform_ra(
         Err(e) => return e,
     };
 
+    // the worker serves the certificate to clients that want to check our keys
+    if let Err(status) = io::write(&cert_der, RA_CERT_FILE) {
+        return status;
+    }
+
     info!("    [Enclave] Compose extrinsic");
     let genesis_hash_slice = slice::from_raw_parts(genesis_hash, genesis_hash_size as usize);
     //let mut nonce_slice     = slice::from_raw_parts(nonce, nonce_size as usize);
@@ -701,6 +913,27 @@ pub unsafe extern "C" fn perform_ra(
     sgx_status_t::SGX_SUCCESS
 }
 
+/// Replaces the cached RA certificate with one from a fresh attestation. The worker serves the
+/// new certificate and reads its quote status to decide whether to renew the registration.
+#[no_mangle]
+pub unsafe extern "C" fn refresh_attestation() -> sgx_status_t {
+    // our certificate is unlinkable
+    let sign_type = sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE;
+
+    let keys_hash = match keys_hash() {
+        Ok(hash) => hash,
+        Err(status) => return status,
+    };
+    let _lock = ra_cache::lock();
+    match refresh_ra_report_and_signature(sign_type, keys_hash) {
+        Ok((_key_der, cert_der, _)) => match io::write(&cert_der, RA_CERT_FILE) {
+            Ok(_) => sgx_status_t::SGX_SUCCESS,
+            Err(status) => status,
+        },
+        Err(status) => status,
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn dump_ra_to_disk() -> sgx_status_t {
     // our certificate is unlinkable
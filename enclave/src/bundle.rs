@@ -0,0 +1,127 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+use std::vec::Vec;
+
+use sgx_crypto_helper::rsa3072::Rsa3072PubKey;
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_types::*;
+
+use base58::ToBase58;
+use codec::{Decode, Encode};
+use log::*;
+use primitives::{crypto::Pair, ed25519, H256};
+use substratee_stf::{EnclaveCredentials, ShardBundle, ShardIdentifier};
+
+use crate::aes;
+use crate::attestation;
+use crate::cert;
+use crate::ed25519 as signer;
+use crate::hex;
+use crate::io;
+use crate::rsa3072;
+use crate::state;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+
+fn signed_payload(
+    shard: &ShardIdentifier,
+    state_hash: &H256,
+    shard_key: &[u8],
+    state: &[u8],
+) -> Vec<u8> {
+    (shard, state_hash, shard_key, state).encode()
+}
+
+pub fn export(
+    shard: &ShardIdentifier,
+    target: &EnclaveCredentials,
+    sign_type: sgx_quote_sign_type_t,
+    path: &str,
+) -> SgxResult<()> {
+    let peer = cert::verify_credentials(target)?;
+    let target_key: Rsa3072PubKey = serde_json::from_slice(&target.rsa_pubkey_json)
+        .sgx_error_with_log("[Enclave] Shard bundle: invalid shielding key of the target")?;
+
+    let (_, state) = state::read_cyphertext(shard)?;
+    let state_hash: H256 = rsgx_sha256_slice(&state)?.into();
+
+    let aes = aes::read_or_derive_shard_key(shard)?;
+    let shard_key = rsa3072::encrypt(&aes::join_key_iv(&aes), &target_key)?;
+
+    let pair = signer::unseal_pair()?;
+    let signature = pair.sign(&signed_payload(shard, &state_hash, &shard_key, &state));
+
+    let bundle = ShardBundle {
+        shard: *shard,
+        state_hash,
+        shard_key,
+        state,
+        signer: attestation::own_credentials(sign_type)?,
+        signature,
+    };
+    io::write(&bundle.encode(), path)?;
+    info!(
+        "[Enclave] Exported shard {} with state hash 0x{} for enclave {} to '{}'",
+        shard.encode().to_base58(),
+        hex::encode_hex(state_hash.as_bytes()),
+        peer.mr_enclave.to_base58(),
+        path
+    );
+    Ok(())
+}
+
+pub fn import(path: &str) -> SgxResult<ShardIdentifier> {
+    let bytes = io::read(path)?;
+    let bundle = ShardBundle::decode(&mut bytes.as_slice())
+        .sgx_error_with_log(&format!("[Enclave] '{}' is not a shard bundle", path))?;
+
+    cert::verify_credentials(&bundle.signer)?;
+    let payload = signed_payload(
+        &bundle.shard,
+        &bundle.state_hash,
+        &bundle.shard_key,
+        &bundle.state,
+    );
+    if !ed25519::Pair::verify(&bundle.signature, &payload, &bundle.signer.signer) {
+        error!("[Enclave] Shard bundle: bad signature");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_SIGNATURE);
+    }
+    if rsgx_sha256_slice(&bundle.state)? != bundle.state_hash.to_fixed_bytes() {
+        error!("[Enclave] Shard bundle: state doesn't match its hash");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let key_iv = rsa3072::decrypt(&bundle.shard_key, &rsa3072::unseal_pair()?)?;
+    if key_iv.len() != 32 {
+        error!("[Enclave] Shard bundle: invalid shard key");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let aes = aes::split_key_iv(key_iv);
+    if !bundle.state.is_empty() {
+        aes::decrypt(&aes, &bundle.state)
+            .sgx_error_with_log("[Enclave] Shard bundle: state doesn't authenticate")?;
+    }
+
+    state::write_cyphertext(&bundle.shard, &bundle.state, bundle.state_hash)?;
+    aes::seal_shard_key(&bundle.shard, &aes)?;
+    info!(
+        "[Enclave] Imported shard {} with state hash 0x{}, signed by enclave {}",
+        bundle.shard.encode().to_base58(),
+        hex::encode_hex(bundle.state_hash.as_bytes()),
+        hex::encode_hex(bundle.signer.signer.as_ref())
+    );
+    Ok(bundle.shard)
+}
@@ -1,7 +1,7 @@
 use std::prelude::v1::*;
 use std::time::*;
 use std::untrusted::time::SystemTimeEx;
-use std::{ptr, str};
+use std::{mem, ptr, str};
 
 use sgx_tcrypto::*;
 use sgx_types::*;
@@ -12,13 +12,26 @@ use chrono::prelude::*;
 use chrono::Duration;
 use chrono::TimeZone;
 use chrono::Utc as TzUtc;
+use codec::{Decode, Encode};
 use itertools::Itertools;
 use log::*;
 use num_bigint::BigUint;
 use serde_json::Value;
 use std::io::BufReader;
+use std::path::Path;
 use yasna::models::ObjectIdentifier;
 
+use substratee_stf::EnclaveCredentials;
+
+use crate::attestation::get_mrenclave_of_self;
+use crate::attestation_policy::{AttestationPolicy, QuoteStatus};
+use crate::constants::ATTESTATION_POLICY_FILE;
+use crate::counter::Counter;
+#[cfg(feature = "dcap")]
+use crate::dcap;
+use crate::io;
+use crate::provisioning_policy;
+use crate::ra_cert;
 use crate::utils::UnwrapOrSgxErrorUnexpected;
 
 extern "C" {
@@ -45,11 +58,29 @@ static SUPPORTED_SIG_ALGS: SignatureAlgorithms = &[
     &webpki::RSA_PKCS1_3072_8192_SHA384,
 ];
 
+#[cfg(not(feature = "mock-ra"))]
 pub const IAS_REPORT_CA: &[u8] = include_bytes!("../AttestationReportSigningCACert.pem");
+/// Root of the test PKI of `substratee-mock-ias`, which also issues its TLS certificate
+#[cfg(feature = "mock-ra")]
+pub const IAS_REPORT_CA: &[u8] = include_bytes!("../../substratee-mock-ias/certs/root_ca.pem");
+
+/// Intel SGX Provisioning Certification Root CA, fetched by `make` with `SGX_DCAP=1`
+#[cfg(feature = "dcap")]
+pub const DCAP_ROOT_CA: &[u8] = include_bytes!("../DcapRootCACert.der");
 
 const ISSUER: &str = "SubstraTEE";
 const SUBJECT: &str = "SubstraTEE ephemeral";
 
+/// Hash of the uncompressed public key (without the `04` prefix) as it appears in the
+/// certificate. It fills the first half of the report data.
+pub fn report_data_key_hash(pub_k: &sgx_ec256_public_t) -> SgxResult<sgx_sha256_hash_t> {
+    let mut pk_gx = pub_k.gx;
+    pk_gx.reverse();
+    let mut pk_gy = pub_k.gy;
+    pk_gy.reverse();
+    rsgx_sha256_slice(&[pk_gx, pk_gy].concat())
+}
+
 pub fn gen_ecc_cert(
     payload: String,
     prv_k: &sgx_ec256_private_t,
@@ -140,11 +171,16 @@ pub fn gen_ecc_cert(
                     .next()
                     .write_tagged(yasna::Tag::context(3), |writer| {
                         writer.write_sequence(|writer| {
+                            writer.next().write_sequence(|writer| {
+                                writer.next().write_der(ra_cert::EVIDENCE_OID);
+                                writer.next().write_bytes(payload.as_bytes());
+                            });
+                            // Netscape Comment, which the enclave registry of the node reads
                             writer.next().write_sequence(|writer| {
                                 writer.next().write_oid(&ObjectIdentifier::from_slice(&[
                                     2, 16, 840, 1, 113_730, 1, 13,
                                 ]));
-                                writer.next().write_bytes(&payload.into_bytes());
+                                writer.next().write_bytes(payload.as_bytes());
                             });
                         });
                     });
@@ -219,49 +255,102 @@ pub fn percent_decode(orig: String) -> SgxResult<String> {
     Ok(ret)
 }
 
-// FIXME: This code is redundant with the host call of the substraTEE-node
-pub fn verify_mra_cert(cert_der: &[u8]) -> Result<(), sgx_status_t> {
-    // Before we reach here, Webpki already verifed the cert is properly signed
+/// The identity of an attested peer enclave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerIdentity {
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    /// Second half of the report data, see `substratee_stf::enclave_keys_hash`.
+    pub keys_hash: [u8; 32],
+}
 
-    // Search for Public Key prime256v1 OID
-    let prime256v1_oid = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
-    let mut offset = cert_der
-        .windows(prime256v1_oid.len())
-        .position(|window| window == prime256v1_oid)
-        .sgx_error()?;
-    offset += 11; // 10 + TAG (0x03)
-
-    // Obtain Public Key length
-    let mut len = cert_der[offset] as usize;
-    if len > 0x80 {
-        len = (cert_der[offset + 1] as usize) * 0x100 + (cert_der[offset + 2] as usize);
-        offset += 2;
+/// The sealed attestation policy, or the default of accepting quote status OK only. Debug
+/// enclaves are accepted by default unless we run in production. Production enclaves never accept
+/// them, whatever the policy says.
+pub fn attestation_policy() -> SgxResult<AttestationPolicy> {
+    let mut policy = match unseal_attestation_policy()? {
+        Some((_, policy, _)) => policy,
+        None => AttestationPolicy {
+            allow_debug: true,
+            ..Default::default()
+        },
+    };
+    if cfg!(feature = "production") {
+        policy.allow_debug = false;
     }
+    Ok(policy)
+}
 
-    // Obtain Public Key
-    offset += 1;
-    let pub_k = cert_der[offset + 2..offset + len].to_vec(); // skip "00 04"
-
-    // Search for Netscape Comment OID
-    let ns_cmt_oid = &[
-        0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x86, 0xF8, 0x42, 0x01, 0x0D,
-    ];
-    let mut offset = cert_der
-        .windows(ns_cmt_oid.len())
-        .position(|window| window == ns_cmt_oid)
-        .sgx_error()?;
-    offset += 12; // 11 + TAG (0x04)
-
-    // Obtain Netscape Comment length
-    let mut len = cert_der[offset] as usize;
-    if len > 0x80 {
-        len = (cert_der[offset + 1] as usize) * 0x100 + (cert_der[offset + 2] as usize);
-        offset += 2;
+/// The version and the sealed policy, refused if the host has put back an older one.
+fn unseal_attestation_policy() -> SgxResult<Option<(u64, AttestationPolicy, Counter)>> {
+    if !Path::new(ATTESTATION_POLICY_FILE).exists() {
+        return Ok(None);
     }
+    let encoded = io::unseal(ATTESTATION_POLICY_FILE)?;
+    let (version, policy, counter) =
+        <(u64, AttestationPolicy, Counter)>::decode(&mut encoded.as_slice())
+            .sgx_error_with_log("[Enclave] Could not decode the attestation policy")?;
+    if !counter.is_current()? {
+        error!(
+            "[Enclave] The sealed attestation policy is outdated. Set the latest policy the \
+             operator has signed anew"
+        );
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(Some((version, policy, counter)))
+}
 
-    // Obtain Netscape Comment
-    offset += 1;
-    let payload = cert_der[offset..offset + len].to_vec();
+/// Seals version `version` of the attestation policy if it is newer than the sealed one, bound
+/// to a monotonic counter like the provisioning policy.
+pub fn seal_attestation_policy(
+    version: u64,
+    policy: &AttestationPolicy,
+) -> SgxResult<sgx_status_t> {
+    let sealed = unseal_attestation_policy()?;
+    let sealed_version = sealed.as_ref().map(|(v, _, _)| *v).unwrap_or_default();
+    if version <= sealed_version {
+        error!(
+            "[Enclave] Attestation policy version {} is not newer than the sealed version {}",
+            version, sealed_version
+        );
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let counter = match sealed {
+        Some((_, _, mut counter)) => {
+            counter.increment()?;
+            counter
+        }
+        None => Counter::create()?,
+    };
+    info!(
+        "[Enclave] Sealing attestation policy version {} {:?}",
+        version, policy
+    );
+    io::seal(
+        &(version, policy, counter).encode(),
+        ATTESTATION_POLICY_FILE,
+    )
+}
+
+// FIXME: This code is redundant with the host call of the substraTEE-node
+pub fn verify_mra_cert(
+    cert_der: &[u8],
+    policy: &AttestationPolicy,
+) -> Result<PeerIdentity, sgx_status_t> {
+    // Before we reach here, Webpki already verifed the cert is properly signed
+    let ra_cert = ra_cert::parse(cert_der).map_err(|e| {
+        error!("    [Enclave] Malformed RA certificate: {:?}", e);
+        sgx_status_t::SGX_ERROR_UNEXPECTED
+    })?;
+    let pub_k = ra_cert.public_key;
+    let payload = ra_cert.evidence;
+
+    #[cfg(feature = "dcap")]
+    {
+        if let Some((quote, collateral)) = dcap::decode_payload(&payload) {
+            return verify_dcap_evidence(&quote, &collateral, &pub_k, policy);
+        }
+    }
 
     // Extract each field
     let mut iter = payload.split(|x| *x == 0x7C);
@@ -271,7 +360,7 @@ pub fn verify_mra_cert(cert_der: &[u8]) -> Result<(), sgx_status_t> {
 
     let sig_cert_raw = iter.next().sgx_error()?;
     let sig_cert_dec = base64::decode_config(&sig_cert_raw, base64::STANDARD).sgx_error()?;
-    let sig_cert = webpki::EndEntityCert::from(&sig_cert_dec).expect("Bad DER");
+    let sig_cert = webpki::EndEntityCert::from(&sig_cert_dec).sgx_error_with_log("Bad DER")?;
 
     // Verify if the signing cert is issued by Intel CA
     let mut ias_ca_stripped = IAS_REPORT_CA.to_vec();
@@ -307,7 +396,10 @@ pub fn verify_mra_cert(cert_der: &[u8]) -> Result<(), sgx_status_t> {
         now_func.sgx_error()?,
     ) {
         Ok(_) => info!("Cert is good"),
-        Err(e) => error!("Cert verification error {:?}", e),
+        Err(e) => {
+            error!("Cert verification error {:?}", e);
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
     }
 
     // Verify the signature against the signing cert
@@ -315,15 +407,81 @@ pub fn verify_mra_cert(cert_der: &[u8]) -> Result<(), sgx_status_t> {
         Ok(_) => info!("Signature good"),
         Err(e) => {
             error!("Signature verification error {:?}", e);
-            panic!();
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+    }
+    verify_attn_report(attn_report_raw, pub_k, policy)
+}
+
+/// Checks that `credentials` belong to an attested enclave whose MRENCLAVE the provisioning
+/// policy allows, and that its RA certificate binds exactly these keys.
+pub fn verify_credentials(credentials: &EnclaveCredentials) -> SgxResult<PeerIdentity> {
+    let peer = verify_mra_cert(&credentials.ra_cert, &attestation_policy()?)?;
+    if peer.keys_hash != credentials.keys_hash().to_fixed_bytes() {
+        error!("[Enclave] The RA certificate does not attest the keys of the enclave");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let own = get_mrenclave_of_self()?;
+    if let Err(reason) = provisioning_policy::unseal()?.check(&peer, &own.m) {
+        error!("[Enclave] Refusing enclave: {}", reason);
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    Ok(peer)
+}
+
+#[cfg(feature = "dcap")]
+fn verify_dcap_evidence(
+    quote: &[u8],
+    collateral: &dcap::Collateral,
+    pub_k: &[u8],
+    policy: &AttestationPolicy,
+) -> Result<PeerIdentity, sgx_status_t> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .sgx_error()?
+        .as_secs();
+    let verified = match dcap::verify(quote, collateral, DCAP_ROOT_CA, now) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("DCAP quote verification failed: {:?}", e);
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
         }
+    };
+    // the freshness of DCAP evidence is bounded by the validity of its collateral
+    let accepted = policy
+        .check_status(&verified.tcb_status.quote_statuses())
+        .and_then(|_| policy.check_status(&verified.qe_tcb_status.quote_statuses()))
+        .and_then(|_| policy.check_debug(&verified.isv_report.attributes));
+    if let Err(e) = accepted {
+        error!(
+            "Peer with TCB status {:?}, QE TCB status {:?} is not accepted: {:?}",
+            verified.tcb_status, verified.qe_tcb_status, e
+        );
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+
+    let pub_k_hash = rsgx_sha256_slice(pub_k)?;
+    if verified.isv_report.report_data[..32] != pub_k_hash[..] {
+        error!("report_data does not match the public key of the certificate");
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
     }
-    verify_attn_report(attn_report_raw, pub_k)
+    info!("Mutual RA done!");
+    let mut keys_hash = [0u8; 32];
+    keys_hash.copy_from_slice(&verified.isv_report.report_data[32..]);
+    Ok(PeerIdentity {
+        mr_enclave: verified.isv_report.mr_enclave,
+        mr_signer: verified.isv_report.mr_signer,
+        keys_hash,
+    })
 }
 
-fn verify_attn_report(report_raw: &[u8], pub_k: Vec<u8>) -> Result<(), sgx_status_t> {
+fn verify_attn_report(
+    report_raw: &[u8],
+    pub_k: Vec<u8>,
+    policy: &AttestationPolicy,
+) -> Result<PeerIdentity, sgx_status_t> {
     // Verify attestation report
-    // 1. Check timestamp is within 24H (90day is recommended by Intel)
+    // 1. Check the age of the report against the policy
     let attn_report: Value = serde_json::from_slice(report_raw).sgx_error()?;
     if let Value::String(time) = &attn_report["timestamp"] {
         let time_fixed = time.clone() + "+0000";
@@ -333,8 +491,12 @@ fn verify_attn_report(report_raw: &[u8], pub_k: Vec<u8>) -> Result<(), sgx_statu
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .sgx_error()?
-            .as_secs() as i64;
-        info!("Time diff = {}", now - ts);
+            .as_secs();
+        info!("Time diff = {}", now as i64 - ts);
+        if let Err(e) = policy.check_report_age(ts.max(0) as u64, now) {
+            error!("Attestation report of the peer is not accepted: {:?}", e);
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
     } else {
         error!("Failed to fetch timestamp from attestation report");
         return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
@@ -343,52 +505,12 @@ fn verify_attn_report(report_raw: &[u8], pub_k: Vec<u8>) -> Result<(), sgx_statu
     // 2. Verify quote status (mandatory field)
     if let Value::String(quote_status) = &attn_report["isvEnclaveQuoteStatus"] {
         debug!("isvEnclaveQuoteStatus = {}", quote_status);
-        match quote_status.as_ref() {
-            "OK" => (),
-            "GROUP_OUT_OF_DATE" | "GROUP_REVOKED" | "CONFIGURATION_NEEDED" => {
-                // Verify platformInfoBlob for further info if status not OK
-                if let Value::String(pib) = &attn_report["platformInfoBlob"] {
-                    let mut buf = Vec::new();
-
-                    // the TLV Header (4 bytes/8 hexes) should be skipped
-                    let n = (pib.len() - 8) / 2;
-                    for i in 0..n {
-                        buf.push(
-                            u8::from_str_radix(&pib[(i * 2 + 8)..(i * 2 + 10)], 16).sgx_error()?,
-                        );
-                    }
-
-                    let mut update_info = sgx_update_info_bit_t::default();
-                    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
-                    let res = unsafe {
-                        ocall_get_update_info(
-                            &mut rt as *mut sgx_status_t,
-                            buf.as_slice().as_ptr() as *const sgx_platform_info_t,
-                            1,
-                            &mut update_info as *mut sgx_update_info_bit_t,
-                        )
-                    };
-                    if res != sgx_status_t::SGX_SUCCESS {
-                        error!("ocall_get_update_info failed. res={:?}", res);
-                        return Err(res);
-                    }
-
-                    if rt != sgx_status_t::SGX_SUCCESS {
-                        warn!("ocall_get_update_info unsuccessful. rt={:?}", rt);
-                        // Borrow of packed field is unsafe in future Rust releases
-                        unsafe {
-                            debug!("update_info.pswUpdate: {}", update_info.pswUpdate);
-                            debug!("update_info.csmeFwUpdate: {}", update_info.csmeFwUpdate);
-                            debug!("update_info.ucodeUpdate: {}", update_info.ucodeUpdate);
-                        }
-                        return Err(rt);
-                    }
-                } else {
-                    error!("Failed to fetch platformInfoBlob from attestation report");
-                    return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
-                }
-            }
-            _ => return Err(sgx_status_t::SGX_ERROR_UNEXPECTED),
+        if let Err(e) = QuoteStatus::from_ias(quote_status).and_then(|s| policy.check_status(&s)) {
+            error!("Quote status of the peer is not accepted: {:?}", e);
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+        if quote_status != "OK" {
+            log_platform_update_info(&attn_report)?;
         }
     } else {
         error!("Failed to fetch isvEnclaveQuoteStatus from attestation report");
@@ -399,15 +521,20 @@ fn verify_attn_report(report_raw: &[u8], pub_k: Vec<u8>) -> Result<(), sgx_statu
     if let Value::String(quote_raw) = &attn_report["isvEnclaveQuoteBody"] {
         let quote = base64::decode(&quote_raw).sgx_error()?;
         debug!("Quote = {:?}", quote);
-        // TODO: lack security check here
-        let sgx_quote: sgx_quote_t = unsafe { ptr::read(quote.as_ptr() as *const _) };
-
-        let ti: sgx_target_info_t = sgx_target_info_t::default();
-
-        if sgx_quote.report_body.mr_enclave.m != ti.mr_enclave.m {
-            error!("mr_enclave is not equal to self");
+        // IAS leaves out the signature and its length, so the body is shorter than an
+        // `sgx_quote_t`
+        let body_size = mem::size_of::<sgx_quote_t>() - mem::size_of::<u32>();
+        if quote.len() < body_size {
+            error!(
+                "Quote body of {} B is shorter than the {} B of an sgx_quote_t body",
+                quote.len(),
+                body_size
+            );
             return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
         }
+        let mut padded = vec![0u8; mem::size_of::<sgx_quote_t>()];
+        padded[..body_size].copy_from_slice(&quote[..body_size]);
+        let sgx_quote: sgx_quote_t = unsafe { ptr::read(padded.as_ptr() as *const _) };
 
         // Borrow of packed field is unsafe in future Rust releases
         // ATTENTION
@@ -431,13 +558,101 @@ fn verify_attn_report(report_raw: &[u8], pub_k: Vec<u8>) -> Result<(), sgx_statu
             );
         }
         debug!("Anticipated public key = {:02x}", pub_k.iter().format(""));
-        if sgx_quote.report_body.report_data.d.to_vec() == pub_k.to_vec() {
-            info!("Mutual RA done!");
+        let pub_k_hash = rsgx_sha256_slice(&pub_k[..])?;
+        if sgx_quote.report_body.report_data.d[..32] != pub_k_hash[..] {
+            error!("report_data does not match the public key of the certificate");
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
         }
+        let flags = sgx_quote.report_body.attributes.flags;
+        if let Err(e) = policy.check_debug(&flags.to_le_bytes()) {
+            error!("Peer is not accepted: {:?}", e);
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+        info!("Mutual RA done!");
+        let mut keys_hash = [0u8; 32];
+        keys_hash.copy_from_slice(&sgx_quote.report_body.report_data.d[32..]);
+        Ok(PeerIdentity {
+            mr_enclave: sgx_quote.report_body.mr_enclave.m,
+            mr_signer: sgx_quote.report_body.mr_signer.m,
+            keys_hash,
+        })
     } else {
         error!("Failed to fetch isvEnclaveQuoteBody from attestation report");
-        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        Err(sgx_status_t::SGX_ERROR_UNEXPECTED)
+    }
+}
+
+/// Logs which updates the platform of the peer lacks, according to its `platformInfoBlob`.
+fn log_platform_update_info(attn_report: &Value) -> Result<(), sgx_status_t> {
+    let pib = match &attn_report["platformInfoBlob"] {
+        Value::String(pib) if pib.len() > 8 => pib,
+        _ => {
+            warn!("Failed to fetch platformInfoBlob from attestation report");
+            return Ok(());
+        }
+    };
+    let mut buf = Vec::new();
+
+    // the TLV Header (4 bytes/8 hexes) should be skipped
+    let n = (pib.len() - 8) / 2;
+    for i in 0..n {
+        buf.push(u8::from_str_radix(&pib[(i * 2 + 8)..(i * 2 + 10)], 16).sgx_error()?);
     }
 
+    let mut update_info = sgx_update_info_bit_t::default();
+    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
+    let res = unsafe {
+        ocall_get_update_info(
+            &mut rt as *mut sgx_status_t,
+            buf.as_slice().as_ptr() as *const sgx_platform_info_t,
+            1,
+            &mut update_info as *mut sgx_update_info_bit_t,
+        )
+    };
+    if res != sgx_status_t::SGX_SUCCESS {
+        error!("ocall_get_update_info failed. res={:?}", res);
+        return Err(res);
+    }
+
+    if rt != sgx_status_t::SGX_SUCCESS {
+        warn!("platform of the peer needs an update. rt={:?}", rt);
+        // Borrow of packed field is unsafe in future Rust releases
+        unsafe {
+            debug!("update_info.pswUpdate: {}", update_info.pswUpdate);
+            debug!("update_info.csmeFwUpdate: {}", update_info.csmeFwUpdate);
+            debug!("update_info.ucodeUpdate: {}", update_info.ucodeUpdate);
+        }
+    }
     Ok(())
 }
+
+pub fn test_malformed_attestation_report_is_refused() {
+    let policy = AttestationPolicy::default();
+    let pub_k = vec![0u8; 64];
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let timestamp = TzUtc
+        .timestamp(now as i64, 0)
+        .format("%Y-%m-%dT%H:%M:%S%.6f");
+    let report = |quote: &[u8]| {
+        format!(
+            "{{\"timestamp\":\"{}\",\"isvEnclaveQuoteStatus\":\"OK\",\"isvEnclaveQuoteBody\":\"{}\"}}",
+            timestamp,
+            base64::encode(quote)
+        )
+    };
+
+    // a quote body cut short must not be read past its end
+    assert!(verify_attn_report(report(&[0u8; 100]).as_bytes(), pub_k.clone(), &policy).is_err());
+    assert!(verify_attn_report(report(&[]).as_bytes(), pub_k.clone(), &policy).is_err());
+    assert!(verify_attn_report(b"{\"timestamp\":7}", pub_k.clone(), &policy).is_err());
+    assert!(verify_attn_report(b"not json", pub_k.clone(), &policy).is_err());
+
+    // a complete body, without the signature length, is accepted
+    let mut quote = [0u8; 432];
+    quote[368..400].copy_from_slice(&rsgx_sha256_slice(&pub_k[..]).unwrap());
+    let peer = verify_attn_report(report(&quote).as_bytes(), pub_k, &policy).unwrap();
+    assert_eq!(peer.mr_enclave, [0u8; 32]);
+}
@@ -16,11 +16,33 @@
 */
 
 pub const RSA3072_SEALED_KEY_FILE: &str = "rsa3072_key_sealed.bin";
+pub const X25519_SEALED_KEY_FILE: &str = "x25519_key_sealed.bin";
+pub const RETIRED_SHIELDING_KEYS_FILE: &str = "shielding_keys_retired_sealed.bin";
 pub const SEALED_SIGNER_SEED_FILE: &str = "ed25519_key_sealed.bin";
 pub const ENCRYPTED_STATE_FILE: &str = "state.bin";
+pub const ROTATED_STATE_FILE_SUFFIX: &str = ".next";
+pub const PREVIOUS_STATE_FILE_SUFFIX: &str = ".prev";
 pub const SHARDS_PATH: &str = "./shards";
 pub const AES_KEY_FILE_AND_INIT_V: &str = "aes_key_sealed.bin";
+pub const AES_KEY_NEXT_FILE: &str = "aes_key_next_sealed.bin";
+pub const AES_ROTATION_COMMIT_FILE: &str = "aes_rotation_commit_sealed.bin";
+pub const SEALED_SHARD_KEY_FILE: &str = "shard_key_sealed.bin";
+pub const STATE_HASH_ALIAS_FILE: &str = "state_hash_alias_sealed.bin";
+pub const STATE_FORMAT_FILE: &str = "state_format_sealed.bin";
+pub const STATE_RECORD_FILE: &str = "state_record_sealed.bin";
+// states whose confirmation the enclave remembers per shard
+pub const MAX_RECORDED_STATES: usize = 256;
+pub const PROVISIONING_POLICY_FILE: &str = "provisioning_policy_sealed.bin";
+pub const ATTESTATION_POLICY_FILE: &str = "attestation_policy_sealed.bin";
+pub const RA_CACHE_FILE: &str = "ra_cache_sealed.bin";
 
+// hex encoded ed25519 public key of the operator, see `operator.rs`
+pub const OPERATOR_PUBKEY: Option<&str> = option_env!("SGX_OPERATOR_PUBKEY");
+pub const RESTORE_STATE_CONTEXT: &[u8] = b"substraTEE/restore-state";
+pub const PROVISIONING_POLICY_CONTEXT: &[u8] = b"substraTEE/provisioning-policy";
+pub const ATTESTATION_POLICY_CONTEXT: &[u8] = b"substraTEE/attestation-policy";
+
+pub const RA_CERT_FILE: &str = "ra_cert.der";
 pub const RA_DUMP_CERT_DER_FILE: &str = "ra_dump_cert.der";
 pub const RA_DUMP_SIGNER_ATTN_FILE: &str = "ra_dump_signer_attn.bin";
 
@@ -0,0 +1,105 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Monotonic counters of the platform, which keep the host from replaying sealed files.
+//!
+//! Sealing hides and authenticates a file, but an older sealed version of it still unseals.
+//! A sealed file that carries the value of a monotonic counter, which only this enclave can
+//! increment, is outdated as soon as the counter has moved on. The counters are kept by the
+//! Platform Services Enclave, so the aesmd of the host must provide platform services.
+
+use sgx_tservice::{
+    rsgx_close_pse_session, rsgx_create_monotonic_counter, rsgx_create_pse_session,
+    rsgx_increment_monotonic_counter, rsgx_read_monotonic_counter,
+};
+use sgx_types::*;
+
+use codec::{Decode, Encode};
+use log::*;
+
+/// A monotonic counter and the value it had when the enclave sealed the file carrying it
+#[derive(Encode, Decode, Default, Clone, Copy, PartialEq, Debug)]
+pub struct Counter {
+    /// counter id and nonce of the `sgx_mc_uuid_t`
+    uuid: [u8; 16],
+    value: u32,
+}
+
+impl Counter {
+    pub fn create() -> SgxResult<Counter> {
+        let mut uuid = sgx_mc_uuid_t::default();
+        let mut value = 0u32;
+        with_pse_session(|| rsgx_create_monotonic_counter(&mut uuid, &mut value))?;
+        Ok(Counter {
+            uuid: to_bytes(&uuid),
+            value,
+        })
+    }
+
+    /// Increments the counter and takes on its new value
+    pub fn increment(&mut self) -> SgxResult<()> {
+        let mut value = 0u32;
+        with_pse_session(|| rsgx_increment_monotonic_counter(&from_bytes(&self.uuid), &mut value))?;
+        self.value = value;
+        Ok(())
+    }
+
+    /// Whether the counter still has the value that has been sealed with it
+    pub fn is_current(&self) -> SgxResult<bool> {
+        let mut value = 0u32;
+        with_pse_session(|| rsgx_read_monotonic_counter(&from_bytes(&self.uuid), &mut value))?;
+        if value != self.value {
+            warn!(
+                "[Enclave] Monotonic counter is at {}, but {} has been sealed",
+                value, self.value
+            );
+        }
+        Ok(value == self.value)
+    }
+}
+
+fn with_pse_session<F: FnOnce() -> SgxError>(f: F) -> SgxError {
+    rsgx_create_pse_session().map_err(|status| {
+        error!(
+            "[Enclave] No session with the platform services: {}",
+            status.as_str()
+        );
+        status
+    })?;
+    let result = f();
+    if let Err(status) = rsgx_close_pse_session() {
+        warn!(
+            "[Enclave] Closing the platform services session failed: {}",
+            status.as_str()
+        );
+    }
+    result
+}
+
+fn to_bytes(uuid: &sgx_mc_uuid_t) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..3].copy_from_slice(&uuid.counter_id);
+    bytes[3..].copy_from_slice(&uuid.nonce);
+    bytes
+}
+
+fn from_bytes(bytes: &[u8; 16]) -> sgx_mc_uuid_t {
+    let mut uuid = sgx_mc_uuid_t::default();
+    uuid.counter_id.copy_from_slice(&bytes[..3]);
+    uuid.nonce.copy_from_slice(&bytes[3..]);
+    uuid
+}
@@ -20,8 +20,10 @@ use std::sgxfs::SgxFile;
 use std::string::String;
 use std::vec::Vec;
 
+use log::*;
 use sgx_types::*;
 
+use crate::ocall_write_state;
 use crate::utils::UnwrapOrSgxErrorUnexpected;
 
 pub fn unseal(filepath: &str) -> SgxResult<Vec<u8>> {
@@ -66,6 +68,29 @@ pub fn write(bytes: &[u8], filepath: &str) -> SgxResult<sgx_status_t> {
         .sgx_error_with_log(&format!("[Enclave] Creating '{}' failed", filepath))?
 }
 
+/// Lets the worker replace `filepath` atomically, keeping the replaced version as a backup
+pub fn write_atomic(bytes: &[u8], filepath: &str) -> SgxResult<()> {
+    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
+    let res = unsafe {
+        ocall_write_state(
+            &mut rt as *mut sgx_status_t,
+            filepath.as_ptr(),
+            filepath.len() as u32,
+            bytes.as_ptr(),
+            bytes.len() as u32,
+        )
+    };
+
+    if rt != sgx_status_t::SGX_SUCCESS {
+        error!("[Enclave] Writing '{}' failed: {}", filepath, rt.as_str());
+        return Err(rt);
+    }
+    if res != sgx_status_t::SGX_SUCCESS {
+        return Err(res);
+    }
+    Ok(())
+}
+
 fn _write<F: Write>(bytes: &[u8], mut file: F) -> SgxResult<sgx_status_t> {
     file.write_all(bytes)
         .sgx_error_with_log("[Enclave] Writing File failed!")?;
@@ -24,19 +24,29 @@
 #![cfg_attr(target_env = "sgx", feature(rustc_private))]
 #![allow(clippy::missing_safety_doc)]
 
+#[cfg(all(feature = "mock-ra", feature = "production"))]
+compile_error!(
+    "mock-ra trusts the test CA of substratee-mock-ias and must not be used in production"
+);
+
 use log::*;
 
 #[cfg(not(target_env = "sgx"))]
 #[macro_use]
 extern crate sgx_tstd as std;
+#[macro_use]
+extern crate lazy_static;
 
 use base58::ToBase58;
 
+use sgx_tcrypto::rsgx_sha256_slice;
 use sgx_tunittest::*;
 use sgx_types::{sgx_epid_group_id_t, sgx_status_t, sgx_target_info_t, size_t, SgxResult};
 
 use substrate_api_client::{compose_extrinsic_offline, utils::storage_key_hash_vec};
-use substratee_stf::{ShardIdentifier, Stf, TrustedCallSigned, TrustedGetterSigned};
+use substratee_stf::{
+    envelope, EnclaveCredentials, ShardIdentifier, Stf, TrustedCallSigned, TrustedGetterSigned,
+};
 
 use codec::{Decode, Encode};
 use primitives::{crypto::Pair, hashing::blake2_256};
@@ -47,17 +57,32 @@ use std::string::String;
 use std::vec::Vec;
 
 use std::collections::HashMap;
-use substrate_api_client::utils::hexstr_to_u256;
-use utils::{hash_from_slice, write_slice_and_whitespace_pad};
+use substrate_api_client::utils::{hexstr_to_u256, hexstr_to_vec};
+use utils::{hash_from_slice, write_slice_and_whitespace_pad, UnwrapOrSgxErrorUnexpected};
 
 mod aes;
 mod attestation;
+#[path = "../../substratee-ra-verify/src/attestation_policy.rs"]
+mod attestation_policy;
+mod bundle;
 mod constants;
+mod counter;
+#[cfg(feature = "dcap")]
+#[path = "../../substratee-ra-verify/src/dcap.rs"]
+mod dcap;
 mod ed25519;
 mod io;
+mod operator;
+mod provisioning_policy;
+mod provisioning_protocol;
+mod ra_cache;
+#[path = "../../substratee-ra-verify/src/ra_cert.rs"]
+mod ra_cert;
 mod rsa3072;
+mod shielding;
 mod state;
 mod utils;
+mod x25519;
 
 pub mod cert;
 pub mod hex;
@@ -89,15 +114,117 @@ pub unsafe extern "C" fn init() -> sgx_status_t {
         return status;
     }
 
-    // create the aes key that is used for state encryption such that a key is always present in tests.
-    // It will be overwritten anyway if mutual remote attastation is performed with the primary worker
+    if let Err(status) = x25519::create_sealed_if_absent() {
+        return status;
+    }
+
+    if let Err(status) = state::finish_pending_key_rotation() {
+        return status;
+    }
+
+    // create the master secret that the per-shard state encryption keys are derived from.
+    // Keys of shards that are provisioned by the primary worker are sealed separately.
     if let Err(status) = aes::read_or_create_sealed() {
         return status;
     }
 
+    if let Err(status) = state::migrate_legacy_format() {
+        return status;
+    }
+
     sgx_status_t::SGX_SUCCESS
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn rotate_state_key() -> sgx_status_t {
+    match state::rotate_key() {
+        Ok(()) => sgx_status_t::SGX_SUCCESS,
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rotate_shielding_key(
+    grace_period: u64,
+    rotation: *mut u8,
+    rotation_size: u32,
+) -> sgx_status_t {
+    let rotation_signed = match shielding::rotate(grace_period) {
+        Ok(r) => r,
+        Err(status) => return status,
+    };
+
+    let rotation_slice = slice::from_raw_parts_mut(rotation, rotation_size as usize);
+    write_slice_and_whitespace_pad(rotation_slice, rotation_signed.encode());
+
+    sgx_status_t::SGX_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn set_provisioning_policy(
+    policy: *const u8,
+    policy_size: u32,
+    authorization: *const u8,
+    authorization_size: u32,
+) -> sgx_status_t {
+    let mut policy_slice = slice::from_raw_parts(policy, policy_size as usize);
+    let authorization = slice::from_raw_parts(authorization, authorization_size as usize);
+    let policy = match provisioning_policy::ProvisioningPolicy::decode(&mut policy_slice) {
+        Ok(p) => p,
+        Err(_) => {
+            error!("[Enclave] Could not decode the provisioning policy");
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+    let own = match attestation::get_mrenclave_of_self() {
+        Ok(m) => m,
+        Err(status) => return status,
+    };
+    if let Err(status) = operator::verify(
+        &operator::provisioning_policy_payload(&own.m, &policy),
+        authorization,
+    ) {
+        return status;
+    }
+    match provisioning_policy::seal(&policy) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn set_attestation_policy(
+    policy: *const u8,
+    policy_size: u32,
+    authorization: *const u8,
+    authorization_size: u32,
+) -> sgx_status_t {
+    let mut policy_slice = slice::from_raw_parts(policy, policy_size as usize);
+    let authorization = slice::from_raw_parts(authorization, authorization_size as usize);
+    let (version, policy) =
+        match <(u64, attestation_policy::AttestationPolicy)>::decode(&mut policy_slice) {
+            Ok(p) => p,
+            Err(_) => {
+                error!("[Enclave] Could not decode the attestation policy");
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+        };
+    let own = match attestation::get_mrenclave_of_self() {
+        Ok(m) => m,
+        Err(status) => return status,
+    };
+    if let Err(status) = operator::verify(
+        &operator::attestation_policy_payload(&own.m, version, &policy),
+        authorization,
+    ) {
+        return status;
+    }
+    match cert::seal_attestation_policy(version, &policy) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(status) => status,
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn get_rsa_encryption_pubkey(
     pubkey: *mut u8,
@@ -125,6 +252,30 @@ pub unsafe extern "C" fn get_rsa_encryption_pubkey(
     sgx_status_t::SGX_SUCCESS
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn get_x25519_encryption_pubkey(
+    pubkey: *mut u8,
+    pubkey_size: u32,
+) -> sgx_status_t {
+    let x25519_pubkey = match x25519::unseal_pubkey() {
+        Ok(key) => key,
+        Err(status) => return status,
+    };
+    if pubkey_size as usize != x25519_pubkey.len() {
+        error!(
+            "[Enclave] The X25519 public key has {} B, not {} B",
+            x25519_pubkey.len(),
+            pubkey_size
+        );
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    let pubkey_slice = slice::from_raw_parts_mut(pubkey, pubkey_size as usize);
+    pubkey_slice.clone_from_slice(&x25519_pubkey);
+
+    sgx_status_t::SGX_SUCCESS
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn get_ecc_signing_pubkey(pubkey: *mut u8, pubkey_size: u32) -> sgx_status_t {
     if let Err(status) = ed25519::create_sealed_if_absent() {
@@ -167,15 +318,12 @@ pub unsafe extern "C" fn execute_stf(
     let extrinsic_slice =
         slice::from_raw_parts_mut(unchecked_extrinsic, unchecked_extrinsic_size as usize);
 
-    debug!("load shielding keypair");
-    let rsa_keypair = match rsa3072::unseal_pair() {
-        Ok(pair) => pair,
-        Err(status) => return status,
-    };
-
     // decrypt the payload
     debug!("decrypt the call");
-    let request_vec = rsa3072::decrypt(&cyphertext_slice, &rsa_keypair);
+    let request_vec = match shielding::unshield(&cyphertext_slice) {
+        Ok(v) => v,
+        Err(status) => return status,
+    };
     let stf_call_signed = TrustedCallSigned::decode(&mut request_vec.as_slice()).unwrap();
 
     debug!("query mrenclave of self");
@@ -190,7 +338,12 @@ pub unsafe extern "C" fn execute_stf(
         return sgx_status_t::SGX_ERROR_UNEXPECTED;
     }
 
-    let mut state = match state::load(&shard) {
+    let confirmed_hash = match confirmed_state_hash(&shard, node_url) {
+        Ok(h) => h,
+        Err(status) => return status,
+    };
+
+    let mut state = match state::load_confirmed(&shard, confirmed_hash) {
         Ok(s) => s,
         Err(status) => return status,
     };
@@ -280,6 +433,116 @@ pub unsafe extern "C" fn execute_stf(
     sgx_status_t::SGX_SUCCESS
 }
 
+/// Restores an encrypted snapshot of the state of `shard` as the current state.
+///
+/// Only states the enclave has written and confirmed itself can be restored, and only with the
+/// operator's `authorization` (see `operator.rs`). Like any other state transition, the restore
+/// is published on chain: the returned extrinsic confirms the restored state hash.
+#[no_mangle]
+pub unsafe extern "C" fn restore_state(
+    shard: *const u8,
+    shard_size: u32,
+    snapshot: *const u8,
+    snapshot_size: u32,
+    authorization: *const u8,
+    authorization_size: u32,
+    genesis_hash: *const u8,
+    genesis_hash_size: u32,
+    nonce: *const u32,
+    unchecked_extrinsic: *mut u8,
+    unchecked_extrinsic_size: u32,
+) -> sgx_status_t {
+    let shard = ShardIdentifier::from_slice(slice::from_raw_parts(shard, shard_size as usize));
+    let snapshot = slice::from_raw_parts(snapshot, snapshot_size as usize);
+    let authorization = slice::from_raw_parts(authorization, authorization_size as usize);
+    let genesis_hash = hash_from_slice(slice::from_raw_parts(
+        genesis_hash,
+        genesis_hash_size as usize,
+    ));
+    let extrinsic_slice =
+        slice::from_raw_parts_mut(unchecked_extrinsic, unchecked_extrinsic_size as usize);
+
+    let state_hash: Hash = match rsgx_sha256_slice(snapshot) {
+        Ok(h) => h.into(),
+        Err(status) => return status,
+    };
+    if let Err(status) = operator::verify(
+        &operator::restore_payload(&shard, &state_hash),
+        authorization,
+    ) {
+        return status;
+    }
+    if let Err(status) = state::restore(&shard, snapshot) {
+        return status;
+    }
+
+    let signer = match ed25519::unseal_pair() {
+        Ok(pair) => pair,
+        Err(status) => return status,
+    };
+
+    // there is no trusted call behind a restore
+    let call_hash = blake2_256(snapshot);
+    let xt_call = [SUBSRATEE_REGISTRY_MODULE, CALL_CONFIRMED];
+    let xt = compose_extrinsic_offline!(
+        signer,
+        (xt_call, shard, call_hash.to_vec(), state_hash.encode()),
+        *nonce,
+        genesis_hash,
+        RUNTIME_SPEC_VERSION
+    );
+
+    write_slice_and_whitespace_pad(extrinsic_slice, xt.encode());
+
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// Packs `shard` into a bundle at `path` that only the enclave with the SCALE encoded
+/// `EnclaveCredentials` `target` can import
+#[no_mangle]
+pub unsafe extern "C" fn export_shard(
+    shard: *const u8,
+    shard_size: u32,
+    target: *const u8,
+    target_size: u32,
+    sign_type: sgx_quote_sign_type_t,
+    path: *const u8,
+    path_size: u32,
+) -> sgx_status_t {
+    let shard = ShardIdentifier::from_slice(slice::from_raw_parts(shard, shard_size as usize));
+    let mut target_slice = slice::from_raw_parts(target, target_size as usize);
+    let path = match std::str::from_utf8(slice::from_raw_parts(path, path_size as usize)) {
+        Ok(p) => p,
+        Err(_) => return sgx_status_t::SGX_ERROR_INVALID_PARAMETER,
+    };
+
+    let target = match EnclaveCredentials::decode(&mut target_slice) {
+        Ok(t) => t,
+        Err(_) => {
+            error!("[Enclave] invalid credentials of the target enclave");
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+
+    match bundle::export(&shard, &target, sign_type, path) {
+        Ok(()) => sgx_status_t::SGX_SUCCESS,
+        Err(status) => status,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn import_shard(path: *const u8, path_size: u32) -> sgx_status_t {
+    let path = match std::str::from_utf8(slice::from_raw_parts(path, path_size as usize)) {
+        Ok(p) => p,
+        Err(_) => return sgx_status_t::SGX_ERROR_INVALID_PARAMETER,
+    };
+
+    match bundle::import(path) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(status) => status,
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn get_state(
     trusted_op: *const u8,
@@ -290,9 +553,26 @@ pub unsafe extern "C" fn get_state(
     value_size: u32,
 ) -> sgx_status_t {
     let shard = ShardIdentifier::from_slice(slice::from_raw_parts(shard, shard_size as usize));
-    let mut trusted_op_slice = slice::from_raw_parts(trusted_op, trusted_op_size as usize);
+    let trusted_op_slice = slice::from_raw_parts(trusted_op, trusted_op_size as usize);
     let value_slice = slice::from_raw_parts_mut(value, value_size as usize);
-    let tusted_getter_signed = TrustedGetterSigned::decode(&mut trusted_op_slice).unwrap();
+
+    // getters may be shielded such that the host doesn't learn who queries what
+    let getter_vec = if envelope::is_envelope(trusted_op_slice) {
+        debug!("decrypt the getter");
+        match shielding::unshield(trusted_op_slice) {
+            Ok(v) => v,
+            Err(status) => return status,
+        }
+    } else {
+        trusted_op_slice.to_vec()
+    };
+    let tusted_getter_signed = match TrustedGetterSigned::decode(&mut getter_vec.as_slice()) {
+        Ok(getter) => getter,
+        Err(_) => {
+            error!("could not decode TrustedGetterSigned");
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+    };
 
     debug!("verifying signature of TrustedCallSigned");
     if let false = tusted_getter_signed.verify_signature() {
@@ -306,10 +586,17 @@ pub unsafe extern "C" fn get_state(
     };
 
     debug!("calling ito STF to get state");
-    let value_opt = Stf::get_state(&mut state, tusted_getter_signed.getter);
+    let value_opt = Stf::get_state(&mut state, tusted_getter_signed.getter.clone());
+
+    debug!("encrypting getter result to the requester");
+    let value_encrypted =
+        match shielding::encrypt_to(&value_opt.encode(), &tusted_getter_signed.response_key) {
+            Ok(v) => v,
+            Err(status) => return status,
+        };
 
     debug!("returning getter result");
-    write_slice_and_whitespace_pad(value_slice, value_opt.encode());
+    write_slice_and_whitespace_pad(value_slice, value_encrypted);
 
     sgx_status_t::SGX_SUCCESS
 }
@@ -331,6 +618,31 @@ extern "C" {
         cid_size: u32,
     ) -> sgx_status_t;
 
+    pub fn ocall_write_state(
+        ret_val: *mut sgx_status_t,
+        path: *const u8,
+        path_size: u32,
+        enc_state: *const u8,
+        enc_state_size: u32,
+    ) -> sgx_status_t;
+
+    pub fn ocall_state_block_number(
+        ret_val: *mut sgx_status_t,
+        shard: *const u8,
+        shard_size: u32,
+        state_hash: *const u8,
+        hash_size: u32,
+        block_number: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_state_recovered(
+        ret_val: *mut sgx_status_t,
+        shard: *const u8,
+        shard_size: u32,
+        state_hash: *const u8,
+        hash_size: u32,
+    ) -> sgx_status_t;
+
     pub fn ocall_worker_request(
         ret_val: *mut sgx_status_t,
         request: *const u8,
@@ -352,6 +664,17 @@ extern "C" {
 pub extern "C" fn test_main_entrance() -> size_t {
     rsgx_unit_tests!(
         state::test_encrypted_state_io_works,
+        state::test_shard_keys_are_distinct,
+        state::test_tampered_state_fails_to_authenticate,
+        state::test_legacy_state_is_migrated,
+        state::test_only_the_recorded_state_is_loaded,
+        state::test_replayed_state_record_is_refused,
+        cert::test_malformed_attestation_report_is_refused,
+        provisioning_policy::test_peer_is_checked_against_allow_list,
+        provisioning_protocol::test_messages_are_framed,
+        provisioning_protocol::test_state_is_streamed_in_chunks,
+        ra_cache::test_cached_ra_is_reused_within_half_the_report_age,
+        tls_ra::test_provisioned_state_of_a_rotated_shard_loads,
         test_ocall_read_write_ipfs,
         test_ocall_worker_request
     )
@@ -426,6 +749,41 @@ fn worker_request<V: Encode + Decode>(
     Ok(Decode::decode(&mut resp.as_slice()).unwrap())
 }
 
+/// The state hash of `shard` that has been confirmed on chain latest, as reported by the host.
+/// Unverified, so it may only be used to refuse a state, see [`state::load_confirmed`].
+/// Rollbacks are caught by the monotonic counter of the state record, not by this hash.
+fn confirmed_state_hash(shard: &ShardIdentifier, node_url: &[u8]) -> SgxResult<Option<Hash>> {
+    let key = storage_key_hash_vec("substraTEERegistry", "LatestIPFSHash", Some(shard.encode()));
+    let mut resp: Vec<WorkerResponse<Vec<u8>>> =
+        worker_request(vec![WorkerRequest::ChainStorage(key)], node_url)?;
+
+    // Todo: verify the storage proof against a finalized header as soon as the api-client
+    // delivers one
+    let value = match resp.pop() {
+        Some(WorkerResponse::ChainStorage(_key, value, _proof)) => {
+            String::from_utf8(value).sgx_error_with_log("[Enclave] Invalid storage value")?
+        }
+        None => {
+            error!("[Enclave] No response for the confirmed state hash");
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+    };
+    if value.trim() == "null" {
+        debug!("no state hash has been confirmed for this shard yet");
+        return Ok(None);
+    }
+
+    let encoded = hexstr_to_vec(value.trim().to_string())
+        .sgx_error_with_log("[Enclave] Confirmed state hash is not hex")?;
+    let hash: Vec<u8> = Decode::decode(&mut encoded.as_slice())
+        .sgx_error_with_log("[Enclave] Could not decode the confirmed state hash")?;
+    if hash.len() != 32 {
+        error!("[Enclave] Confirmed state hash has {}B", hash.len());
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(Some(hash_from_slice(&hash)))
+}
+
 fn test_ocall_worker_request() {
     info!("testing ocall_worker_request. Hopefully substraTEE-node is running...");
     let mut requests = Vec::new();
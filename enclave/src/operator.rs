@@ -0,0 +1,85 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+//! Actions the host must not take on its own are authorized by the operator of the worker, who
+//! signs them with an ed25519 key. Its public key is compiled into the enclave with
+//! `SGX_OPERATOR_PUBKEY=<hex> make`. An enclave built without it refuses these actions.
+
+use std::vec::Vec;
+
+use sgx_types::*;
+
+use codec::Encode;
+use log::*;
+use primitives::{crypto::Pair, ed25519, H256};
+use substratee_stf::ShardIdentifier;
+
+use crate::attestation_policy::AttestationPolicy;
+use crate::constants::{
+    ATTESTATION_POLICY_CONTEXT, OPERATOR_PUBKEY, PROVISIONING_POLICY_CONTEXT, RESTORE_STATE_CONTEXT,
+};
+use crate::hex;
+use crate::provisioning_policy::ProvisioningPolicy;
+
+/// What the operator signs to have the enclave restore the state with hash `state_hash`
+pub fn restore_payload(shard: &ShardIdentifier, state_hash: &H256) -> Vec<u8> {
+    (RESTORE_STATE_CONTEXT.to_vec(), shard, state_hash).encode()
+}
+
+/// What the operator signs to have the enclave running `mr_enclave` seal `policy`. The enclave
+/// refuses a policy whose version is not higher than the sealed one, see `provisioning_policy`.
+pub fn provisioning_policy_payload(mr_enclave: &[u8; 32], policy: &ProvisioningPolicy) -> Vec<u8> {
+    (PROVISIONING_POLICY_CONTEXT.to_vec(), mr_enclave, policy).encode()
+}
+
+/// What the operator signs to have the enclave running `mr_enclave` seal version `version` of
+/// the attestation policy. Like for the provisioning policy, older versions are refused.
+pub fn attestation_policy_payload(
+    mr_enclave: &[u8; 32],
+    version: u64,
+    policy: &AttestationPolicy,
+) -> Vec<u8> {
+    (
+        ATTESTATION_POLICY_CONTEXT.to_vec(),
+        mr_enclave,
+        version,
+        policy,
+    )
+        .encode()
+}
+
+/// Checks that the operator has signed `payload`.
+pub fn verify(payload: &[u8], signature: &[u8]) -> SgxResult<()> {
+    let pubkey = match OPERATOR_PUBKEY {
+        Some(k) if !k.is_empty() => hex::decode_hex(k)?,
+        _ => {
+            error!("[Enclave] No operator key has been compiled into this enclave");
+            return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+        }
+    };
+    if pubkey.len() != 32 || signature.len() != 64 {
+        error!("[Enclave] Invalid operator key or signature length");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let public = ed25519::Public::from_slice(&pubkey);
+    let signature = ed25519::Signature::from_slice(signature);
+    if !ed25519::Pair::verify(&signature, payload, &public) {
+        error!("[Enclave] The operator has not authorized this action");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_SIGNATURE);
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Which attested enclaves may take part in MU-RA key provisioning.
+//!
+//! Peers running our own MRENCLAVE are always accepted. Further MRENCLAVE and MRSIGNER values,
+//! e.g. of an upgraded enclave build, can be allowed with a sealed allow-list that the operator
+//! has signed. Each list the operator signs carries a higher version, and the enclave only seals
+//! a list newer than the one it has, bound to a monotonic counter. The host can thus neither
+//! apply an older signed list again nor put back an older sealed one. Deleting the sealed list
+//! falls back to our own MRENCLAVE only, after which any signed list can be set again.
+
+use std::path::Path;
+use std::string::String;
+use std::vec::Vec;
+
+use sgx_types::*;
+
+use base58::ToBase58;
+use codec::{Decode, Encode};
+use log::*;
+
+use crate::cert::PeerIdentity;
+use crate::constants::PROVISIONING_POLICY_FILE;
+use crate::counter::Counter;
+use crate::io;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+
+#[derive(Encode, Decode, Default, Debug, Clone, PartialEq)]
+pub struct ProvisioningPolicy {
+    /// higher than the version of any policy the operator has signed before
+    pub version: u64,
+    pub mr_enclaves: Vec<[u8; 32]>,
+    pub mr_signers: Vec<[u8; 32]>,
+}
+
+impl ProvisioningPolicy {
+    /// Accepts `peer` if it runs `own_mr_enclave` or is on the allow-list, or tells why not.
+    /// Production enclaves ignore the MRSIGNERs, see `seal`.
+    pub fn check(&self, peer: &PeerIdentity, own_mr_enclave: &[u8; 32]) -> Result<(), String> {
+        if &peer.mr_enclave == own_mr_enclave || self.mr_enclaves.contains(&peer.mr_enclave) {
+            return Ok(());
+        }
+        if !cfg!(feature = "production") && self.mr_signers.contains(&peer.mr_signer) {
+            return Ok(());
+        }
+        Err(format!(
+            "MRENCLAVE {} and MRSIGNER {} of the peer are not allowed",
+            peer.mr_enclave.to_base58(),
+            peer.mr_signer.to_base58()
+        ))
+    }
+}
+
+/// The sealed policy, or the default of accepting our own MRENCLAVE only.
+pub fn unseal() -> SgxResult<ProvisioningPolicy> {
+    Ok(unseal_sealed()?
+        .map(|(policy, _)| policy)
+        .unwrap_or_default())
+}
+
+fn unseal_sealed() -> SgxResult<Option<(ProvisioningPolicy, Counter)>> {
+    if !Path::new(PROVISIONING_POLICY_FILE).exists() {
+        return Ok(None);
+    }
+    let encoded = io::unseal(PROVISIONING_POLICY_FILE)?;
+    let (policy, counter) = <(ProvisioningPolicy, Counter)>::decode(&mut encoded.as_slice())
+        .sgx_error_with_log("[Enclave] Could not decode the provisioning policy")?;
+    if !counter.is_current()? {
+        error!(
+            "[Enclave] The sealed provisioning policy is outdated. Set the latest policy the \
+             operator has signed anew"
+        );
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(Some((policy, counter)))
+}
+
+/// Seals `policy` if it is newer than the sealed one.
+///
+/// Production enclaves refuse MRSIGNERs: they would hand the keys to any enclave signed with the
+/// same key, including debug builds and ones not yet reviewed.
+pub fn seal(policy: &ProvisioningPolicy) -> SgxResult<sgx_status_t> {
+    if cfg!(feature = "production") && !policy.mr_signers.is_empty() {
+        error!("[Enclave] Production enclaves allow peers by MRENCLAVE only");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let sealed = unseal_sealed()?;
+    let sealed_version = sealed.as_ref().map(|(p, _)| p.version).unwrap_or_default();
+    if policy.version <= sealed_version {
+        error!(
+            "[Enclave] Provisioning policy version {} is not newer than the sealed version {}",
+            policy.version, sealed_version
+        );
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    let counter = match sealed {
+        Some((_, mut counter)) => {
+            counter.increment()?;
+            counter
+        }
+        None => Counter::create()?,
+    };
+    info!(
+        "[Enclave] Sealing provisioning policy version {} with {} MRENCLAVE(s) and {} MRSIGNER(s)",
+        policy.version,
+        policy.mr_enclaves.len(),
+        policy.mr_signers.len()
+    );
+    io::seal(&(policy, counter).encode(), PROVISIONING_POLICY_FILE)
+}
+
+pub fn test_peer_is_checked_against_allow_list() {
+    let own = [1u8; 32];
+    let peer = |mr_enclave: [u8; 32], mr_signer: [u8; 32]| PeerIdentity {
+        mr_enclave,
+        mr_signer,
+        keys_hash: [0u8; 32],
+    };
+
+    let default = ProvisioningPolicy::default();
+    assert!(default.check(&peer(own, [9u8; 32]), &own).is_ok());
+    assert!(default.check(&peer([2u8; 32], [9u8; 32]), &own).is_err());
+
+    let policy = ProvisioningPolicy {
+        version: 1,
+        mr_enclaves: vec![[2u8; 32]],
+        mr_signers: vec![[8u8; 32]],
+    };
+    assert!(policy.check(&peer([2u8; 32], [9u8; 32]), &own).is_ok());
+    assert!(policy.check(&peer([3u8; 32], [8u8; 32]), &own).is_ok());
+    assert!(policy.check(&peer([3u8; 32], [9u8; 32]), &own).is_err());
+}
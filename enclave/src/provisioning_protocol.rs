@@ -0,0 +1,278 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Messages of the key provisioning protocol spoken over the MU-RA TLS session.
+//!
+//! Each message is framed as a u32 LE length followed by the SCALE encoded `Message`.
+//!
+//! ```text
+//! client                                server
+//!   Hello { version, capabilities } ->
+//!                                   <-  Hello { version, capabilities }
+//!   Identity                        ->
+//!   ShardList                       ->
+//!                                   <-  KeyBundle
+//!                                   <-  ShardState, one per shard key in the bundle
+//!                                   <-  StateChunk*, if the state is streamed
+//!   Ack                             ->
+//! ```
+//!
+//! The capabilities each side announces are the ways it accepts to transfer the state, as
+//! configured by its worker. The server sends the states the way it is configured to, and the
+//! client refuses any other.
+//!
+//! `Identity` names the chain signer of the client, which the server records. The server refuses
+//! it unless the report data of the client's RA certificate commits to it.
+//!
+//! Either side may send `Error` instead of the next message and close the session.
+
+use std::io::{Cursor, Read, Write};
+use std::string::String;
+use std::vec::Vec;
+
+use sgx_types::*;
+
+use codec::{Decode, Encode};
+use log::*;
+use primitives::H256;
+use substratee_stf::{envelope::X25519Key, ShardIdentifier};
+
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// The state is written to and fetched from IPFS.
+pub const CAP_IPFS_STATE: u32 = 1;
+/// The state is streamed in `StateChunk`s over the session itself.
+pub const CAP_STREAM_STATE: u32 = 2;
+
+/// Larger frames are refused before allocating a buffer for them.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+pub const STATE_CHUNK_SIZE: usize = 1 << 16;
+/// Larger states are refused, they wouldn't fit into the enclave heap anyway.
+pub const MAX_STATE_SIZE: u64 = 1 << 27;
+
+/// (shard, key, iv)
+pub type ShardKey = (ShardIdentifier, Vec<u8>, Vec<u8>);
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum Message {
+    Hello {
+        version: u32,
+        capabilities: u32,
+    },
+    /// The keys the client's RA certificate commits to, see `substratee_stf::enclave_keys_hash`
+    Identity {
+        /// ed25519 public key the client signs extrinsics with
+        signer: [u8; 32],
+        rsa_pubkey_json: Vec<u8>,
+        x25519_pubkey: X25519Key,
+    },
+    ShardList(Vec<ShardIdentifier>),
+    KeyBundle {
+        /// JSON of the RSA shielding key pair
+        shielding_key: Vec<u8>,
+        /// secret of the X25519 shielding key
+        x25519_secret: X25519Key,
+        shard_keys: Vec<ShardKey>,
+    },
+    /// Announces the encrypted state of `shard`.
+    ShardState {
+        shard: ShardIdentifier,
+        /// sha256 of the encrypted state
+        state_hash: H256,
+        /// the hash the state is confirmed under on chain. Differs from `state_hash` if the
+        /// sender re-encrypted the state since, e.g. in a key rotation
+        confirmed_hash: H256,
+        /// the block at which the sender's state was confirmed, 0 if unknown
+        block_number: u64,
+        size: u64,
+        transfer: Transfer,
+    },
+    StateChunk(Vec<u8>),
+    Ack,
+    Error(String),
+}
+
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum Transfer {
+    /// `StateChunk`s of at most `STATE_CHUNK_SIZE` follow until `size` bytes have been sent
+    Stream,
+    /// to be fetched from IPFS by its CID
+    Ipfs(Vec<u8>),
+}
+
+/// Whether `capability` names exactly one way to transfer the state.
+pub fn is_transfer(capability: u32) -> bool {
+    capability == CAP_IPFS_STATE || capability == CAP_STREAM_STATE
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "hello",
+            Message::Identity { .. } => "identity",
+            Message::ShardList(_) => "shard list",
+            Message::KeyBundle { .. } => "key bundle",
+            Message::ShardState { .. } => "shard state",
+            Message::StateChunk(_) => "state chunk",
+            Message::Ack => "acknowledgement",
+            Message::Error(_) => "error",
+        }
+    }
+}
+
+pub fn send<W: Write>(stream: &mut W, message: &Message) -> SgxResult<()> {
+    let encoded = message.encode();
+    if encoded.len() > MAX_FRAME_SIZE {
+        error!(
+            "    [Enclave] (MU-RA) The {} of {} B exceeds the frame size",
+            message.name(),
+            encoded.len()
+        );
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    stream
+        .write_all(&(encoded.len() as u32).to_le_bytes())
+        .and_then(|_| stream.write_all(&encoded))
+        .and_then(|_| stream.flush())
+        .sgx_error_with_log(&format!(
+            "    [Enclave] (MU-RA) Error sending the {}",
+            message.name()
+        ))
+}
+
+/// The next message of the peer. An `Error` of the peer is logged and returned as our error.
+pub fn receive<R: Read>(stream: &mut R) -> SgxResult<Message> {
+    let mut len = [0u8; 4];
+    stream
+        .read_exact(&mut len)
+        .sgx_error_with_log("    [Enclave] (MU-RA) Error receiving the frame length")?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        error!("    [Enclave] (MU-RA) Refusing a frame of {} B", len);
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    let mut frame = vec![0u8; len];
+    stream
+        .read_exact(&mut frame)
+        .sgx_error_with_log("    [Enclave] (MU-RA) Error receiving a frame")?;
+    let message = Message::decode(&mut frame.as_slice())
+        .sgx_error_with_log("    [Enclave] (MU-RA) Could not decode a message")?;
+    if let Message::Error(reason) = message {
+        error!("    [Enclave] (MU-RA) The peer reported: {}", reason);
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(message)
+}
+
+/// Tells the peer why the session ends, as far as the connection still allows it.
+pub fn abort<W: Write>(stream: &mut W, reason: &str) -> sgx_status_t {
+    error!("    [Enclave] (MU-RA) Aborting provisioning: {}", reason);
+    let _ = send(stream, &Message::Error(reason.to_string()));
+    sgx_status_t::SGX_ERROR_UNEXPECTED
+}
+
+/// Aborts because `got` is not the `expected` message.
+pub fn unexpected<W: Write>(stream: &mut W, expected: &str, got: &Message) -> sgx_status_t {
+    abort(
+        stream,
+        &format!("expected a {}, but got a {}", expected, got.name()),
+    )
+}
+
+pub fn send_stream<W: Write>(stream: &mut W, state: &[u8]) -> SgxResult<()> {
+    for chunk in state.chunks(STATE_CHUNK_SIZE) {
+        send(stream, &Message::StateChunk(chunk.to_vec()))?;
+    }
+    Ok(())
+}
+
+/// Receives a streamed state of `size` bytes. The buffer grows with the chunks received, so a
+/// false `size` doesn't make us allocate more than the peer actually sends.
+pub fn receive_stream<S: Read + Write>(stream: &mut S, size: u64) -> SgxResult<Vec<u8>> {
+    let mut state = Vec::new();
+    while (state.len() as u64) < size {
+        match receive(stream)? {
+            Message::StateChunk(ref chunk) if (state.len() + chunk.len()) as u64 > size => {
+                return Err(abort(stream, "the state exceeds its announced size"))
+            }
+            Message::StateChunk(ref chunk) if chunk.is_empty() => {
+                return Err(abort(stream, "empty state chunk"))
+            }
+            Message::StateChunk(chunk) => state.extend_from_slice(&chunk),
+            other => return Err(unexpected(stream, "state chunk", &other)),
+        }
+    }
+    Ok(state)
+}
+
+pub fn test_messages_are_framed() {
+    let hello = Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAP_STREAM_STATE,
+    };
+    let keys = Message::KeyBundle {
+        shielding_key: vec![1, 2, 3],
+        x25519_secret: [7u8; 32],
+        shard_keys: vec![(ShardIdentifier::default(), vec![4; 16], vec![5; 16])],
+    };
+    let mut stream = Vec::new();
+    send(&mut stream, &hello).unwrap();
+    send(&mut stream, &keys).unwrap();
+
+    let mut reader = stream.as_slice();
+    assert_eq!(receive(&mut reader).unwrap(), hello);
+    assert_eq!(receive(&mut reader).unwrap(), keys);
+    assert!(receive(&mut reader).is_err());
+
+    // a partial frame is an error, not a truncated message
+    let truncated = &stream[..stream.len() - 1];
+    let mut reader = truncated;
+    assert_eq!(receive(&mut reader).unwrap(), hello);
+    assert!(receive(&mut reader).is_err());
+
+    let oversized = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes();
+    assert!(receive(&mut &oversized[..]).is_err());
+
+    let mut stream = Vec::new();
+    abort(&mut stream, "no such shard");
+    assert!(receive(&mut stream.as_slice()).is_err());
+}
+
+pub fn test_state_is_streamed_in_chunks() {
+    let state: Vec<u8> = (0..3 * STATE_CHUNK_SIZE + 7).map(|i| i as u8).collect();
+    let mut stream = Vec::new();
+    send_stream(&mut stream, &state).unwrap();
+    send(&mut stream, &Message::Ack).unwrap();
+
+    let mut reader = Cursor::new(stream.clone());
+    assert_eq!(
+        receive_stream(&mut reader, state.len() as u64).unwrap(),
+        state
+    );
+    assert_eq!(receive(&mut reader).unwrap(), Message::Ack);
+
+    // chunks beyond the announced size
+    let mut reader = Cursor::new(stream.clone());
+    assert!(receive_stream(&mut reader, state.len() as u64 - 1).is_err());
+
+    // the stream ends early
+    let mut reader = Cursor::new(stream);
+    assert!(receive_stream(&mut reader, state.len() as u64 + 1).is_err());
+}
@@ -0,0 +1,127 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! The latest RA certificate with its key, sealed so that registration and MU-RA reuse it
+//! instead of contacting IAS each time.
+//!
+//! Cached evidence is reused while it is younger than half the `max_report_age` of the
+//! attestation policy, so peers with the same policy still accept it for a while. It is
+//! regenerated once older, when the enclave keys it commits to changed, or on
+//! `refresh_attestation`. Concurrent MU-RA sessions take the [`lock`] while doing so, such that
+//! they attest once and don't overwrite each other's sealed evidence.
+
+use std::path::Path;
+use std::sync::{SgxMutex, SgxMutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::untrusted::time::SystemTimeEx;
+use std::vec::Vec;
+
+use sgx_types::*;
+
+use codec::{Decode, Encode};
+use log::*;
+
+use crate::constants::RA_CACHE_FILE;
+use crate::io;
+
+lazy_static! {
+    static ref LOCK: SgxMutex<()> = SgxMutex::new(());
+}
+
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct CachedRa {
+    pub key_der: Vec<u8>,
+    pub cert_der: Vec<u8>,
+    pub chain_signer_attestation: [u32; 16],
+    /// `enclave_keys_hash` the report data commits to
+    pub keys_hash: [u8; 32],
+    pub sign_type: u32,
+    /// seconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl CachedRa {
+    pub fn is_reusable(
+        &self,
+        keys_hash: &[u8; 32],
+        sign_type: sgx_quote_sign_type_t,
+        max_report_age: u64,
+        now: u64,
+    ) -> bool {
+        &self.keys_hash == keys_hash
+            && self.sign_type == sign_type as u32
+            && now.saturating_sub(self.created_at) < max_report_age / 2
+    }
+}
+
+/// Held while the cache is checked and renewed
+pub fn lock() -> SgxMutexGuard<'static, ()> {
+    // the lock guards no data, so a panic of a holder is harmless
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The cached evidence, if any. Evidence that can't be unsealed is regenerated.
+pub fn unseal() -> Option<CachedRa> {
+    if !Path::new(RA_CACHE_FILE).exists() {
+        return None;
+    }
+    let encoded = io::unseal(RA_CACHE_FILE).ok()?;
+    match CachedRa::decode(&mut encoded.as_slice()) {
+        Ok(cached) => Some(cached),
+        Err(_) => {
+            warn!("[Enclave] Ignoring undecodable cached RA evidence");
+            None
+        }
+    }
+}
+
+pub fn seal(cached: &CachedRa) -> SgxResult<sgx_status_t> {
+    io::seal(&cached.encode(), RA_CACHE_FILE)
+}
+
+// Todo: the time is provided by the untrusted host, which could extend the reuse of evidence
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn test_cached_ra_is_reused_within_half_the_report_age() {
+    let sign_type = sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE;
+    let cached = CachedRa {
+        key_der: vec![1],
+        cert_der: vec![2],
+        chain_signer_attestation: [3; 16],
+        keys_hash: [4; 32],
+        sign_type: sign_type as u32,
+        created_at: 1000,
+    };
+    assert!(cached.is_reusable(&[4; 32], sign_type, 100, 1049));
+    assert!(!cached.is_reusable(&[4; 32], sign_type, 100, 1050));
+    assert!(!cached.is_reusable(&[5; 32], sign_type, 100, 1000));
+    assert!(!cached.is_reusable(
+        &[4; 32],
+        sgx_quote_sign_type_t::SGX_LINKABLE_SIGNATURE,
+        100,
+        1000
+    ));
+    assert_eq!(
+        CachedRa::decode(&mut cached.encode().as_slice()).unwrap(),
+        cached
+    );
+}
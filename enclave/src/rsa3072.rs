@@ -23,9 +23,11 @@ use sgx_crypto_helper::RsaKeyPair;
 use sgx_types::*;
 
 use log::*;
+use substratee_stf::envelope;
 
 use crate::constants::RSA3072_SEALED_KEY_FILE;
 use crate::io;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
 
 pub fn unseal_pair() -> SgxResult<Rsa3072KeyPair> {
     let keyvec = io::unseal(RSA3072_SEALED_KEY_FILE)?;
@@ -63,10 +65,41 @@ pub fn seal(pair: &[u8]) -> SgxResult<sgx_status_t> {
     io::seal(pair, RSA3072_SEALED_KEY_FILE)
 }
 
-pub fn decrypt(ciphertext_slice: &[u8], rsa_pair: &Rsa3072KeyPair) -> Vec<u8> {
+pub fn encrypt(plaintext: &[u8], pubkey: &Rsa3072PubKey) -> SgxResult<Vec<u8>> {
+    let mut encrypted_buffer = Vec::new();
+    pubkey.encrypt_buffer(plaintext, &mut encrypted_buffer)?;
+    Ok(encrypted_buffer)
+}
+
+/// Decrypts a payload that has been shielded either with the hybrid envelope or, for legacy
+/// clients, with RSA only.
+// Todo: drop plain RSA once all clients use the envelope
+pub fn unshield(cyphertext: &[u8], rsa_pair: &Rsa3072KeyPair) -> SgxResult<Vec<u8>> {
+    if envelope::is_envelope(cyphertext) {
+        let unwrap_key = |key: &[u8]| {
+            let mut decrypted = Vec::new();
+            rsa_pair
+                .decrypt_buffer(key, &mut decrypted)
+                .map(|_| decrypted)
+                .map_err(|_| ())
+        };
+        match envelope::decrypt(cyphertext, unwrap_key) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(e) => debug!("not a valid envelope ({:?}), trying plain RSA", e),
+        }
+    }
+
+    let mut decrypted_buffer = Vec::new();
+    rsa_pair
+        .decrypt_buffer(cyphertext, &mut decrypted_buffer)
+        .sgx_error_with_log("[Enclave] Could not decrypt the payload")?;
+    Ok(decrypted_buffer)
+}
+
+pub fn decrypt(ciphertext_slice: &[u8], rsa_pair: &Rsa3072KeyPair) -> SgxResult<Vec<u8>> {
     let mut decrypted_buffer = Vec::new();
     rsa_pair
         .decrypt_buffer(ciphertext_slice, &mut decrypted_buffer)
-        .unwrap();
-    decrypted_buffer
+        .sgx_error_with_log("[Enclave] Could not decrypt the RSA cyphertext")?;
+    Ok(decrypted_buffer)
 }
@@ -0,0 +1,168 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::untrusted::time::SystemTimeEx;
+use std::vec::Vec;
+
+use sgx_crypto_helper::rsa3072::Rsa3072KeyPair;
+use sgx_rand::{Rng, StdRng};
+use sgx_types::*;
+
+use codec::{Decode, Encode};
+use log::*;
+use primitives::crypto::Pair;
+use substratee_stf::envelope::{self, X25519Key, ENVELOPE_VERSION_X25519};
+use substratee_stf::{shielding_key_hash, ShieldingKeyRotation, ShieldingKeyRotationSigned};
+
+use crate::constants::RETIRED_SHIELDING_KEYS_FILE;
+use crate::ed25519;
+use crate::io;
+use crate::rsa3072;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+use crate::x25519;
+
+/// Shielding keys that have been replaced, kept to decrypt calls that were in flight.
+#[derive(Encode, Decode)]
+struct RetiredKeys {
+    rsa_pair: Vec<u8>,
+    x25519_secret: X25519Key,
+    valid_until: u64,
+}
+
+/// Decrypts a trusted call with whichever shielding key the client has chosen. Within the
+/// grace period after a rotation, the retired keys are tried as well.
+pub fn unshield(cyphertext: &[u8]) -> SgxResult<Vec<u8>> {
+    let rsa_pair = rsa3072::unseal_pair()?;
+    let x25519_secret = x25519::unseal_secret()?;
+    match unshield_with(cyphertext, &rsa_pair, &x25519_secret) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(status) => match retired_keys()? {
+            Some(retired) => {
+                info!("[Enclave] trying retired shielding keys");
+                let rsa_pair: Rsa3072KeyPair = serde_json::from_slice(&retired.rsa_pair)
+                    .sgx_error_with_log("[Enclave] Invalid retired RSA key")?;
+                unshield_with(cyphertext, &rsa_pair, &retired.x25519_secret)
+            }
+            None => Err(status),
+        },
+    }
+}
+
+fn unshield_with(
+    cyphertext: &[u8],
+    rsa_pair: &Rsa3072KeyPair,
+    x25519_secret: &X25519Key,
+) -> SgxResult<Vec<u8>> {
+    if envelope::version(cyphertext) == Some(ENVELOPE_VERSION_X25519) {
+        return envelope::decrypt_x25519(cyphertext, x25519_secret)
+            .sgx_error_with_log("[Enclave] Could not decrypt the ECIES payload");
+    }
+    rsa3072::unshield(cyphertext, rsa_pair)
+}
+
+/// Replaces both shielding keys. The old ones keep decrypting for `grace_period` seconds.
+/// A retired key of an earlier rotation is dropped.
+///
+/// The rotation is local to this enclave. Workers that have been provisioned with the old keys
+/// keep using them until they request key provisioning from this worker again, which hands out
+/// the new keys but not the retired ones.
+pub fn rotate(grace_period: u64) -> SgxResult<ShieldingKeyRotationSigned> {
+    let old_rsa_pair = rsa3072::unseal_pair()?;
+    let old_x25519_secret = x25519::unseal_secret()?;
+    let old_rsa_pubkey_hash = rsa_pubkey_hash()?;
+    let old_x25519_pubkey = envelope::x25519_public(&old_x25519_secret);
+
+    let grace_period_end = now() + grace_period;
+    let retired = RetiredKeys {
+        rsa_pair: serde_json::to_vec(&old_rsa_pair).sgx_error()?,
+        x25519_secret: old_x25519_secret,
+        valid_until: grace_period_end,
+    };
+    io::seal(&retired.encode(), RETIRED_SHIELDING_KEYS_FILE)?;
+
+    rsa3072::create_sealed()?;
+    x25519::create_sealed()?;
+
+    let rotation = ShieldingKeyRotation {
+        old_rsa_pubkey_hash,
+        new_rsa_pubkey_hash: rsa_pubkey_hash()?,
+        old_x25519_pubkey,
+        new_x25519_pubkey: x25519::unseal_pubkey()?,
+        grace_period_end,
+    };
+    let signer = ed25519::unseal_pair()?;
+    let signature = signer.sign(&rotation.encode());
+    info!(
+        "[Enclave] Rotated shielding keys. Retired keys are valid until {}",
+        grace_period_end
+    );
+    Ok(ShieldingKeyRotationSigned {
+        rotation,
+        signer: signer.public(),
+        signature,
+    })
+}
+
+fn rsa_pubkey_hash() -> SgxResult<primitives::H256> {
+    let pubkey = rsa3072::unseal_pubkey()?;
+    let json = serde_json::to_vec(&pubkey).sgx_error()?;
+    Ok(shielding_key_hash(&json))
+}
+
+/// The retired keys if their grace period hasn't ended. Expired keys are deleted.
+fn retired_keys() -> SgxResult<Option<RetiredKeys>> {
+    if !Path::new(RETIRED_SHIELDING_KEYS_FILE).exists() {
+        return Ok(None);
+    }
+    let bytes = io::unseal(RETIRED_SHIELDING_KEYS_FILE)?;
+    let retired = RetiredKeys::decode(&mut bytes.as_slice())
+        .sgx_error_with_log("[Enclave] Invalid retired shielding keys")?;
+    if now() > retired.valid_until {
+        info!("[Enclave] Grace period of retired shielding keys is over, deleting them");
+        fs::remove_file(RETIRED_SHIELDING_KEYS_FILE).sgx_error_with_log(&format!(
+            "[Enclave] Removing '{}' failed",
+            RETIRED_SHIELDING_KEYS_FILE
+        ))?;
+        return Ok(None);
+    }
+    Ok(Some(retired))
+}
+
+/// Unix time as told by the host. SGX has no trusted clock, so the host can stretch the grace
+/// period by holding its clock back. That only keeps the retired keys usable for longer: they are
+/// still dropped by the next rotation and never leave the enclave.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Encrypts a response to the X25519 key of the requester.
+pub fn encrypt_to(plaintext: &[u8], recipient: &X25519Key) -> SgxResult<Vec<u8>> {
+    let mut rand =
+        StdRng::new().sgx_error_with_log("[Enclave] Could not create random number generator")?;
+    let mut ephemeral_secret = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    rand.fill_bytes(&mut ephemeral_secret);
+    rand.fill_bytes(&mut nonce);
+    envelope::encrypt_x25519(plaintext, recipient, ephemeral_secret, nonce)
+        .sgx_error_with_log("[Enclave] Could not encrypt the response")
+}
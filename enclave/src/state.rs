@@ -15,6 +15,9 @@
 
 */
 
+use std::fs;
+use std::path::Path;
+use std::string::String;
 use std::vec::Vec;
 
 use log::*;
@@ -22,26 +25,73 @@ use sgx_tcrypto::rsgx_sha256_slice;
 use sgx_types::*;
 
 use crate::aes;
-use crate::constants::{ENCRYPTED_STATE_FILE, SHARDS_PATH};
+use crate::constants::{
+    ENCRYPTED_STATE_FILE, MAX_RECORDED_STATES, PREVIOUS_STATE_FILE_SUFFIX,
+    ROTATED_STATE_FILE_SUFFIX, SHARDS_PATH, STATE_FORMAT_FILE, STATE_HASH_ALIAS_FILE,
+    STATE_RECORD_FILE,
+};
+use crate::counter::Counter;
 use crate::hex;
 use crate::io;
-use base58::ToBase58;
-use codec::Encode;
+use crate::ocall_state_recovered;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+use base58::{FromBase58, ToBase58};
+use codec::{Decode, Encode};
 use primitives::H256;
 use sgx_externalities::SgxExternalitiesTrait;
 use substratee_stf::{ShardIdentifier, State as StfState, Stf};
 
+/// What the enclave knows itself about the state of a shard. Sealed next to the state.
+///
+/// Each record is bound to a monotonic counter of its shard, which is incremented whenever a
+/// new record is sealed. A record the host replays together with the state it describes is
+/// refused, because the counter has moved on since.
+#[derive(Encode, Decode, Default)]
+struct StateRecord {
+    /// hash of the cyphertext the enclave has written latest
+    latest: H256,
+    /// the states the enclave has written and emitted a confirmation for, by the hash they are
+    /// confirmed under on chain. Oldest first
+    written: Vec<H256>,
+    counter: Counter,
+}
+
 pub fn load(shard: &ShardIdentifier) -> SgxResult<StfState> {
     // load last state
-    let state_path = format!(
-        "{}/{}/{}",
-        SHARDS_PATH,
-        shard.encode().to_base58(),
-        ENCRYPTED_STATE_FILE
-    );
-    debug!("loading state from: {}", state_path);
-    let state_vec = read(&state_path)?;
+    debug!("loading state of shard {}", shard.encode().to_base58());
+    let (_, state_vec) = read_recorded(shard)?;
+    Ok(decode(state_vec))
+}
+
+/// Loads the state of `shard` like [`load`] and checks it against the state hash that has been
+/// confirmed on chain latest.
+///
+/// Which state is loaded is decided by the enclave's own record, which can't be rolled back.
+/// `confirmed` comes from the host without a verified storage proof, so it can only make the
+/// enclave refuse: it has to name a state the enclave has written itself. States whose
+/// confirmation is still pending are fine.
+pub fn load_confirmed(shard: &ShardIdentifier, confirmed: Option<H256>) -> SgxResult<StfState> {
+    let (_, state_vec) = read_recorded(shard)?;
 
+    if let Some(confirmed) = confirmed {
+        let known = match unseal_record(shard)? {
+            Some(record) => record.written.contains(&confirmed),
+            None => false,
+        };
+        if !known {
+            error!(
+                "[Enclave] The enclave has never written the confirmed state 0x{} of shard {}. \
+                 Resync the shard through key provisioning from an up-to-date worker",
+                hex::encode_hex(confirmed.as_bytes()),
+                shard.encode().to_base58()
+            );
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+    }
+    Ok(decode(state_vec))
+}
+
+fn decode(state_vec: Vec<u8>) -> StfState {
     // state is now decrypted!
     let state: StfState = match state_vec.len() {
         0 => {
@@ -54,19 +104,14 @@ pub fn load(shard: &ShardIdentifier) -> SgxResult<StfState> {
         }
     };
     debug!("state decoded successfully");
-    Ok(state)
+    state
 }
 
 pub fn write(state: StfState, shard: &ShardIdentifier) -> SgxResult<H256> {
-    let state_path = format!(
-        "{}/{}/{}",
-        SHARDS_PATH,
-        shard.encode().to_base58(),
-        ENCRYPTED_STATE_FILE
-    );
+    let state_path = state_path(shard);
     debug!("writing state to: {}", state_path);
 
-    let cyphertext = encrypt(state.encode())?;
+    let cyphertext = encrypt(state.encode(), shard)?;
 
     let state_hash = match rsgx_sha256_slice(&cyphertext) {
         Ok(h) => h,
@@ -75,48 +120,594 @@ pub fn write(state: StfState, shard: &ShardIdentifier) -> SgxResult<H256> {
 
     debug!("new state hash=0x{}", hex::encode_hex(&state_hash));
 
-    io::write(&cyphertext, &state_path)?;
+    io::write_atomic(&cyphertext, &state_path)?;
+    // the new hash is going to be confirmed on chain directly
+    record_written(shard, state_hash.into(), state_hash.into())?;
+    remove_hash_alias(&alias_path(shard))?;
     Ok(state_hash.into())
 }
 
-fn read(path: &str) -> SgxResult<Vec<u8>> {
-    let mut bytes = match io::read(path) {
-        Ok(vec) => match vec.len() {
-            0 => return Ok(vec),
-            _ => vec,
+/// The encrypted state of `shard` as stored on disk, in the version [`load`] would use, with
+/// the hash it has been confirmed under on chain
+pub fn read_cyphertext(shard: &ShardIdentifier) -> SgxResult<(H256, Vec<u8>)> {
+    let (hash, bytes) = read_recorded_cyphertext(shard)?;
+    Ok((confirmed_hash_of(shard, hash)?, bytes))
+}
+
+/// Replaces the state of `shard` with `cyphertext`, which has been encrypted under the key of
+/// `shard` elsewhere and is confirmed on chain under `confirmed`. That differs from the hash of
+/// `cyphertext` if the sender re-encrypted the state since.
+pub fn write_cyphertext(
+    shard: &ShardIdentifier,
+    cyphertext: &[u8],
+    confirmed: H256,
+) -> SgxResult<()> {
+    let dir = format!("{}/{}", SHARDS_PATH, shard.encode().to_base58());
+    fs::create_dir_all(&dir).sgx_error_with_log(&format!("[Enclave] Creating '{}' failed", dir))?;
+    let hash: H256 = rsgx_sha256_slice(cyphertext)?.into();
+    if hash == confirmed {
+        remove_hash_alias(&alias_path(shard))?;
+    } else {
+        seal_hash_alias(&alias_path(shard), confirmed, hash)?;
+    }
+    io::write_atomic(cyphertext, &state_path(shard))?;
+    record_installed(shard, hash, confirmed)
+}
+
+/// Makes an encrypted `snapshot` of a former state of `shard` the current state again.
+///
+/// Only states that the enclave has written and emitted a confirmation for itself are
+/// accepted, as far back as it remembers them. The host can't bring back anything else, in
+/// particular no state that has been superseded before the enclave recorded its states.
+pub fn restore(shard: &ShardIdentifier, snapshot: &[u8]) -> SgxResult<H256> {
+    let state_hash: H256 = rsgx_sha256_slice(snapshot)?.into();
+    let known = match unseal_record(shard)? {
+        Some(record) => record.written.contains(&state_hash),
+        None => false,
+    };
+    if !known {
+        error!(
+            "[Enclave] Refusing to restore state 0x{} of shard {}: not written by this enclave",
+            hex::encode_hex(state_hash.as_bytes()),
+            shard.encode().to_base58()
+        );
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    if !snapshot.is_empty() {
+        aes::decrypt(&aes::read_or_derive_shard_key(shard)?, snapshot)
+            .sgx_error_with_log("[Enclave] Snapshot doesn't authenticate under the shard key")?;
+    }
+
+    io::write_atomic(snapshot, &state_path(shard))?;
+    record_written(shard, state_hash, state_hash)?;
+    remove_hash_alias(&alias_path(shard))?;
+    info!(
+        "[Enclave] Restored state 0x{} of shard {}",
+        hex::encode_hex(state_hash.as_bytes()),
+        shard.encode().to_base58()
+    );
+    Ok(state_hash)
+}
+
+/// Re-encrypts all shards under a freshly generated master secret.
+///
+/// The old master secret stays sealed until every shard has been migrated, such that an
+/// interrupted rotation can be resumed by simply running it again.
+pub fn rotate_key() -> SgxResult<()> {
+    if aes::rotation_committed() {
+        info!("[Enclave] Resuming committed state key rotation");
+        return finish_key_rotation();
+    }
+
+    let next = aes::read_or_create_sealed_next()?;
+    let shards = list_shards()?;
+    for shard in shards.iter() {
+        let path = state_path(shard);
+        let (hash, mut bytes) = read_recorded(shard)?;
+        if !bytes.is_empty() {
+            bytes = aes::encrypt(&aes::derive_shard_key_from(&next, shard)?, &bytes)?;
+        }
+        io::write(&bytes, &format!("{}{}", path, ROTATED_STATE_FILE_SUFFIX))?;
+        seal_hash_alias(
+            &format!("{}{}", alias_path(shard), ROTATED_STATE_FILE_SUFFIX),
+            confirmed_hash_of(shard, hash)?,
+            rsgx_sha256_slice(&bytes)?.into(),
+        )?;
+        debug!("re-encrypted shard {}", shard.encode().to_base58());
+    }
+
+    aes::commit_next()?;
+    finish_key_rotation()
+}
+
+/// Migrates the states of earlier releases, which were encrypted with AES-OFB under the master
+/// secret itself, to AES-GCM under the shard keys. A migrated state keeps standing for the
+/// hash that has been confirmed on chain through a hash alias.
+///
+/// Earlier releases kept no record of the states they wrote, so the states found here are
+/// trusted once and recorded.
+///
+/// Runs once. Afterwards, a sealed marker makes the enclave reject the legacy format.
+pub fn migrate_legacy_format() -> SgxResult<()> {
+    if Path::new(STATE_FORMAT_FILE).exists() {
+        // fails if the host swapped the marker
+        io::unseal(STATE_FORMAT_FILE)?;
+        return Ok(());
+    }
+
+    let master = aes::read_or_create_sealed()?;
+    let shards = list_shards()?;
+    for shard in shards.iter() {
+        let path = state_path(shard);
+        let mut bytes = match io::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if bytes.is_empty() || unseal_record(shard)?.is_some() {
+            continue;
+        }
+        let hash: H256 = rsgx_sha256_slice(&bytes)?.into();
+        let confirmed = confirmed_hash_of(shard, hash)?;
+        if aes::has_header(&bytes) {
+            record_written(shard, hash, confirmed)?;
+            continue;
+        }
+        aes::decrypt_legacy(&master, &mut bytes)?;
+        let cyphertext = aes::encrypt(&aes::read_or_derive_shard_key(shard)?, &bytes)?;
+        let migrated: H256 = rsgx_sha256_slice(&cyphertext)?.into();
+        seal_hash_alias(&alias_path(shard), confirmed, migrated)?;
+        io::write_atomic(&cyphertext, &path)?;
+        record_written(shard, migrated, confirmed)?;
+        // the legacy version must not be recovered
+        remove_previous(shard)?;
+        info!(
+            "[Enclave] Migrated state of shard {} to the authenticated format",
+            shard.encode().to_base58()
+        );
+    }
+
+    io::seal(&[1u8], STATE_FORMAT_FILE)?;
+    Ok(())
+}
+
+/// Completes a rotation that has been interrupted after all shards were re-encrypted.
+pub fn finish_pending_key_rotation() -> SgxResult<()> {
+    if aes::rotation_committed() {
+        warn!("[Enclave] Found an interrupted state key rotation, completing it");
+        return finish_key_rotation();
+    }
+    Ok(())
+}
+
+fn finish_key_rotation() -> SgxResult<()> {
+    aes::seal_next_as_current()?;
+
+    let shards = list_shards()?;
+    // provisioned keys are superseded by the ones derived from the new master secret
+    for shard in shards.iter() {
+        aes::remove_shard_key(shard)?;
+    }
+    for shard in shards.iter() {
+        let path = state_path(shard);
+        let rotated = format!("{}{}", path, ROTATED_STATE_FILE_SUFFIX);
+        if Path::new(&rotated).exists() {
+            fs::rename(&rotated, &path)
+                .sgx_error_with_log(&format!("[Enclave] Replacing '{}' failed", path))?;
+        }
+        // sealed files can't be renamed
+        let alias = alias_path(shard);
+        let rotated_alias = format!("{}{}", alias, ROTATED_STATE_FILE_SUFFIX);
+        if let Some((confirmed, hash)) = unseal_hash_alias(&rotated_alias)? {
+            seal_hash_alias(&alias, confirmed, hash)?;
+            record_reencrypted(shard, hash)?;
+            remove_hash_alias(&rotated_alias)?;
+        }
+        // encrypted under the retired key
+        remove_previous(shard)?;
+    }
+
+    aes::remove_rotation_keys()?;
+    info!(
+        "[Enclave] State key rotation of {} shard(s) done",
+        shards.len()
+    );
+    Ok(())
+}
+
+/// All shards that have been initialized on this worker
+pub fn list_shards() -> SgxResult<Vec<ShardIdentifier>> {
+    if !Path::new(SHARDS_PATH).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut shards = Vec::new();
+    for entry in fs::read_dir(SHARDS_PATH)
+        .sgx_error_with_log(&format!("[Enclave] Reading '{}' failed", SHARDS_PATH))?
+    {
+        let name = entry
+            .sgx_error()?
+            .file_name()
+            .into_string()
+            .sgx_error_with_log("[Enclave] Invalid shard directory name")?;
+        match name.from_base58() {
+            Ok(ref s) if s.len() == 32 => shards.push(ShardIdentifier::from_slice(s)),
+            _ => warn!("ignoring '{}/{}': not a shard", SHARDS_PATH, name),
+        }
+    }
+    Ok(shards)
+}
+
+fn state_path(shard: &ShardIdentifier) -> String {
+    format!(
+        "{}/{}/{}",
+        SHARDS_PATH,
+        shard.encode().to_base58(),
+        ENCRYPTED_STATE_FILE
+    )
+}
+
+fn previous_path(path: &str) -> String {
+    format!("{}{}", path, PREVIOUS_STATE_FILE_SUFFIX)
+}
+
+fn remove_previous(shard: &ShardIdentifier) -> SgxResult<()> {
+    let prev = previous_path(&state_path(shard));
+    if !Path::new(&prev).exists() {
+        return Ok(());
+    }
+    fs::remove_file(&prev).sgx_error_with_log(&format!("[Enclave] Removing '{}' failed", prev))
+}
+
+fn alias_path(shard: &ShardIdentifier) -> String {
+    format!(
+        "{}/{}/{}",
+        SHARDS_PATH,
+        shard.encode().to_base58(),
+        STATE_HASH_ALIAS_FILE
+    )
+}
+
+/// Re-encrypting a state changes its hash without a new confirmation on chain. The enclave
+/// remembers which confirmed hash such a re-encrypted state stands for.
+fn seal_hash_alias(path: &str, confirmed: H256, hash: H256) -> SgxResult<sgx_status_t> {
+    io::seal(&(confirmed, hash).encode(), path)
+}
+
+fn unseal_hash_alias(path: &str) -> SgxResult<Option<(H256, H256)>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    let bytes = io::unseal(path)?;
+    Decode::decode(&mut bytes.as_slice())
+        .map(Some)
+        .sgx_error_with_log(&format!("[Enclave] Decoding '{}' failed", path))
+}
+
+fn remove_hash_alias(path: &str) -> SgxResult<()> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    fs::remove_file(path).sgx_error_with_log(&format!("[Enclave] Removing '{}' failed", path))
+}
+
+/// The hash under which the state with cyphertext hash `hash` has been confirmed on chain
+fn confirmed_hash_of(shard: &ShardIdentifier, hash: H256) -> SgxResult<H256> {
+    match unseal_hash_alias(&alias_path(shard))? {
+        Some((confirmed, aliased)) if aliased == hash => Ok(confirmed),
+        _ => Ok(hash),
+    }
+}
+
+fn record_path(shard: &ShardIdentifier) -> String {
+    format!(
+        "{}/{}/{}",
+        SHARDS_PATH,
+        shard.encode().to_base58(),
+        STATE_RECORD_FILE
+    )
+}
+
+/// The record of `shard`, if the enclave has sealed one. A record that has been superseded is
+/// refused.
+fn unseal_record(shard: &ShardIdentifier) -> SgxResult<Option<StateRecord>> {
+    let path = record_path(shard);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let bytes = io::unseal(&path)?;
+    let record = StateRecord::decode(&mut bytes.as_slice())
+        .sgx_error_with_log(&format!("[Enclave] Decoding '{}' failed", path))?;
+    if !record.counter.is_current()? {
+        error!(
+            "[Enclave] The state record of shard {} is outdated. Resync the shard through key \
+             provisioning from an up-to-date worker",
+            shard.encode().to_base58()
+        );
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(Some(record))
+}
+
+/// Seals `record` for `shard`, bound to the next value of the shard's counter.
+///
+/// The counter is incremented before the record is sealed, such that no two records ever
+/// carry the same value. A crash in between leaves the shard refusing its record until it is
+/// provisioned anew.
+fn seal_record(shard: &ShardIdentifier, mut record: StateRecord) -> SgxResult<()> {
+    if record.counter == Counter::default() {
+        record.counter = Counter::create()?;
+    } else {
+        record.counter.increment()?;
+    }
+    io::seal(&record.encode(), &record_path(shard))?;
+    Ok(())
+}
+
+/// Records the cyphertext with hash `latest` as the current state of `shard`. It is
+/// confirmed on chain under `confirmed`.
+fn record_written(shard: &ShardIdentifier, latest: H256, confirmed: H256) -> SgxResult<()> {
+    let mut record = unseal_record(shard)?.unwrap_or_default();
+    record.latest = latest;
+    record.written.retain(|h| *h != confirmed);
+    record.written.push(confirmed);
+    if record.written.len() > MAX_RECORDED_STATES {
+        let excess = record.written.len() - MAX_RECORDED_STATES;
+        record.written = record.written.split_off(excess);
+    }
+    seal_record(shard, record)
+}
+
+/// Records the cyphertext with hash `latest`, received from elsewhere, as the current state of
+/// `shard`. The states written before belong to a history that the received state supersedes.
+/// An outdated record is replaced, along with its counter.
+fn record_installed(shard: &ShardIdentifier, latest: H256, confirmed: H256) -> SgxResult<()> {
+    let counter = match unseal_record(shard) {
+        Ok(Some(record)) => record.counter,
+        _ => Counter::default(),
+    };
+    seal_record(
+        shard,
+        StateRecord {
+            latest,
+            written: vec![confirmed],
+            counter,
         },
-        Err(e) => return Err(e),
+    )
+}
+
+/// Records the cyphertext with hash `latest` as the current state of `shard`, which
+/// re-encrypts the state that was current before.
+fn record_reencrypted(shard: &ShardIdentifier, latest: H256) -> SgxResult<()> {
+    let mut record = unseal_record(shard)?.unwrap_or_default();
+    record.latest = latest;
+    seal_record(shard, record)
+}
+
+/// Reads the state of `shard` that the enclave has written latest. That is the current state
+/// file or, if the worker crashed before the enclave recorded a new state, the version it
+/// replaced. A state that has been written but not recorded has never been confirmed, so the
+/// older version is the right one to continue from. Falling back to it is reported to the
+/// worker. Any other state is refused.
+///
+/// Returns the hash of the cyphertext alongside the decrypted state.
+fn read_recorded(shard: &ShardIdentifier) -> SgxResult<(H256, Vec<u8>)> {
+    let (hash, bytes) = read_recorded_cyphertext(shard)?;
+    if bytes.is_empty() {
+        return Ok((hash, bytes));
+    }
+
+    let bytes = aes::decrypt(&aes::read_or_derive_shard_key(shard)?, &bytes)?;
+    debug!("buffer decrypted = {:?}", bytes);
+    Ok((hash, bytes))
+}
+
+fn read_recorded_cyphertext(shard: &ShardIdentifier) -> SgxResult<(H256, Vec<u8>)> {
+    let path = state_path(shard);
+    let record = match unseal_record(shard)? {
+        Some(record) => record,
+        None => {
+            // only a shard that has never been written may come without a record
+            let bytes = io::read(&path)?;
+            if !bytes.is_empty() {
+                error!(
+                    "[Enclave] The state of shard {} has not been written by this enclave",
+                    shard.encode().to_base58()
+                );
+                return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+            }
+            return Ok((rsgx_sha256_slice(&bytes)?.into(), bytes));
+        }
     };
 
-    aes::de_or_encrypt(&mut bytes)?;
+    for candidate in [path.clone(), previous_path(&path)].iter() {
+        let bytes = match io::read(candidate) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let hash: H256 = rsgx_sha256_slice(&bytes)?.into();
+        if hash != record.latest {
+            continue;
+        }
+        if *candidate != path {
+            warn!(
+                "[Enclave] State '{}' has not been recorded, recovering previous version '{}'",
+                path, candidate
+            );
+            report_recovered(shard, hash);
+        }
+        return Ok((hash, bytes));
+    }
+
+    error!(
+        "[Enclave] Refusing to operate on a state of shard {} that the enclave has not \
+         written latest. Resync the shard through key provisioning from an up-to-date worker",
+        shard.encode().to_base58()
+    );
+    Err(sgx_status_t::SGX_ERROR_UNEXPECTED)
+}
+
+fn report_recovered(shard: &ShardIdentifier, hash: H256) {
+    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
+    let res = unsafe {
+        ocall_state_recovered(
+            &mut rt as *mut sgx_status_t,
+            shard.as_bytes().as_ptr(),
+            shard.as_bytes().len() as u32,
+            hash.as_bytes().as_ptr(),
+            hash.as_bytes().len() as u32,
+        )
+    };
+    if res != sgx_status_t::SGX_SUCCESS || rt != sgx_status_t::SGX_SUCCESS {
+        warn!("[Enclave] Failed to report the recovered state to the worker");
+    }
+}
+
+fn read(path: &str, shard: &ShardIdentifier) -> SgxResult<Vec<u8>> {
+    read_with_hash(path, shard).map(|(_, bytes)| bytes)
+}
+
+fn read_with_hash(path: &str, shard: &ShardIdentifier) -> SgxResult<(H256, Vec<u8>)> {
+    let bytes = io::read(path)?;
+    let hash = rsgx_sha256_slice(&bytes)?.into();
+    if bytes.is_empty() {
+        return Ok((hash, bytes));
+    }
+
+    let bytes = aes::decrypt(&aes::read_or_derive_shard_key(shard)?, &bytes)?;
     debug!("buffer decrypted = {:?}", bytes);
 
-    Ok(bytes)
+    Ok((hash, bytes))
 }
 
-fn write_encrypted(bytes: &mut Vec<u8>, path: &str) -> SgxResult<sgx_status_t> {
+fn write_encrypted(bytes: &[u8], path: &str, shard: &ShardIdentifier) -> SgxResult<sgx_status_t> {
     debug!("plaintext data to be written: {:?}", bytes);
 
-    aes::de_or_encrypt(bytes)?;
-
-    io::write(&bytes, path)?;
-    Ok(sgx_status_t::SGX_SUCCESS)
+    io::write(&encrypt(bytes.to_vec(), shard)?, path)
 }
 
-fn encrypt(mut state: Vec<u8>) -> SgxResult<Vec<u8>> {
-    aes::de_or_encrypt(&mut state)?;
-    Ok(state)
+fn encrypt(state: Vec<u8>, shard: &ShardIdentifier) -> SgxResult<Vec<u8>> {
+    aes::encrypt(&aes::read_or_derive_shard_key(shard)?, &state)
 }
 
 pub fn test_encrypted_state_io_works() {
     let path = "test_state_file.bin";
     let plaintext = b"The quick brown fox jumps over the lazy dog.";
+    let shard = ShardIdentifier::default();
     aes::create_sealed().unwrap();
 
-    aes::de_or_encrypt(&mut plaintext.to_vec()).unwrap();
-    write_encrypted(&mut plaintext.to_vec(), path).unwrap();
-    let state: Vec<u8> = read(path).unwrap();
+    write_encrypted(plaintext, path, &shard).unwrap();
+    let state: Vec<u8> = read(path, &shard).unwrap();
 
     assert_eq!(state, plaintext.to_vec());
     std::fs::remove_file(path).unwrap();
 }
+
+pub fn test_shard_keys_are_distinct() {
+    let shard_a = ShardIdentifier::from_low_u64_be(1);
+    let shard_b = ShardIdentifier::from_low_u64_be(2);
+    aes::create_sealed().unwrap();
+
+    let key_a = aes::derive_shard_key(&shard_a).unwrap();
+    assert_eq!(key_a, aes::derive_shard_key(&shard_a).unwrap());
+    assert_ne!(key_a, aes::derive_shard_key(&shard_b).unwrap());
+
+    let cyphertext = aes::encrypt(&key_a, b"The quick brown fox jumps over the lazy dog.").unwrap();
+    assert!(aes::decrypt(&aes::derive_shard_key(&shard_b).unwrap(), &cyphertext).is_err());
+}
+
+pub fn test_legacy_state_is_migrated() {
+    let shard = ShardIdentifier::from_low_u64_be(28);
+    let plaintext = b"The quick brown fox jumps over the lazy dog.".to_vec();
+    aes::create_sealed().unwrap();
+    let _ = std::fs::remove_file(STATE_FORMAT_FILE);
+
+    // as written by earlier releases
+    let mut legacy = plaintext.clone();
+    aes::decrypt_legacy(&aes::read_sealed().unwrap(), &mut legacy).unwrap();
+    std::fs::create_dir_all(format!("{}/{}", SHARDS_PATH, shard.encode().to_base58())).unwrap();
+    io::write(&legacy, &state_path(&shard)).unwrap();
+    let legacy_hash: H256 = rsgx_sha256_slice(&legacy).unwrap().into();
+
+    migrate_legacy_format().unwrap();
+    let (hash, state) = read_with_hash(&state_path(&shard), &shard).unwrap();
+    assert_eq!(state, plaintext);
+    assert_eq!(confirmed_hash_of(&shard, hash).unwrap(), legacy_hash);
+
+    // only once
+    io::write(&legacy, &state_path(&shard)).unwrap();
+    migrate_legacy_format().unwrap();
+    assert!(read_with_hash(&state_path(&shard), &shard).is_err());
+
+    std::fs::remove_dir_all(format!("{}/{}", SHARDS_PATH, shard.encode().to_base58())).unwrap();
+    std::fs::remove_file(STATE_FORMAT_FILE).unwrap();
+}
+
+pub fn test_only_the_recorded_state_is_loaded() {
+    let shard = ShardIdentifier::from_low_u64_be(29);
+    let dir = format!("{}/{}", SHARDS_PATH, shard.encode().to_base58());
+    aes::create_sealed().unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    io::write(&[], &state_path(&shard)).unwrap();
+
+    let first = write(Stf::init_state(), &shard).unwrap();
+    let first_cyphertext = io::read(&state_path(&shard)).unwrap();
+    write(Stf::init_state(), &shard).unwrap();
+
+    // the confirmation of the latest state is still pending
+    assert!(load_confirmed(&shard, Some(first)).is_ok());
+    assert!(load_confirmed(&shard, Some(H256::from_low_u64_be(1))).is_err());
+
+    // rolled back by the host
+    io::write(&first_cyphertext, &state_path(&shard)).unwrap();
+    assert!(load(&shard).is_err());
+
+    // but the enclave restores a state it has written
+    assert_eq!(restore(&shard, &first_cyphertext).unwrap(), first);
+    assert!(load(&shard).is_ok());
+    assert!(restore(
+        &shard,
+        &aes::encrypt(&aes::derive_shard_key(&shard).unwrap(), b"forged").unwrap()
+    )
+    .is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+pub fn test_replayed_state_record_is_refused() {
+    let shard = ShardIdentifier::from_low_u64_be(129);
+    let dir = format!("{}/{}", SHARDS_PATH, shard.encode().to_base58());
+    aes::create_sealed().unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+
+    write(Stf::init_state(), &shard).unwrap();
+    let first_cyphertext = io::read(&state_path(&shard)).unwrap();
+    let first_record = io::read(&record_path(&shard)).unwrap();
+    write(Stf::init_state(), &shard).unwrap();
+
+    // rolled back by the host, together with the sealed record
+    io::write(&first_cyphertext, &state_path(&shard)).unwrap();
+    io::write(&first_record, &record_path(&shard)).unwrap();
+    assert!(load(&shard).is_err());
+    assert!(load_confirmed(&shard, None).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+pub fn test_tampered_state_fails_to_authenticate() {
+    let path = "test_tampered_state_file.bin";
+    let shard = ShardIdentifier::default();
+    aes::create_sealed().unwrap();
+
+    write_encrypted(
+        b"The quick brown fox jumps over the lazy dog.",
+        path,
+        &shard,
+    )
+    .unwrap();
+    let mut cyphertext = io::read(path).unwrap();
+    cyphertext[20] ^= 1;
+    io::write(&cyphertext, path).unwrap();
+
+    assert!(read(path, &shard).is_err());
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,30 +1,56 @@
 use std::backtrace::{self, PrintFormat};
-use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::sync::Arc;
+use std::path::Path;
+use std::slice;
+use std::sync::{Arc, SgxMutex};
 use std::vec::Vec;
 
+use sgx_tcrypto::rsgx_sha256_slice;
 use sgx_types::*;
 
+use base58::ToBase58;
+use codec::{Decode, Encode};
 use log::*;
+use primitives::{Pair, H256};
 use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Stream};
+use substratee_stf::{
+    enclave_keys_hash, envelope::X25519Key, ShardIdentifier, Stf, MAX_PROVISIONED_SHARDS,
+};
 
 use crate::aes;
-use crate::attestation::{create_ra_report_and_signature, DEV_HOSTNAME};
-use crate::cert;
-use crate::constants::ENCRYPTED_STATE_FILE;
-use crate::io;
+use crate::attestation::{create_ra_report_and_signature, get_mrenclave_of_self, DEV_HOSTNAME};
+use crate::attestation_policy::AttestationPolicy;
+use crate::cert::{self, PeerIdentity};
+use crate::constants::SHARDS_PATH;
+use crate::ed25519;
+use crate::hex;
+use crate::provisioning_policy;
+use crate::provisioning_protocol::{
+    self as protocol, Message, ShardKey, Transfer, CAP_IPFS_STATE, CAP_STREAM_STATE,
+    MAX_STATE_SIZE, PROTOCOL_VERSION,
+};
 use crate::rsa3072;
-use crate::utils::UnwrapOrSgxErrorUnexpected;
-use crate::{ocall_read_ipfs, ocall_write_ipfs};
+use crate::state;
+use crate::utils::{write_slice_and_whitespace_pad, UnwrapOrSgxErrorUnexpected};
+use crate::x25519;
+use crate::{ocall_read_ipfs, ocall_state_block_number, ocall_write_ipfs};
 
 struct ClientAuth {
-    outdated_ok: bool,
+    policy: AttestationPolicy,
+    /// the client, once it has been verified
+    peer: SgxMutex<Option<PeerIdentity>>,
 }
 
 impl ClientAuth {
-    fn new(outdated_ok: bool) -> ClientAuth {
-        ClientAuth { outdated_ok }
+    fn new(policy: AttestationPolicy) -> ClientAuth {
+        ClientAuth {
+            policy,
+            peer: SgxMutex::new(None),
+        }
+    }
+
+    fn peer(&self) -> Option<PeerIdentity> {
+        self.peer.lock().ok().and_then(|peer| peer.clone())
     }
 }
 
@@ -38,33 +64,21 @@ impl rustls::ClientCertVerifier for ClientAuth {
         _certs: &[rustls::Certificate],
     ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
         info!("client cert: {:?}", _certs);
-        // This call will automatically verify cert is properly signed
-        match cert::verify_mra_cert(&_certs[0].0) {
-            Ok(()) => Ok(rustls::ClientCertVerified::assertion()),
-            Err(sgx_status_t::SGX_ERROR_UPDATE_NEEDED) => {
-                if self.outdated_ok {
-                    warn!("outdated_ok is set, overriding outdated error");
-                    Ok(rustls::ClientCertVerified::assertion())
-                } else {
-                    Err(rustls::TLSError::WebPKIError(
-                        webpki::Error::ExtensionValueInvalid,
-                    ))
-                }
-            }
-            Err(_) => Err(rustls::TLSError::WebPKIError(
-                webpki::Error::ExtensionValueInvalid,
-            )),
+        let peer = verify_peer(_certs, &self.policy)?;
+        if let Ok(mut verified) = self.peer.lock() {
+            *verified = Some(peer);
         }
+        Ok(rustls::ClientCertVerified::assertion())
     }
 }
 
 struct ServerAuth {
-    outdated_ok: bool,
+    policy: AttestationPolicy,
 }
 
 impl ServerAuth {
-    fn new(outdated_ok: bool) -> ServerAuth {
-        ServerAuth { outdated_ok }
+    fn new(policy: AttestationPolicy) -> ServerAuth {
+        ServerAuth { policy }
     }
 }
 
@@ -77,34 +91,57 @@ impl rustls::ServerCertVerifier for ServerAuth {
         _ocsp: &[u8],
     ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
         info!("server cert: {:?}", _certs);
-        // This call will automatically verify cert is properly signed
-        match cert::verify_mra_cert(&_certs[0].0) {
-            Ok(()) => Ok(rustls::ServerCertVerified::assertion()),
-            Err(sgx_status_t::SGX_ERROR_UPDATE_NEEDED) => {
-                if self.outdated_ok {
-                    warn!("outdated_ok is set, overriding outdated error");
-                    Ok(rustls::ServerCertVerified::assertion())
-                } else {
-                    Err(rustls::TLSError::WebPKIError(
-                        webpki::Error::ExtensionValueInvalid,
-                    ))
-                }
-            }
-            Err(_) => Err(rustls::TLSError::WebPKIError(
-                webpki::Error::ExtensionValueInvalid,
-            )),
-        }
+        verify_peer(_certs, &self.policy).map(|_| rustls::ServerCertVerified::assertion())
     }
 }
 
+/// Checks the attestation of the peer against `policy` and that the provisioning policy
+/// allows it.
+fn verify_peer(
+    certs: &[rustls::Certificate],
+    policy: &AttestationPolicy,
+) -> Result<PeerIdentity, rustls::TLSError> {
+    let refuse = |reason: &str| {
+        error!("    [Enclave] (MU-RA) Refusing peer: {}", reason);
+        rustls::TLSError::WebPKIError(webpki::Error::ExtensionValueInvalid)
+    };
+    let cert = certs.first().ok_or_else(|| refuse("no certificate"))?;
+    // This call will automatically verify cert is properly signed
+    let peer = cert::verify_mra_cert(&cert.0, policy)
+        .map_err(|_| refuse("attestation is invalid or not accepted"))?;
+
+    let provisioning =
+        provisioning_policy::unseal().map_err(|_| refuse("no provisioning policy"))?;
+    let own = get_mrenclave_of_self().map_err(|_| refuse("own MRENCLAVE unknown"))?;
+    provisioning
+        .check(&peer, &own.m)
+        .map_err(|reason| refuse(&reason))?;
+    Ok(peer)
+}
+
+/// Serves one provisioning session on `socket_fd`. Sessions may run concurrently.
+///
+/// On success, `record` receives the SCALE encoded `(mr_enclave, signer, shards)` of the
+/// provisioned peer for the worker's audit log, `signer` being its ed25519 chain signer.
 #[no_mangle]
 pub unsafe extern "C" fn run_key_provisioning_server(
     socket_fd: c_int,
     sign_type: sgx_quote_sign_type_t,
+    state_transfer: u32,
+    record: *mut u8,
+    record_size: u32,
 ) -> sgx_status_t {
     let _ = backtrace::enable_backtrace("enclave.signed.so", PrintFormat::Short);
 
-    let cfg = match tls_server_config(sign_type) {
+    if !protocol::is_transfer(state_transfer) {
+        error!(
+            "    [Enclave] (MU-RA-Server) Unknown state transfer {}",
+            state_transfer
+        );
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    let (cfg, client_auth) = match tls_server_config(sign_type) {
         Ok(cfg) => cfg,
         Err(e) => return e,
     };
@@ -115,21 +152,108 @@ pub unsafe extern "C" fn run_key_provisioning_server(
     };
 
     let mut tls = rustls::Stream::new(&mut sess, &mut conn);
-    println!("    [Enclave] (MU-RA-Server) MU-RA successful sending keys");
+    println!("    [Enclave] (MU-RA-Server) MU-RA successful, waiting for hello");
 
-    let (rsa_pair, aes, enc_state) = match read_files_to_send() {
-        Ok((r, a, s)) => (r, a, s),
+    let (peer, signer, shards) = match serve_keys(&mut tls, state_transfer, &client_auth) {
+        Ok(served) => served,
         Err(e) => return e,
     };
+    println!("    [Enclave] (MU-RA-Server) Registration procedure successful!\n");
 
-    match send_files(&mut tls, &rsa_pair, &aes, &enc_state) {
-        Ok(_) => println!("    [Enclave] (MU-RA-Server) Registration procedure successful!\n"),
-        Err(e) => return e,
-    }
+    let record_slice = slice::from_raw_parts_mut(record, record_size as usize);
+    write_slice_and_whitespace_pad(record_slice, (peer.mr_enclave, signer, shards).encode());
 
     sgx_status_t::SGX_SUCCESS
 }
 
+/// Returns the peer, its chain signer and the shards whose keys and states have been delivered.
+fn serve_keys(
+    tls: &mut Stream<ServerSession, TcpStream>,
+    state_transfer: u32,
+    client_auth: &ClientAuth,
+) -> SgxResult<(PeerIdentity, [u8; 32], Vec<ShardIdentifier>)> {
+    let capabilities = match protocol::receive(tls)? {
+        Message::Hello {
+            version,
+            capabilities,
+        } if version == PROTOCOL_VERSION => capabilities,
+        Message::Hello { version, .. } => {
+            return Err(protocol::abort(
+                tls,
+                &format!(
+                    "protocol version {} is not supported, only {}",
+                    version, PROTOCOL_VERSION
+                ),
+            ))
+        }
+        other => return Err(protocol::unexpected(tls, "hello", &other)),
+    };
+    protocol::send(
+        tls,
+        &Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: state_transfer,
+        },
+    )?;
+
+    // the handshake, and thus the verification of the peer, is done once a message was received
+    let peer = match client_auth.peer() {
+        Some(peer) => peer,
+        None => return Err(protocol::abort(tls, "the peer has not been attested")),
+    };
+    let signer = match protocol::receive(tls)? {
+        Message::Identity {
+            signer,
+            rsa_pubkey_json,
+            x25519_pubkey,
+        } => {
+            let keys_hash = enclave_keys_hash(&signer, &rsa_pubkey_json, &x25519_pubkey);
+            if keys_hash != H256::from(peer.keys_hash) {
+                return Err(protocol::abort(
+                    tls,
+                    "the identity is not the one the RA certificate commits to",
+                ));
+            }
+            signer
+        }
+        other => return Err(protocol::unexpected(tls, "identity", &other)),
+    };
+
+    let shards = match protocol::receive(tls)? {
+        Message::ShardList(shards) => shards,
+        other => return Err(protocol::unexpected(tls, "shard list", &other)),
+    };
+    if shards.len() > MAX_PROVISIONED_SHARDS {
+        return Err(protocol::abort(
+            tls,
+            &format!(
+                "at most {} shards can be requested at once",
+                MAX_PROVISIONED_SHARDS
+            ),
+        ));
+    }
+    info!(
+        "    [Enclave] (MU-RA-Server) Peer requests keys for {} shard(s)",
+        shards.len()
+    );
+
+    let (shielding_keys, shard_keys, states) = read_files_to_send(&shards)
+        .map_err(|_| protocol::abort(tls, "the keys or states could not be read"))?;
+    if states.iter().any(|(_, s)| !s.is_empty()) && capabilities & state_transfer == 0 {
+        return Err(protocol::abort(
+            tls,
+            "the peer doesn't accept the state the way it is sent",
+        ));
+    }
+    let delivered = shard_keys.iter().map(|(shard, _, _)| *shard).collect();
+    send_files(tls, shielding_keys, shard_keys, &states, state_transfer)?;
+
+    match protocol::receive(tls)? {
+        Message::Ack => Ok((peer, signer, delivered)),
+        other => Err(protocol::unexpected(tls, "acknowledgement", &other)),
+    }
+}
+
 fn tls_server_sesssion_stream(
     socket_fd: i32,
     cfg: ServerConfig,
@@ -139,56 +263,127 @@ fn tls_server_sesssion_stream(
     Ok((sess, conn))
 }
 
-fn tls_server_config(sign_type: sgx_quote_sign_type_t) -> SgxResult<ServerConfig> {
+fn tls_server_config(
+    sign_type: sgx_quote_sign_type_t,
+) -> SgxResult<(ServerConfig, Arc<ClientAuth>)> {
     let (key_der, cert_der, _chain_signer_attn) =
         create_ra_report_and_signature(sign_type).sgx_error()?;
 
-    let mut cfg = rustls::ServerConfig::new(Arc::new(ClientAuth::new(true)));
+    let client_auth = Arc::new(ClientAuth::new(cert::attestation_policy()?));
+    let mut cfg = rustls::ServerConfig::new(client_auth.clone());
     let mut certs = Vec::new();
     certs.push(rustls::Certificate(cert_der));
     let privkey = rustls::PrivateKey(key_der);
     cfg.set_single_cert_with_ocsp_and_sct(certs, privkey, vec![], vec![])
         .sgx_error()?;
-    Ok(cfg)
+    Ok((cfg, client_auth))
 }
 
-fn read_files_to_send() -> SgxResult<(Vec<u8>, aes::Aes, Vec<u8>)> {
+/// Both shielding keys, and the key and encrypted state of each of `shards` we serve. Each
+/// state comes with the hash it is confirmed under on chain.
+fn read_files_to_send(
+    shards: &[ShardIdentifier],
+) -> SgxResult<((Vec<u8>, X25519Key), Vec<ShardKey>, Vec<(H256, Vec<u8>)>)> {
     let shielding_key = rsa3072::unseal_pair().sgx_error()?;
-    let aes = aes::read_or_create_sealed().sgx_error()?;
     let rsa_pair = serde_json::to_string(&shielding_key).sgx_error()?;
-    let enc_state = io::read(ENCRYPTED_STATE_FILE).sgx_error()?;
+    let x25519_secret = x25519::unseal_secret()?;
+
+    let mut shard_keys = Vec::new();
+    let mut states = Vec::new();
+    for shard in shards {
+        let shard_path = format!("{}/{}", SHARDS_PATH, shard.encode().to_base58());
+        if !Path::new(&shard_path).exists() {
+            // only hand out keys for shards we actually serve
+            warn!(
+                "    [Enclave] (MU-RA-Server) Refusing key for unknown shard {}",
+                shard.encode().to_base58()
+            );
+            continue;
+        }
+        let (key, iv) = aes::read_or_derive_shard_key(shard)?;
+        shard_keys.push((*shard, key, iv));
+        states.push(state::read_cyphertext(shard)?);
+    }
 
     let rsa_len = rsa_pair.as_bytes().len();
-    info!("    [Enclave] Read Shielding Key: {:?}", rsa_len);
-    info!("    [Enclave] Read AES key {:?}\nIV: {:?}\n", aes.0, aes.1);
+    info!("    [Enclave] Read Shielding Keys: {:?}", rsa_len);
+    info!(
+        "    [Enclave] Read keys and states of {} shard(s)",
+        shard_keys.len()
+    );
 
-    Ok((rsa_pair.as_bytes().to_vec(), aes, enc_state))
+    Ok((
+        (rsa_pair.as_bytes().to_vec(), x25519_secret),
+        shard_keys,
+        states,
+    ))
 }
 
 fn send_files(
     tls: &mut Stream<ServerSession, TcpStream>,
-    rsa_pair: &[u8],
-    aes: &(Vec<u8>, Vec<u8>),
+    (rsa_pair, x25519_secret): (Vec<u8>, X25519Key),
+    shard_keys: Vec<ShardKey>,
+    states: &[(H256, Vec<u8>)],
+    state_transfer: u32,
+) -> SgxResult<()> {
+    let shards: Vec<ShardIdentifier> = shard_keys.iter().map(|(shard, _, _)| *shard).collect();
+    protocol::send(
+        tls,
+        &Message::KeyBundle {
+            shielding_key: rsa_pair,
+            x25519_secret,
+            shard_keys,
+        },
+    )?;
+
+    println!("    [Enclave] (MU-RA-Server) Keys sent, sending the states");
+    for (shard, (confirmed_hash, enc_state)) in shards.iter().zip(states) {
+        send_state(tls, shard, *confirmed_hash, enc_state, state_transfer)?;
+    }
+    Ok(())
+}
+
+fn send_state(
+    tls: &mut Stream<ServerSession, TcpStream>,
+    shard: &ShardIdentifier,
+    confirmed_hash: H256,
     enc_state: &[u8],
+    state_transfer: u32,
 ) -> SgxResult<()> {
-    tls.write(&rsa_pair.len().to_le_bytes()).sgx_error()?;
-    tls.write(&rsa_pair).sgx_error()?;
-    tls.write(&aes.0[..]).sgx_error()?;
-    tls.write(&aes.1[..]).sgx_error()?;
+    let state_hash: H256 = rsgx_sha256_slice(enc_state)?.into();
+    let block_number = state_block_number(shard, &state_hash);
+
+    let transfer = if state_transfer == CAP_IPFS_STATE && !enc_state.is_empty() {
+        println!("    [Enclave] (MU-RA-Server) Writing state to IPFS (= file hosting service)");
+        Transfer::Ipfs(write_ipfs(tls, enc_state)?)
+    } else {
+        Transfer::Stream
+    };
 
     println!(
-        "    [Enclave] (MU-RA-Server) Keys sent, writing state to IPFS (= file hosting service)"
+        "    [Enclave] (MU-RA-Server) Sending state 0x{} of shard {} at block {}",
+        hex::encode_hex(state_hash.as_bytes()),
+        shard.encode().to_base58(),
+        block_number
     );
-    info!("    [Enclave] (MU-RA-Server) Sending encrypted state length");
-
-    tls.write(&enc_state.len().to_le_bytes()).sgx_error()?;
-    if enc_state.is_empty() {
-        println!(
-            "    [Enclave] (MU-RA-Server) No state has been written yet. Nothing to write to ipfs."
-        );
-        println!("    [Enclave] (MU-RA-Server) Registration procedure successful!\n");
-        return Ok(());
+    protocol::send(
+        tls,
+        &Message::ShardState {
+            shard: *shard,
+            state_hash,
+            confirmed_hash,
+            block_number,
+            size: enc_state.len() as u64,
+            transfer: transfer.clone(),
+        },
+    )?;
+    if transfer == Transfer::Stream {
+        protocol::send_stream(tls, enc_state)?;
     }
+    Ok(())
+}
+
+fn write_ipfs(tls: &mut Stream<ServerSession, TcpStream>, enc_state: &[u8]) -> SgxResult<Vec<u8>> {
     let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
     let mut cid_buf: [u8; 46] = [0; 46];
     let res = unsafe {
@@ -200,23 +395,66 @@ fn send_files(
             cid_buf.len() as u32,
         )
     };
-
-    if res == sgx_status_t::SGX_ERROR_UNEXPECTED || rt == sgx_status_t::SGX_ERROR_UNEXPECTED {
-        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    if res != sgx_status_t::SGX_SUCCESS || rt != sgx_status_t::SGX_SUCCESS {
+        return Err(protocol::abort(
+            tls,
+            "the state could not be written to IPFS",
+        ));
     }
+    Ok(cid_buf.to_vec())
+}
 
-    println!("    [Enclave] (MU-RA-Server) Write to IPFS successful, sending storage hash");
-    tls.write(&cid_buf).sgx_error()?;
-    Ok(())
+/// The block at which the worker snapshotted the state with `state_hash`, or 0 if its history
+/// doesn't tell.
+fn state_block_number(shard: &ShardIdentifier, state_hash: &H256) -> u64 {
+    let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
+    let mut block_number = 0u64;
+    let res = unsafe {
+        ocall_state_block_number(
+            &mut rt as *mut sgx_status_t,
+            shard.as_bytes().as_ptr(),
+            shard.as_bytes().len() as u32,
+            state_hash.as_bytes().as_ptr(),
+            state_hash.as_bytes().len() as u32,
+            &mut block_number as *mut u64,
+        )
+    };
+    if res != sgx_status_t::SGX_SUCCESS || rt != sgx_status_t::SGX_SUCCESS {
+        warn!("    [Enclave] (MU-RA-Server) The block number of the state is unknown");
+        return 0;
+    }
+    block_number
 }
 
 #[no_mangle]
-pub extern "C" fn request_key_provisioning(
+pub unsafe extern "C" fn request_key_provisioning(
     socket_fd: c_int,
     sign_type: sgx_quote_sign_type_t,
+    state_transfer: u32,
+    shards: *const u8,
+    shards_size: u32,
+    provisioned: *mut u8,
+    provisioned_size: u32,
 ) -> sgx_status_t {
     let _ = backtrace::enable_backtrace("enclave.signed.so", PrintFormat::Short);
 
+    if !protocol::is_transfer(state_transfer) {
+        error!(
+            "    [Enclave] (MU-RA-Client) Unknown state transfer {}",
+            state_transfer
+        );
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    let mut shards_slice = slice::from_raw_parts(shards, shards_size as usize);
+    let shards: Vec<ShardIdentifier> = match Decode::decode(&mut shards_slice) {
+        Ok(s) => s,
+        Err(_) => {
+            error!("    [Enclave] (MU-RA-Client) Could not decode requested shards");
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+
     let cfg = match tls_client_config(sign_type) {
         Ok(cfg) => cfg,
         Err(e) => return e,
@@ -230,82 +468,149 @@ pub extern "C" fn request_key_provisioning(
     let mut tls = rustls::Stream::new(&mut sess, &mut conn);
 
     println!();
-    println!("    [Enclave] (MU-RA-Client) MU-RA successful waiting for keys...");
+    println!("    [Enclave] (MU-RA-Client) MU-RA successful, requesting keys...");
 
-    match receive_files(&mut tls) {
-        Ok(_) => println!("    [Enclave] (MU-RA-Client) Registration procedure successful!\n"),
+    let installed = match fetch_keys(&mut tls, shards, state_transfer) {
+        Ok(installed) => installed,
         Err(e) => return e,
-    }
+    };
+    println!("    [Enclave] (MU-RA-Client) Registration procedure successful!\n");
+
+    let provisioned_slice = slice::from_raw_parts_mut(provisioned, provisioned_size as usize);
+    write_slice_and_whitespace_pad(provisioned_slice, installed.encode());
 
     sgx_status_t::SGX_SUCCESS
 }
 
-fn receive_files(tls: &mut Stream<ClientSession, TcpStream>) -> SgxResult<()> {
-    let mut key_len_arr = [0u8; 8];
-
-    let key_len = tls
-        .read(&mut key_len_arr)
-        .map(|_| usize::from_le_bytes(key_len_arr))
-        .sgx_error_with_log("    [Enclave] (MU-RA-Client) Error receiving shielding key length")?;
-
-    let mut rsa_pair = vec![0u8; key_len];
-    tls.read(&mut rsa_pair)
-        .map(|_| info!("    [Enclave] Received Shielding key"))
-        .sgx_error_with_log("    [Enclave] (MU-RA-Client) Error receiving shielding key")?;
-
-    rsa3072::seal(&rsa_pair)?;
-
-    let mut aes_key = [0u8; 16];
-    tls.read(&mut aes_key)
-        .map(|_| {
-            info!(
-                "    [Enclave] (MU-RA-Client)Received AES key: {:?}",
-                &aes_key[..]
-            )
-        })
-        .sgx_error_with_log("    [Enclave] (MU-RA-Client) Error receiving aes key ")?;
-
-    let mut aes_iv = [0u8; 16];
-    tls.read(&mut aes_iv)
-        .map(|_| {
-            info!(
-                "    [Enclave] (MU-RA-Client) Received AES IV: {:?}",
-                &aes_iv[..]
-            )
-        })
-        .sgx_error_with_log("    [Enclave] (MU-RA-Client) Error receiving aes iv")?;
-
-    aes::seal(aes_key, aes_iv)?;
-
-    println!("    [Enclave] (MU-RA-Client) Received and stored keys, waiting for storage hash...");
-
-    let mut state_len_arr = [0u8; 8];
-    let state_len = tls
-        .read(&mut state_len_arr)
-        .map(|_| usize::from_le_bytes(state_len_arr))
-        .sgx_error_with_log("Error receiving state length")?;
-
-    if state_len == 0 {
-        println!("    [Enclave] (MU-RA-Client) No state has been written yet, nothing to fetch from IPFS");
-        println!("    [Enclave] (MU-RA-Client) Registration Procedure successful!\n");
-        return Ok(());
+/// Returns the shards whose state has been installed, with the block number of each state.
+fn fetch_keys(
+    tls: &mut Stream<ClientSession, TcpStream>,
+    shards: Vec<ShardIdentifier>,
+    state_transfer: u32,
+) -> SgxResult<Vec<(ShardIdentifier, u64)>> {
+    protocol::send(
+        tls,
+        &Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: state_transfer,
+        },
+    )?;
+    match protocol::receive(tls)? {
+        Message::Hello { version, .. } if version == PROTOCOL_VERSION => (),
+        Message::Hello { version, .. } => {
+            return Err(protocol::abort(
+                tls,
+                &format!("protocol version {} is not supported", version),
+            ))
+        }
+        other => return Err(protocol::unexpected(tls, "hello", &other)),
     }
+    let identity =
+        own_identity().map_err(|_| protocol::abort(tls, "our keys could not be read"))?;
+    protocol::send(tls, &identity)?;
+    protocol::send(tls, &Message::ShardList(shards))?;
+
+    let installed = receive_files(tls, state_transfer)?;
+    protocol::send(tls, &Message::Ack)?;
+    Ok(installed)
+}
 
-    let mut cid = [0u8; 46];
-    tls.read(&mut cid)
-        .map(|_| {
-            info!(
-                "    [Enclave] (MU-RA-Client) Received ipfs CID: {:?}",
-                &cid[..]
-            )
-        })
-        .sgx_error_with_log("    [Enclave] (MU-RA-Client) Error receiving ipfs CID")?;
+/// The keys our RA certificate commits to.
+fn own_identity() -> SgxResult<Message> {
+    Ok(Message::Identity {
+        signer: ed25519::unseal_pair()?.public().0,
+        rsa_pubkey_json: serde_json::to_vec(&rsa3072::unseal_pubkey()?).sgx_error()?,
+        x25519_pubkey: x25519::unseal_pubkey()?,
+    })
+}
+
+fn receive_files(
+    tls: &mut Stream<ClientSession, TcpStream>,
+    state_transfer: u32,
+) -> SgxResult<Vec<(ShardIdentifier, u64)>> {
+    let (rsa_pair, x25519_secret, shard_keys) = match protocol::receive(tls)? {
+        Message::KeyBundle {
+            shielding_key,
+            x25519_secret,
+            shard_keys,
+        } => (shielding_key, x25519_secret, shard_keys),
+        other => return Err(protocol::unexpected(tls, "key bundle", &other)),
+    };
+    info!("    [Enclave] Received Shielding keys");
+    rsa3072::seal(&rsa_pair)
+        .and_then(|_| x25519::seal(&x25519_secret))
+        .map_err(|_| protocol::abort(tls, "the shielding keys could not be stored"))?;
 
-    println!("    [Enclave] (MU-RA-Client) Received IPFS storage hash, reading from IPFS...");
+    println!(
+        "    [Enclave] (MU-RA-Client) Received keys for {} shard(s), waiting for the states...",
+        shard_keys.len()
+    );
+
+    // Each shard key is sealed only once the state encrypted under it has been installed, such
+    // that a state that is refused leaves the local state and key of its shard untouched.
+    let mut installed = Vec::new();
+    for (shard, key, iv) in shard_keys {
+        let (state_hash, confirmed_hash, block_number, size, transfer) =
+            match protocol::receive(tls)? {
+                Message::ShardState {
+                    shard: s,
+                    state_hash,
+                    confirmed_hash,
+                    block_number,
+                    size,
+                    transfer,
+                } if s == shard => (state_hash, confirmed_hash, block_number, size, transfer),
+                other => return Err(protocol::unexpected(tls, "state of the next shard", &other)),
+            };
+        if size > MAX_STATE_SIZE {
+            return Err(protocol::abort(tls, "the state exceeds the maximum size"));
+        }
+
+        let enc_state = match transfer {
+            _ if size == 0 => Vec::new(),
+            Transfer::Stream if state_transfer == CAP_STREAM_STATE => {
+                protocol::receive_stream(tls, size)?
+            }
+            Transfer::Ipfs(cid) if state_transfer == CAP_IPFS_STATE => {
+                read_ipfs(tls, size as usize, &cid)?
+            }
+            _ => {
+                return Err(protocol::abort(
+                    tls,
+                    "the state is sent in a way we didn't offer",
+                ))
+            }
+        };
+        let key = (key, iv);
+        install_state(&shard, &key, &enc_state, &state_hash, confirmed_hash)
+            .map_err(|_| protocol::abort(tls, "a shard state could not be installed"))?;
+        aes::seal_shard_key(&shard, &key)
+            .map_err(|_| protocol::abort(tls, "a shard key could not be stored"))?;
+        println!(
+            "    [Enclave] (MU-RA-Client) Installed state 0x{} of shard {} at block {}",
+            hex::encode_hex(state_hash.as_bytes()),
+            shard.encode().to_base58(),
+            block_number
+        );
+        installed.push((shard, block_number));
+    }
+
+    Ok(installed)
+}
+
+fn read_ipfs(
+    tls: &mut Stream<ClientSession, TcpStream>,
+    state_len: usize,
+    cid: &[u8],
+) -> SgxResult<Vec<u8>> {
+    if cid.len() != 46 {
+        return Err(protocol::abort(tls, "the IPFS CID must be 46 bytes"));
+    }
+    info!("    [Enclave] (MU-RA-Client) Received ipfs CID: {:?}", cid);
 
     let mut enc_state = vec![0u8; state_len];
     let mut rt: sgx_status_t = sgx_status_t::SGX_ERROR_UNEXPECTED;
-    let _res = unsafe {
+    let res = unsafe {
         ocall_read_ipfs(
             &mut rt as *mut sgx_status_t,
             enc_state.as_mut_ptr(),
@@ -314,14 +619,35 @@ fn receive_files(tls: &mut Stream<ClientSession, TcpStream>) -> SgxResult<()> {
             cid.len() as u32,
         )
     };
-    println!(
-        "    [Enclave] (MU-RA-Client) Got encrypted state from ipfs: {:?}\n",
-        enc_state
-    );
-    io::write(&enc_state, ENCRYPTED_STATE_FILE)?;
+    if res != sgx_status_t::SGX_SUCCESS || rt != sgx_status_t::SGX_SUCCESS {
+        return Err(protocol::abort(
+            tls,
+            "the state could not be read from IPFS",
+        ));
+    }
     println!("    [Enclave] (MU-RA-Client) Successfully read state from IPFS");
+    Ok(enc_state)
+}
 
-    Ok(())
+/// Replaces the local state of `shard` with a received one, which has to hash to `state_hash`
+/// and authenticate under the received shard key. It is confirmed on chain under
+/// `confirmed_hash`.
+fn install_state(
+    shard: &ShardIdentifier,
+    key: &aes::Aes,
+    enc_state: &[u8],
+    state_hash: &H256,
+    confirmed_hash: H256,
+) -> SgxResult<()> {
+    if rsgx_sha256_slice(enc_state)? != state_hash.to_fixed_bytes() {
+        error!("    [Enclave] (MU-RA-Client) The state doesn't match its hash");
+        return Err(sgx_status_t::SGX_ERROR_INVALID_PARAMETER);
+    }
+    if !enc_state.is_empty() {
+        aes::decrypt(key, enc_state)
+            .sgx_error_with_log("    [Enclave] (MU-RA-Client) The state doesn't authenticate")?;
+    }
+    state::write_cyphertext(shard, enc_state, confirmed_hash)
 }
 
 fn tls_client_session_stream(
@@ -345,8 +671,39 @@ fn tls_client_config(sign_type: sgx_quote_sign_type_t) -> SgxResult<ClientConfig
 
     cfg.set_single_client_cert(certs, privkey);
     cfg.dangerous()
-        .set_certificate_verifier(Arc::new(ServerAuth::new(true)));
+        .set_certificate_verifier(Arc::new(ServerAuth::new(cert::attestation_policy()?)));
     cfg.versions.clear();
     cfg.versions.push(rustls::ProtocolVersion::TLSv1_2);
     Ok(cfg)
 }
+
+pub fn test_provisioned_state_of_a_rotated_shard_loads() {
+    let shard = ShardIdentifier::from_low_u64_be(27);
+    let dir = format!("{}/{}", SHARDS_PATH, shard.encode().to_base58());
+    aes::create_sealed().unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    let confirmed = state::write(Stf::init_state(), &shard).unwrap();
+    state::rotate_key().unwrap();
+
+    // as sent by the provisioning worker
+    let key = aes::read_or_derive_shard_key(&shard).unwrap();
+    let (confirmed_hash, enc_state) = state::read_cyphertext(&shard).unwrap();
+    let state_hash: H256 = rsgx_sha256_slice(&enc_state).unwrap().into();
+    assert_eq!(confirmed_hash, confirmed);
+    assert_ne!(state_hash, confirmed);
+
+    // on a worker that has never seen the shard
+    std::fs::remove_dir_all(&dir).unwrap();
+    aes::remove_shard_key(&shard).unwrap();
+    let other_key = aes::derive_shard_key(&ShardIdentifier::from_low_u64_be(1)).unwrap();
+    let forged = aes::encrypt(&other_key, b"forged").unwrap();
+    let forged_hash: H256 = rsgx_sha256_slice(&forged).unwrap().into();
+    assert!(install_state(&shard, &key, &forged, &forged_hash, confirmed_hash).is_err());
+
+    install_state(&shard, &key, &enc_state, &state_hash, confirmed_hash).unwrap();
+    aes::seal_shard_key(&shard, &key).unwrap();
+    assert!(state::load_confirmed(&shard, Some(confirmed)).is_ok());
+
+    aes::remove_shard_key(&shard).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,78 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! X25519 shielding key, a lightweight alternative to the RSA-3072 key for encrypting
+//! trusted calls with ECIES (see `substratee_stf::envelope`).
+
+use std::path::Path;
+use std::sgxfs::SgxFile;
+
+use sgx_rand::{Rng, StdRng};
+use sgx_types::*;
+
+use log::*;
+use substratee_stf::envelope::{self, X25519Key};
+
+use crate::constants::X25519_SEALED_KEY_FILE;
+use crate::io;
+use crate::utils::UnwrapOrSgxErrorUnexpected;
+
+pub fn create_sealed_if_absent() -> SgxResult<sgx_status_t> {
+    if SgxFile::open(X25519_SEALED_KEY_FILE).is_err() {
+        if Path::new(X25519_SEALED_KEY_FILE).exists() {
+            error!(
+                "[Enclave] Keyfile {} exists but can't be opened. has it been written by the same enclave?",
+                X25519_SEALED_KEY_FILE
+            );
+            return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+        }
+        info!(
+            "[Enclave] Keyfile not found, creating new! {}",
+            X25519_SEALED_KEY_FILE
+        );
+        return create_sealed();
+    }
+    Ok(sgx_status_t::SGX_SUCCESS)
+}
+
+pub fn create_sealed() -> SgxResult<sgx_status_t> {
+    let mut secret = [0u8; 32];
+    StdRng::new()
+        .sgx_error_with_log("[Enclave] Could not create random number generator")?
+        .fill_bytes(&mut secret);
+    io::seal(&secret, X25519_SEALED_KEY_FILE)
+}
+
+/// Replaces the key, e.g. with the one of the enclave that provisions us.
+pub fn seal(secret: &X25519Key) -> SgxResult<sgx_status_t> {
+    io::seal(secret, X25519_SEALED_KEY_FILE)
+}
+
+pub fn unseal_secret() -> SgxResult<X25519Key> {
+    let secret = io::unseal(X25519_SEALED_KEY_FILE)?;
+    if secret.len() != 32 {
+        error!("[Enclave] Sealed X25519 key has invalid length");
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&secret);
+    Ok(key)
+}
+
+pub fn unseal_pubkey() -> SgxResult<X25519Key> {
+    Ok(envelope::x25519_public(&unseal_secret()?))
+}
@@ -15,7 +15,9 @@
 
 */
 
-use crate::{AccountId, ShardIdentifier, TrustedCall, TrustedGetter, TrustedOperationSigned};
+use crate::{
+    envelope, AccountId, ShardIdentifier, TrustedCall, TrustedGetter, TrustedOperationSigned,
+};
 use application_crypto::{ed25519, sr25519};
 use base58::{FromBase58, ToBase58};
 use clap::{Arg, ArgMatches};
@@ -24,6 +26,7 @@ use codec::Encode;
 use keystore::Store;
 use log::*;
 use primitives::{crypto::Ss58Codec, sr25519 as sr25519_core, Pair};
+use rand::RngCore;
 use runtime_primitives::traits::IdentifyAccount;
 use std::path::PathBuf;
 
@@ -82,6 +85,67 @@ pub fn cmd<'a>(
                     .default_value("//Alice")
                     .help("signer for publicly observable extrinsic"),
             )
+            .arg(
+                Arg::with_name("shielding-key")
+                    .long("shielding-key")
+                    .global(true)
+                    .takes_value(true)
+                    .possible_values(&["x25519", "rsa"])
+                    .default_value("x25519")
+                    .help("enclave key to encrypt trusted calls with"),
+            )
+            .arg(
+                Arg::with_name("ias-root-ca")
+                    .long("ias-root-ca")
+                    .global(true)
+                    .takes_value(true)
+                    .value_name("PEM FILE")
+                    .help("root CA of IAS report signing, defaults to Intel's"),
+            )
+            .arg(
+                Arg::with_name("dcap-root-ca")
+                    .long("dcap-root-ca")
+                    .global(true)
+                    .takes_value(true)
+                    .value_name("DER FILE")
+                    .help("Intel SGX root CA, to accept workers attested with DCAP"),
+            )
+            .arg(
+                Arg::with_name("accept-status")
+                    .long("accept-status")
+                    .global(true)
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .possible_values(&[
+                        "GROUP_OUT_OF_DATE",
+                        "CONFIGURATION_NEEDED",
+                        "SW_HARDENING_NEEDED",
+                    ])
+                    .help("quote status of the worker to accept besides OK, may be repeated"),
+            )
+            .arg(
+                Arg::with_name("max-report-age")
+                    .long("max-report-age")
+                    .global(true)
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .help("maximum age of the worker's attestation report. Default is 90 days"),
+            )
+            .arg(
+                Arg::with_name("allow-debug")
+                    .long("allow-debug")
+                    .global(true)
+                    .takes_value(false)
+                    .help("accept workers whose enclave runs in debug mode"),
+            )
+            .arg(
+                Arg::with_name("insecure")
+                    .long("insecure")
+                    .global(true)
+                    .takes_value(false)
+                    .help("talk to the worker without verifying its attestation"),
+            )
             .about("trusted calls to worker enclave")
         })
         .add_cmd(
@@ -241,8 +305,16 @@ pub fn cmd<'a>(
                     let who = get_pair_from_str(matches, arg_who);
                     let tgetter =
                         TrustedGetter::free_balance(sr25519_core::Public::from(who.public()));
-                    let tsgetter = tgetter.sign(&sr25519_core::Pair::from(who));
-                    perform_operation(matches, &TrustedOperationSigned::get(tsgetter));
+                    let mut response_secret = [0u8; 32];
+                    rand::thread_rng().fill_bytes(&mut response_secret);
+                    let tsgetter = tgetter.sign(
+                        &sr25519_core::Pair::from(who),
+                        envelope::x25519_public(&response_secret),
+                    );
+                    perform_operation(
+                        matches,
+                        &TrustedOperationSigned::get(tsgetter, response_secret),
+                    );
                     Ok(())
                 }),
         )
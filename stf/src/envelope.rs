@@ -0,0 +1,265 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Hybrid encryption for payloads that don't fit into a single RSA block.
+//!
+//! Wire format: `ENVELOPE_MAGIC || version || SCALE(Envelope)`. The payload is encrypted with
+//! a random AES-128-GCM key, which in turn is wrapped with the shielding key of the enclave.
+//! RSA is left to the caller, such that this module works on both sides of the enclave boundary.
+//!
+//! Version 2 is ECIES over X25519: the AES key is derived from an ephemeral-static
+//! Diffie-Hellman exchange with the enclave's X25519 key instead of being wrapped.
+
+use alloc::vec::Vec;
+
+use aes_gcm::aead::{generic_array::GenericArray, Aead, NewAead};
+use aes_gcm::Aes128Gcm;
+use codec::{Decode, Encode};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const ENVELOPE_MAGIC: [u8; 3] = *b"STE";
+/// RSA-OAEP wrapped AES-128-GCM key
+pub const ENVELOPE_VERSION_RSA: u8 = 1;
+/// AES-128-GCM key derived from an X25519 key agreement
+pub const ENVELOPE_VERSION_X25519: u8 = 2;
+
+const ECIES_INFO: &[u8] = b"substraTEE-ecies-aes128gcm";
+
+pub type AesKey = [u8; 16];
+pub type Nonce = [u8; 12];
+pub type X25519Key = [u8; 32];
+
+#[derive(Encode, Decode)]
+struct Envelope {
+    wrapped_key: Vec<u8>,
+    nonce: Nonce,
+    cyphertext: Vec<u8>,
+}
+
+#[derive(Encode, Decode)]
+struct EciesEnvelope {
+    ephemeral_public: X25519Key,
+    nonce: Nonce,
+    cyphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvelopeError {
+    NoEnvelope,
+    UnsupportedVersion(u8),
+    Malformed,
+    KeyWrap,
+    Cipher,
+}
+
+/// Whether `bytes` claim to be an envelope. Payloads that have been encrypted with RSA only
+/// (the legacy format) practically never start with the magic.
+pub fn is_envelope(bytes: &[u8]) -> bool {
+    bytes.len() > ENVELOPE_MAGIC.len() && bytes.starts_with(&ENVELOPE_MAGIC)
+}
+
+/// The envelope version, or `None` if `bytes` are no envelope.
+pub fn version(bytes: &[u8]) -> Option<u8> {
+    if is_envelope(bytes) {
+        Some(bytes[ENVELOPE_MAGIC.len()])
+    } else {
+        None
+    }
+}
+
+/// The X25519 public key belonging to `secret`.
+pub fn x25519_public(secret: &X25519Key) -> X25519Key {
+    *PublicKey::from(&StaticSecret::from(*secret)).as_bytes()
+}
+
+/// Encrypts `plaintext` under `key`, which is wrapped with `wrap_key`. `key` and `nonce` must
+/// be fresh random values.
+pub fn encrypt<W>(
+    plaintext: &[u8],
+    key: AesKey,
+    nonce: Nonce,
+    wrap_key: W,
+) -> Result<Vec<u8>, EnvelopeError>
+where
+    W: FnOnce(&[u8]) -> Result<Vec<u8>, ()>,
+{
+    let cyphertext = Aes128Gcm::new(GenericArray::clone_from_slice(&key))
+        .encrypt(GenericArray::from_slice(&nonce), plaintext)
+        .map_err(|_| EnvelopeError::Cipher)?;
+    let wrapped_key = wrap_key(&key).map_err(|_| EnvelopeError::KeyWrap)?;
+
+    let mut bytes = header(ENVELOPE_VERSION_RSA);
+    Envelope {
+        wrapped_key,
+        nonce,
+        cyphertext,
+    }
+    .encode_to(&mut bytes);
+    Ok(bytes)
+}
+
+/// Inverse of [`encrypt`]. `unwrap_key` recovers the AES key from its wrapped form.
+pub fn decrypt<U>(bytes: &[u8], unwrap_key: U) -> Result<Vec<u8>, EnvelopeError>
+where
+    U: FnOnce(&[u8]) -> Result<Vec<u8>, ()>,
+{
+    let envelope = Envelope::decode(&mut body(bytes, ENVELOPE_VERSION_RSA)?)
+        .map_err(|_| EnvelopeError::Malformed)?;
+
+    let key = unwrap_key(&envelope.wrapped_key).map_err(|_| EnvelopeError::KeyWrap)?;
+    if key.len() != 16 {
+        return Err(EnvelopeError::KeyWrap);
+    }
+    open(&key, &envelope.nonce, &envelope.cyphertext)
+}
+
+/// Encrypts `plaintext` to the X25519 key `recipient`. `ephemeral_secret` and `nonce` must be
+/// fresh random values.
+pub fn encrypt_x25519(
+    plaintext: &[u8],
+    recipient: &X25519Key,
+    ephemeral_secret: X25519Key,
+    nonce: Nonce,
+) -> Result<Vec<u8>, EnvelopeError> {
+    let ephemeral_public = x25519_public(&ephemeral_secret);
+    let shared = StaticSecret::from(ephemeral_secret).diffie_hellman(&PublicKey::from(*recipient));
+    let key = ecies_key(shared.as_bytes(), &ephemeral_public, recipient)?;
+
+    let cyphertext = Aes128Gcm::new(GenericArray::clone_from_slice(&key))
+        .encrypt(GenericArray::from_slice(&nonce), plaintext)
+        .map_err(|_| EnvelopeError::Cipher)?;
+
+    let mut bytes = header(ENVELOPE_VERSION_X25519);
+    EciesEnvelope {
+        ephemeral_public,
+        nonce,
+        cyphertext,
+    }
+    .encode_to(&mut bytes);
+    Ok(bytes)
+}
+
+/// Inverse of [`encrypt_x25519`], given the recipient's X25519 secret.
+pub fn decrypt_x25519(bytes: &[u8], secret: &X25519Key) -> Result<Vec<u8>, EnvelopeError> {
+    let envelope = EciesEnvelope::decode(&mut body(bytes, ENVELOPE_VERSION_X25519)?)
+        .map_err(|_| EnvelopeError::Malformed)?;
+
+    let static_secret = StaticSecret::from(*secret);
+    let recipient = *PublicKey::from(&static_secret).as_bytes();
+    let shared = static_secret.diffie_hellman(&PublicKey::from(envelope.ephemeral_public));
+    let key = ecies_key(shared.as_bytes(), &envelope.ephemeral_public, &recipient)?;
+    open(&key, &envelope.nonce, &envelope.cyphertext)
+}
+
+fn header(version: u8) -> Vec<u8> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.push(version);
+    bytes
+}
+
+fn body(bytes: &[u8], expected_version: u8) -> Result<&[u8], EnvelopeError> {
+    match version(bytes) {
+        None => Err(EnvelopeError::NoEnvelope),
+        Some(v) if v != expected_version => Err(EnvelopeError::UnsupportedVersion(v)),
+        Some(_) => Ok(&bytes[ENVELOPE_MAGIC.len() + 1..]),
+    }
+}
+
+fn open(key: &[u8], nonce: &Nonce, cyphertext: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    Aes128Gcm::new(GenericArray::clone_from_slice(key))
+        .decrypt(GenericArray::from_slice(nonce), cyphertext)
+        .map_err(|_| EnvelopeError::Cipher)
+}
+
+/// Both public keys go into the KDF such that the key is bound to this exchange.
+fn ecies_key(
+    shared: &[u8; 32],
+    ephemeral_public: &X25519Key,
+    recipient: &X25519Key,
+) -> Result<AesKey, EnvelopeError> {
+    let mut info = ECIES_INFO.to_vec();
+    info.extend_from_slice(ephemeral_public);
+    info.extend_from_slice(recipient);
+
+    let mut key = [0u8; 16];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .map_err(|_| EnvelopeError::Cipher)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_wrap(key: &[u8]) -> Result<Vec<u8>, ()> {
+        Ok(key.to_vec())
+    }
+
+    #[test]
+    fn encrypt_decrypt_works() {
+        let plaintext = [42u8; 1000];
+        let bytes = encrypt(&plaintext, [1u8; 16], [2u8; 12], no_wrap).unwrap();
+
+        assert!(is_envelope(&bytes));
+        assert_eq!(decrypt(&bytes, no_wrap).unwrap(), plaintext.to_vec());
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_envelope() {
+        let mut bytes = encrypt(b"memo", [1u8; 16], [2u8; 12], no_wrap).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert_eq!(decrypt(&bytes, no_wrap), Err(EnvelopeError::Cipher));
+    }
+
+    #[test]
+    fn decrypt_rejects_unknown_version() {
+        let mut bytes = encrypt(b"memo", [1u8; 16], [2u8; 12], no_wrap).unwrap();
+        bytes[ENVELOPE_MAGIC.len()] = 0xff;
+
+        assert_eq!(
+            decrypt(&bytes, no_wrap),
+            Err(EnvelopeError::UnsupportedVersion(0xff))
+        );
+    }
+
+    #[test]
+    fn x25519_encrypt_decrypt_works() {
+        let secret = [7u8; 32];
+        let plaintext = [42u8; 1000];
+        let bytes =
+            encrypt_x25519(&plaintext, &x25519_public(&secret), [3u8; 32], [2u8; 12]).unwrap();
+
+        assert_eq!(version(&bytes), Some(ENVELOPE_VERSION_X25519));
+        assert_eq!(decrypt_x25519(&bytes, &secret).unwrap(), plaintext.to_vec());
+    }
+
+    #[test]
+    fn x25519_decrypt_fails_with_wrong_secret() {
+        let bytes =
+            encrypt_x25519(b"memo", &x25519_public(&[7u8; 32]), [3u8; 32], [2u8; 12]).unwrap();
+
+        assert_eq!(
+            decrypt_x25519(&bytes, &[8u8; 32]),
+            Err(EnvelopeError::Cipher)
+        );
+    }
+}
@@ -25,15 +25,20 @@
 
 extern crate alloc;
 
+use alloc::vec::Vec;
+
 #[cfg(feature = "std")]
 extern crate clap;
 
 use codec::{Compact, Decode, Encode};
-use primitives::{sr25519, Pair, H256};
+use primitives::{ed25519, sr25519, Pair, H256};
 use runtime_primitives::{traits::Verify, AnySignature};
+use sha2::{Digest, Sha256};
 //pub use my_node_runtime::substratee_registry::ShardIdentifier;
 pub type ShardIdentifier = H256;
 
+pub mod envelope;
+
 #[cfg(feature = "sgx")]
 pub mod sgx;
 
@@ -57,7 +62,8 @@ pub type State = sr_io::SgxExternalities;
 #[allow(non_camel_case_types)]
 pub enum TrustedOperationSigned {
     call(TrustedCallSigned),
-    get(TrustedGetterSigned),
+    /// The secret belonging to the getter's response key stays with the requester
+    get(TrustedGetterSigned, envelope::X25519Key),
 }
 
 #[derive(Encode, Decode, Clone)]
@@ -112,10 +118,16 @@ impl TrustedGetter {
         }
     }
 
-    pub fn sign(&self, pair: &sr25519::Pair) -> TrustedGetterSigned {
-        let signature = pair.sign(self.encode().as_slice()).into();
+    /// The enclave encrypts the result to the X25519 `response_key`.
+    pub fn sign(
+        &self,
+        pair: &sr25519::Pair,
+        response_key: envelope::X25519Key,
+    ) -> TrustedGetterSigned {
+        let signature = pair.sign((self, &response_key).encode().as_slice()).into();
         TrustedGetterSigned {
             getter: self.clone(),
+            response_key,
             signature,
         }
     }
@@ -124,17 +136,28 @@ impl TrustedGetter {
 #[derive(Encode, Decode, Clone)]
 pub struct TrustedGetterSigned {
     pub getter: TrustedGetter,
+    pub response_key: envelope::X25519Key,
     pub signature: AnySignature,
 }
 
 impl TrustedGetterSigned {
-    pub fn new(getter: TrustedGetter, signature: AnySignature) -> Self {
-        TrustedGetterSigned { getter, signature }
+    pub fn new(
+        getter: TrustedGetter,
+        response_key: envelope::X25519Key,
+        signature: AnySignature,
+    ) -> Self {
+        TrustedGetterSigned {
+            getter,
+            response_key,
+            signature,
+        }
     }
 
     pub fn verify_signature(&self) -> bool {
-        self.signature
-            .verify(self.getter.encode().as_slice(), self.getter.account())
+        self.signature.verify(
+            (&self.getter, &self.response_key).encode().as_slice(),
+            self.getter.account(),
+        )
     }
 }
 
@@ -164,6 +187,87 @@ impl TrustedCallSigned {
     }
 }
 
+/// Hash under which a shielding public key is committed to, e.g. the JSON of an RSA key.
+pub fn shielding_key_hash(pubkey: &[u8]) -> H256 {
+    H256::from_slice(Sha256::digest(pubkey).as_slice())
+}
+
+/// Second half of the report data of an enclave's attestation report. Binds the chain signer and
+/// both shielding keys, such that clients can check the keys a worker hands out.
+pub fn enclave_keys_hash(
+    signer: &[u8; 32],
+    rsa_pubkey_json: &[u8],
+    x25519_pubkey: &envelope::X25519Key,
+) -> H256 {
+    let mut hasher = Sha256::new();
+    hasher.input(signer);
+    hasher.input(rsa_pubkey_json);
+    hasher.input(x25519_pubkey);
+    H256::from_slice(hasher.result().as_slice())
+}
+
+/// What an enclave presents to prove who it is: its RA certificate, whose report data binds the
+/// chain signer and both shielding keys.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct EnclaveCredentials {
+    pub ra_cert: Vec<u8>,
+    pub signer: ed25519::Public,
+    pub rsa_pubkey_json: Vec<u8>,
+    pub x25519_pubkey: envelope::X25519Key,
+}
+
+impl EnclaveCredentials {
+    /// The hash the report data of `ra_cert` has to carry for these keys.
+    pub fn keys_hash(&self) -> H256 {
+        enclave_keys_hash(&self.signer.0, &self.rsa_pubkey_json, &self.x25519_pubkey)
+    }
+}
+
+/// How many shards a peer may request in one key provisioning session. The worker sizes the
+/// buffer for the enclave's audit record by it.
+pub const MAX_PROVISIONED_SHARDS: usize = 64;
+
+/// A shard packed for migration to the enclave that owns the `target` shielding key.
+///
+/// The state stays encrypted under the shard key, which is wrapped to the target's shielding
+/// key. Hence the state hash is preserved and has to match the latest confirmed hash on chain
+/// before the target executes any call on it. Both ends have to be attested enclaves the
+/// provisioning policy allows; the worker checks that they are registered on chain.
+#[derive(Encode, Decode)]
+pub struct ShardBundle {
+    pub shard: ShardIdentifier,
+    pub state_hash: H256,
+    pub shard_key: Vec<u8>,
+    pub state: Vec<u8>,
+    pub signer: EnclaveCredentials,
+    pub signature: ed25519::Signature,
+}
+
+/// Announces that an enclave replaced its shielding keys. Calls encrypted to the old keys are
+/// still accepted until `grace_period_end` (unix time in seconds).
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ShieldingKeyRotation {
+    pub old_rsa_pubkey_hash: H256,
+    pub new_rsa_pubkey_hash: H256,
+    pub old_x25519_pubkey: envelope::X25519Key,
+    pub new_x25519_pubkey: envelope::X25519Key,
+    pub grace_period_end: u64,
+}
+
+/// A [`ShieldingKeyRotation`] signed with the enclave's ed25519 key, which is registered on chain.
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct ShieldingKeyRotationSigned {
+    pub rotation: ShieldingKeyRotation,
+    pub signer: ed25519::Public,
+    pub signature: ed25519::Signature,
+}
+
+impl ShieldingKeyRotationSigned {
+    pub fn verify_signature(&self) -> bool {
+        ed25519::Pair::verify(&self.signature, self.rotation.encode(), &self.signer)
+    }
+}
+
 // TODO: #91 signed return value
 /*
 pub struct TrustedReturnValue<T> {
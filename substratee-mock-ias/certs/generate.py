@@ -0,0 +1,75 @@
+#!/usr/bin/env python3
+"""Generates the test PKI of the mock IAS.
+
+The root CA replaces the IAS report signing CA in enclaves built with the `mock-ra` feature and
+also issues the TLS certificate, which is valid for the IAS hostname. Never use these keys for
+anything but tests.
+
+Requires the `cryptography` package. Run from this directory.
+"""
+
+import datetime
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import rsa
+from cryptography.x509.oid import ExtendedKeyUsageOID, NameOID
+
+IAS_HOSTNAME = "api.trustedservices.intel.com"
+NOT_BEFORE = datetime.datetime(2020, 1, 1)
+NOT_AFTER = datetime.datetime(2049, 12, 31)
+
+
+def name(cn):
+    return x509.Name([
+        x509.NameAttribute(NameOID.ORGANIZATION_NAME, "SubstraTEE test"),
+        x509.NameAttribute(NameOID.COMMON_NAME, cn),
+    ])
+
+
+def certificate(cn, key, issuer, issuer_key, serial, ca, extensions=()):
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(name(cn))
+        .issuer_name(issuer)
+        .public_key(key.public_key())
+        .serial_number(serial)
+        .not_valid_before(NOT_BEFORE)
+        .not_valid_after(NOT_AFTER)
+        .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+    )
+    for ext in extensions:
+        builder = builder.add_extension(ext, critical=False)
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def pem(cert):
+    return cert.public_bytes(serialization.Encoding.PEM)
+
+
+def main():
+    root_key = rsa.generate_private_key(public_exponent=65537, key_size=3072)
+    signing_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+    tls_key = rsa.generate_private_key(public_exponent=65537, key_size=2048)
+
+    root = certificate("Mock IAS Root CA", root_key, name("Mock IAS Root CA"), root_key, 1, True)
+    signing = certificate("Mock IAS Report Signing", signing_key, root.subject, root_key, 2, False)
+    tls = certificate("Mock IAS", tls_key, root.subject, root_key, 3, False, [
+        x509.SubjectAlternativeName([x509.DNSName(IAS_HOSTNAME), x509.DNSName("localhost")]),
+        x509.ExtendedKeyUsage([ExtendedKeyUsageOID.SERVER_AUTH]),
+    ])
+
+    pkcs8 = lambda key: key.private_bytes(
+        serialization.Encoding.PEM,
+        serialization.PrivateFormat.PKCS8,
+        serialization.NoEncryption(),
+    )
+    open("root_ca.pem", "wb").write(pem(root))
+    open("report_signing.pem", "wb").write(pem(signing))
+    open("report_signing_key.pem", "wb").write(pkcs8(signing_key))
+    open("tls.pem", "wb").write(pem(tls) + pem(root))
+    open("tls_key.pem", "wb").write(pkcs8(tls_key))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,272 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! A stand-in for the Intel Attestation Service, for tests in SGX simulation mode.
+//!
+//! It answers the requests of the enclave's IAS client: an empty SigRL for every EPID group
+//! and an `OK` attestation report for every quote, signed with the test PKI in `certs/`.
+//! Enclaves built with the `mock-ra` feature trust its root CA instead of Intel's.
+
+use chrono::Utc;
+use log::*;
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde_json::{json, Value};
+
+pub const ROOT_CA: &[u8] = include_bytes!("../certs/root_ca.pem");
+pub const REPORT_SIGNING_CERT: &[u8] = include_bytes!("../certs/report_signing.pem");
+pub const REPORT_SIGNING_KEY: &[u8] = include_bytes!("../certs/report_signing_key.pem");
+pub const TLS_CERT_CHAIN: &[u8] = include_bytes!("../certs/tls.pem");
+pub const TLS_KEY: &[u8] = include_bytes!("../certs/tls_key.pem");
+
+/// IAS reports contain the quote without its signature
+const QUOTE_BODY_SIZE: usize = 432;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    MalformedKey,
+    MalformedRequest,
+    SigningFailed,
+}
+
+pub struct MockIas {
+    signing_key: RsaKeyPair,
+    /// percent encoded PEM of the signing certificate and the root CA
+    signing_chain: String,
+    rng: SystemRandom,
+}
+
+impl MockIas {
+    pub fn new(
+        signing_key_pem: &[u8],
+        signing_cert_pem: &[u8],
+        root_ca_pem: &[u8],
+    ) -> Result<Self, Error> {
+        let key = pem_body(signing_key_pem).ok_or(Error::MalformedKey)?;
+        let signing_key = RsaKeyPair::from_pkcs8(&key).map_err(|_| Error::MalformedKey)?;
+        Ok(MockIas {
+            signing_key,
+            signing_chain: percent_encode(&[signing_cert_pem, root_ca_pem].concat()),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Answers an HTTP request of the enclave's IAS client.
+    pub fn respond(&self, request: &[u8]) -> Vec<u8> {
+        let request = String::from_utf8_lossy(request);
+        let mut parts = request.splitn(2, "\r\n\r\n");
+        let head = parts.next().unwrap_or_default();
+        let body = parts.next().unwrap_or_default();
+        let target: Vec<&str> = head.lines().next().unwrap_or_default().split(' ').collect();
+        debug!("request {:?}", target);
+
+        match target.as_slice() {
+            ["GET", path, ..] if path.contains("/attestation/v3/sigrl/") => {
+                http_response("200 OK", &[], "")
+            }
+            ["POST", path, ..] if path.ends_with("/attestation/v3/report") => {
+                match self.report_for_request(body) {
+                    Ok((report, signature)) => http_response(
+                        "200 OK",
+                        &[
+                            ("X-IASReport-Signature", &signature),
+                            ("X-IASReport-Signing-Certificate", &self.signing_chain),
+                        ],
+                        &report,
+                    ),
+                    Err(e) => {
+                        warn!("can't answer report request: {:?}", e);
+                        http_response("400 Bad Request", &[], "")
+                    }
+                }
+            }
+            _ => http_response("404 Not Found", &[], ""),
+        }
+    }
+
+    /// An attestation report for `quote` and its base64 encoded signature.
+    pub fn report(&self, quote: &[u8]) -> Result<(String, String), Error> {
+        let quote_body = &quote[..quote.len().min(QUOTE_BODY_SIZE)];
+        let report = json!({
+            "id": Utc::now().format("%s%9f").to_string(),
+            "timestamp": Utc::now().format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+            "version": 3,
+            "isvEnclaveQuoteStatus": "OK",
+            "isvEnclaveQuoteBody": base64::encode(quote_body),
+        })
+        .to_string();
+
+        let mut signature = vec![0u8; self.signing_key.public_modulus_len()];
+        self.signing_key
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &self.rng,
+                report.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| Error::SigningFailed)?;
+        Ok((report, base64::encode(&signature)))
+    }
+
+    fn report_for_request(&self, body: &str) -> Result<(String, String), Error> {
+        let request: Value =
+            serde_json::from_str(body.trim()).map_err(|_| Error::MalformedRequest)?;
+        let quote = request["isvEnclaveQuote"]
+            .as_str()
+            .and_then(|q| base64::decode(q).ok())
+            .ok_or(Error::MalformedRequest)?;
+        self.report(&quote)
+    }
+}
+
+impl Default for MockIas {
+    fn default() -> Self {
+        MockIas::new(REPORT_SIGNING_KEY, REPORT_SIGNING_CERT, ROOT_CA)
+            .expect("the bundled test key is valid")
+    }
+}
+
+/// Whether `buf` holds the headers and the complete body of an HTTP request.
+pub fn request_complete(buf: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(buf);
+    let header_end = match text.find("\r\n\r\n") {
+        Some(pos) => pos,
+        None => return false,
+    };
+    let content_length = text[..header_end]
+        .lines()
+        .filter_map(|line| {
+            let mut field = line.splitn(2, ':');
+            let name = field.next()?;
+            if name.eq_ignore_ascii_case("content-length") {
+                field.next()?.trim().parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .unwrap_or(0);
+    buf.len() >= header_end + 4 + content_length
+}
+
+fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        response += &format!("{}: {}\r\n", name, value);
+    }
+    response += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    response.into_bytes()
+}
+
+fn percent_encode(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| match *b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (*b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The DER content of the first PEM block.
+fn pem_body(pem: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(pem).ok()?;
+    let b64: String = text
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END"))
+        .collect();
+    base64::decode(&b64).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(response: &'a str, name: &str) -> &'a str {
+        response
+            .lines()
+            .find(|l| l.starts_with(name))
+            .map(|l| &l[name.len() + 2..])
+            .unwrap()
+    }
+
+    #[test]
+    fn report_is_signed_by_certificate_chaining_to_root() {
+        let ias = MockIas::default();
+        let quote = base64::encode(&[7u8; 1116][..]);
+        let request = format!(
+            "POST /sgx/dev/attestation/v3/report HTTP/1.1\r\nContent-Length:{}\r\n\r\n{{\"isvEnclaveQuote\":\"{}\"}}\r\n",
+            quote.len() + 24,
+            quote
+        );
+        assert!(request_complete(request.as_bytes()));
+        let response = String::from_utf8(ias.respond(request.as_bytes())).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let report = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        let parsed: Value = serde_json::from_str(report).unwrap();
+        assert_eq!(parsed["isvEnclaveQuoteStatus"], "OK");
+        assert_eq!(
+            base64::decode(parsed["isvEnclaveQuoteBody"].as_str().unwrap()).unwrap(),
+            vec![7u8; QUOTE_BODY_SIZE]
+        );
+
+        let signature = base64::decode(header(&response, "X-IASReport-Signature")).unwrap();
+        let signing_cert = pem_body(REPORT_SIGNING_CERT).unwrap();
+        let root = pem_body(ROOT_CA).unwrap();
+        let cert = webpki::EndEntityCert::from(&signing_cert).unwrap();
+        cert.verify_signature(
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+            report.as_bytes(),
+            &signature,
+        )
+        .unwrap();
+        cert.verify_is_valid_tls_server_cert(
+            &[&webpki::RSA_PKCS1_2048_8192_SHA256],
+            &webpki::TLSServerTrustAnchors(&[webpki::trust_anchor_util::cert_der_as_trust_anchor(
+                &root,
+            )
+            .unwrap()]),
+            &[],
+            webpki::Time::from_seconds_since_unix_epoch(1_600_000_000),
+        )
+        .unwrap();
+        assert!(header(&response, "X-IASReport-Signing-Certificate")
+            .starts_with("-----BEGIN%20CERTIFICATE-----%0A"));
+    }
+
+    #[test]
+    fn sigrl_is_empty_and_unknown_paths_are_rejected() {
+        let ias = MockIas::default();
+        let sigrl = ias.respond(b"GET /sgx/attestation/v3/sigrl/00000b1d HTTP/1.1\r\n\r\n");
+        assert!(String::from_utf8(sigrl)
+            .unwrap()
+            .contains("200 OK\r\nContent-Length: 0\r\n"));
+        let other = ias.respond(b"GET /index.html HTTP/1.1\r\n\r\n");
+        assert!(other.starts_with(b"HTTP/1.1 404"));
+        assert!(!request_complete(
+            b"POST /report HTTP/1.1\r\nContent-Length: 5\r\n\r\nab"
+        ));
+    }
+}
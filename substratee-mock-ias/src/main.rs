@@ -0,0 +1,91 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use clap::{App, Arg};
+use log::*;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session};
+
+use substratee_mock_ias::{request_complete, MockIas, TLS_CERT_CHAIN, TLS_KEY};
+
+fn main() {
+    env_logger::init();
+    let matches = App::new("substraTEE-mock-ias")
+        .about("Signs fake attestation reports for enclaves built with the mock-ra feature")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .takes_value(true)
+                .default_value("127.0.0.1:8443")
+                .help("address to accept the enclave's TLS connections on"),
+        )
+        .get_matches();
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    let chain = certs(&mut BufReader::new(TLS_CERT_CHAIN)).unwrap();
+    let key = pkcs8_private_keys(&mut BufReader::new(TLS_KEY)).unwrap()[0].clone();
+    config.set_single_cert(chain, key).unwrap();
+    let config = Arc::new(config);
+    let ias = Arc::new(MockIas::default());
+
+    let listen = matches.value_of("listen").unwrap();
+    let listener = TcpListener::bind(listen).unwrap();
+    println!("Mock IAS listening on {}", listen);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let config = config.clone();
+        let ias = ias.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &config, &ias) {
+                warn!("connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn serve(mut sock: TcpStream, config: &Arc<ServerConfig>, ias: &MockIas) -> std::io::Result<()> {
+    let mut session = ServerSession::new(config);
+    let mut request = Vec::new();
+    {
+        let mut tls = rustls::Stream::new(&mut session, &mut sock);
+        let mut buf = [0u8; 4096];
+        while !request_complete(&request) {
+            let n = tls.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        tls.write_all(&ias.respond(&request))?;
+    }
+    session.send_close_notify();
+    while session.wants_write() {
+        session.write_tls(&mut sock)?;
+    }
+    Ok(())
+}
@@ -93,6 +93,37 @@ where
     }
 }
 
+pub fn get_block_number<P: Pair>(api: &substrate_api_client::Api<P>) -> u64
+where
+    MultiSignature: From<P::Signature>,
+{
+    let result_str = api.get_storage("System", "Number", None).unwrap();
+    hexstr_to_u64(result_str).unwrap()
+}
+
+/// The state hash that has been published for `shard` with the latest `CallConfirmed`
+pub fn get_latest_state_hash<P: Pair>(
+    api: &substrate_api_client::Api<P>,
+    shard: &ShardIdentifier,
+) -> Option<Vec<u8>>
+where
+    MultiSignature: From<P::Signature>,
+{
+    let result_str = api
+        .get_storage("substraTEERegistry", "LatestIPFSHash", Some(shard.encode()))
+        .unwrap();
+    match result_str.as_str() {
+        "null" => {
+            info!(
+                "no state hash has been confirmed for shard {}",
+                shard.encode().to_base58()
+            );
+            None
+        }
+        _ => Decode::decode(&mut &hexstr_to_vec(result_str).unwrap()[..]).ok(),
+    }
+}
+
 fn hexstr_to_enclave(hexstr: String) -> Enclave<AccountId, Vec<u8>> {
     let unhex = hexstr_to_vec(hexstr).unwrap();
     Enclave::decode(&mut &unhex[..]).unwrap()
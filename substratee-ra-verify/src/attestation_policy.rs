@@ -0,0 +1,185 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! What a verifier accepts of otherwise valid attestation evidence.
+//!
+//! This file is shared with the enclave, which seals the policy, so it must only depend on `std`
+//! and `codec`.
+
+use std::prelude::v1::*;
+
+use codec::{Decode, Encode};
+
+/// Seconds in 90 days, Intel's recommendation for the age of an attestation report
+pub const DEFAULT_MAX_REPORT_AGE: u64 = 90 * 24 * 3600;
+
+/// `SGX_FLAGS_DEBUG` in the attributes of a report body
+const FLAGS_DEBUG: u8 = 0x02;
+
+/// Quote statuses besides `OK` that a verifier may accept. The platform is genuine, but it
+/// lacks updates or mitigations.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum QuoteStatus {
+    GroupOutOfDate,
+    ConfigurationNeeded,
+    SwHardeningNeeded,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyError {
+    StatusRejected(String),
+    StatusNotAccepted(QuoteStatus),
+    ReportTooOld,
+    DebugEnclave,
+}
+
+/// The default accepts quote status OK only, and no debug enclaves.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct AttestationPolicy {
+    pub accepted_statuses: Vec<QuoteStatus>,
+    /// in seconds
+    pub max_report_age: u64,
+    pub allow_debug: bool,
+}
+
+impl Default for AttestationPolicy {
+    fn default() -> Self {
+        AttestationPolicy {
+            accepted_statuses: vec![],
+            max_report_age: DEFAULT_MAX_REPORT_AGE,
+            allow_debug: false,
+        }
+    }
+}
+
+impl QuoteStatus {
+    /// The statuses an `isvEnclaveQuoteStatus` of IAS consists of, none for `OK`.
+    pub fn from_ias(status: &str) -> Result<Vec<QuoteStatus>, PolicyError> {
+        use QuoteStatus::*;
+        match status {
+            "OK" => Ok(vec![]),
+            "GROUP_OUT_OF_DATE" => Ok(vec![GroupOutOfDate]),
+            "CONFIGURATION_NEEDED" => Ok(vec![ConfigurationNeeded]),
+            "SW_HARDENING_NEEDED" => Ok(vec![SwHardeningNeeded]),
+            "CONFIGURATION_AND_SW_HARDENING_NEEDED" => {
+                Ok(vec![ConfigurationNeeded, SwHardeningNeeded])
+            }
+            other => Err(PolicyError::StatusRejected(other.to_string())),
+        }
+    }
+}
+
+impl AttestationPolicy {
+    pub fn check_status(&self, statuses: &[QuoteStatus]) -> Result<(), PolicyError> {
+        match statuses
+            .iter()
+            .find(|s| !self.accepted_statuses.contains(s))
+        {
+            Some(s) => Err(PolicyError::StatusNotAccepted(*s)),
+            None => Ok(()),
+        }
+    }
+
+    /// Both times in seconds since the unix epoch. Reports from the future are accepted, as the
+    /// clocks of IAS and verifier may differ.
+    pub fn check_report_age(&self, report_time: u64, now: u64) -> Result<(), PolicyError> {
+        if now.saturating_sub(report_time) > self.max_report_age {
+            return Err(PolicyError::ReportTooOld);
+        }
+        Ok(())
+    }
+
+    /// `attributes` of the attested report body, of which the first byte holds the debug flag.
+    pub fn check_debug(&self, attributes: &[u8]) -> Result<(), PolicyError> {
+        let debug = match attributes.first() {
+            Some(flags) => flags & FLAGS_DEBUG != 0,
+            None => true,
+        };
+        if debug && !self.allow_debug {
+            return Err(PolicyError::DebugEnclave);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_checked() {
+        let policy = AttestationPolicy {
+            accepted_statuses: vec![QuoteStatus::ConfigurationNeeded],
+            ..Default::default()
+        };
+        let combined = QuoteStatus::from_ias("CONFIGURATION_AND_SW_HARDENING_NEEDED").unwrap();
+        assert!(policy
+            .check_status(&QuoteStatus::from_ias("OK").unwrap())
+            .is_ok());
+        assert!(policy
+            .check_status(&QuoteStatus::from_ias("CONFIGURATION_NEEDED").unwrap())
+            .is_ok());
+        assert_eq!(
+            policy.check_status(&combined),
+            Err(PolicyError::StatusNotAccepted(
+                QuoteStatus::SwHardeningNeeded
+            ))
+        );
+        assert_eq!(
+            QuoteStatus::from_ias("GROUP_REVOKED"),
+            Err(PolicyError::StatusRejected("GROUP_REVOKED".to_string()))
+        );
+    }
+
+    #[test]
+    fn age_and_debug_are_checked() {
+        let policy = AttestationPolicy::default();
+        assert!(policy
+            .check_report_age(1000, 1000 + DEFAULT_MAX_REPORT_AGE)
+            .is_ok());
+        assert_eq!(
+            policy.check_report_age(1000, 1001 + DEFAULT_MAX_REPORT_AGE),
+            Err(PolicyError::ReportTooOld)
+        );
+        assert!(policy.check_report_age(2000, 1000).is_ok());
+
+        assert!(policy.check_debug(&[0x05, 0, 0]).is_ok());
+        assert_eq!(policy.check_debug(&[0x07]), Err(PolicyError::DebugEnclave));
+        let debug_ok = AttestationPolicy {
+            allow_debug: true,
+            ..Default::default()
+        };
+        assert!(debug_ok.check_debug(&[0x07]).is_ok());
+    }
+
+    #[test]
+    fn encoding_roundtrip() {
+        let policy = AttestationPolicy {
+            accepted_statuses: vec![QuoteStatus::SwHardeningNeeded],
+            max_report_age: 3600,
+            allow_debug: true,
+        };
+        let encoded = policy.encode();
+        assert_eq!(
+            AttestationPolicy::decode(&mut encoded.as_slice()).ok(),
+            Some(policy)
+        );
+        assert!(AttestationPolicy::decode(&mut &encoded[..8]).is_err());
+        // an unknown status
+        assert!(AttestationPolicy::decode(&mut &[4, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0][..]).is_err());
+    }
+}
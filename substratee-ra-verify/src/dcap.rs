@@ -0,0 +1,982 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Verification of DCAP (ECDSA) quotes against the collateral of the Intel PCS.
+//!
+//! This file is shared with the enclave, so it must not depend on anything but `std`, `ring`,
+//! `webpki`, `base64`, `serde_json` and `attestation_policy`. The caller supplies the current
+//! time.
+
+use std::prelude::v1::*;
+
+use ring::digest;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde_json::Value;
+
+use crate::attestation_policy::QuoteStatus;
+
+/// First field of RA certificate payloads carrying DCAP evidence. IAS reports start with `{`.
+pub const PAYLOAD_TAG: &str = "dcap";
+
+const QUOTE_VERSION: u16 = 3;
+const ATTESTATION_KEY_TYPE_ECDSA_P256: u16 = 2;
+const CERT_DATA_TYPE_PCK_CHAIN: u16 = 5;
+
+const HEADER_SIZE: usize = 48;
+const REPORT_BODY_SIZE: usize = 384;
+const SIGNATURE_SIZE: usize = 64;
+const ATTESTATION_KEY_SIZE: usize = 64;
+
+/// 1.2.840.113741.1.13.1, the SGX extension of PCK certificates
+const SGX_EXTENSION_OID: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF8, 0x4D, 0x01, 0x0D, 0x01];
+const SGX_EXTENSION_TCB: u8 = 2;
+const SGX_EXTENSION_FMSPC: u8 = 4;
+const SGX_TCB_PCESVN: u8 = 17;
+
+static PCK_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[&webpki::ECDSA_P256_SHA256];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DcapError {
+    MalformedQuote,
+    UnsupportedQuote,
+    MalformedCertificate,
+    UntrustedCertificate,
+    InvalidQeReportSignature,
+    AttestationKeyMismatch,
+    InvalidQuoteSignature,
+    MalformedCollateral,
+    InvalidCollateralSignature,
+    CollateralExpired,
+    FmspcMismatch,
+    TcbLevelNotFound,
+    QeIdentityMismatch,
+    Revoked,
+}
+
+/// The fields of an `sgx_report_body_t`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportBody {
+    pub cpu_svn: [u8; 16],
+    pub misc_select: u32,
+    pub attributes: [u8; 16],
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; 64],
+}
+
+impl ReportBody {
+    pub fn parse(body: &[u8]) -> Option<ReportBody> {
+        if body.len() != REPORT_BODY_SIZE {
+            return None;
+        }
+        let mut report = ReportBody {
+            cpu_svn: [0u8; 16],
+            misc_select: u32::from_le_bytes([body[16], body[17], body[18], body[19]]),
+            attributes: [0u8; 16],
+            mr_enclave: [0u8; 32],
+            mr_signer: [0u8; 32],
+            isv_prod_id: u16::from_le_bytes([body[256], body[257]]),
+            isv_svn: u16::from_le_bytes([body[258], body[259]]),
+            report_data: [0u8; 64],
+        };
+        report.cpu_svn.copy_from_slice(&body[..16]);
+        report.attributes.copy_from_slice(&body[48..64]);
+        report.mr_enclave.copy_from_slice(&body[64..96]);
+        report.mr_signer.copy_from_slice(&body[128..160]);
+        report.report_data.copy_from_slice(&body[320..384]);
+        Some(report)
+    }
+}
+
+/// The PCS collateral needed to verify a quote, as JSON documents and PEM issuer chains, and
+/// the revocation lists of the root CA and the CA that issued the PCK certificate, in DER, PEM
+/// or hex encoded DER.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Collateral {
+    pub tcb_info: Vec<u8>,
+    pub tcb_info_issuer_chain: Vec<u8>,
+    pub qe_identity: Vec<u8>,
+    pub qe_identity_issuer_chain: Vec<u8>,
+    pub pck_crl: Vec<u8>,
+    pub root_ca_crl: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TcbStatus {
+    UpToDate,
+    SwHardeningNeeded,
+    ConfigurationNeeded,
+    ConfigurationAndSwHardeningNeeded,
+    OutOfDate,
+    OutOfDateConfigurationNeeded,
+}
+
+impl TcbStatus {
+    /// The statuses to check against an `AttestationPolicy`.
+    pub fn quote_statuses(self) -> Vec<QuoteStatus> {
+        use QuoteStatus::*;
+        match self {
+            TcbStatus::UpToDate => vec![],
+            TcbStatus::SwHardeningNeeded => vec![SwHardeningNeeded],
+            TcbStatus::ConfigurationNeeded => vec![ConfigurationNeeded],
+            TcbStatus::ConfigurationAndSwHardeningNeeded => {
+                vec![ConfigurationNeeded, SwHardeningNeeded]
+            }
+            TcbStatus::OutOfDate => vec![GroupOutOfDate],
+            TcbStatus::OutOfDateConfigurationNeeded => vec![GroupOutOfDate, ConfigurationNeeded],
+        }
+    }
+}
+
+/// The result of a successful quote verification.
+#[derive(Debug, Clone)]
+pub struct Verified {
+    pub isv_report: ReportBody,
+    pub tcb_status: TcbStatus,
+    pub qe_tcb_status: TcbStatus,
+    pub fmspc: [u8; 6],
+}
+
+struct Quote<'a> {
+    signed: &'a [u8],
+    isv_report: ReportBody,
+    isv_signature: &'a [u8],
+    attestation_key: &'a [u8],
+    qe_report_raw: &'a [u8],
+    qe_report: ReportBody,
+    qe_report_signature: &'a [u8],
+    qe_auth_data: &'a [u8],
+    pck_chain: &'a [u8],
+}
+
+/// Encodes the evidence for the evidence extension of an RA certificate.
+pub fn encode_payload(quote: &[u8], collateral: &Collateral) -> String {
+    [
+        quote,
+        &collateral.tcb_info,
+        &collateral.tcb_info_issuer_chain,
+        &collateral.qe_identity,
+        &collateral.qe_identity_issuer_chain,
+        &collateral.pck_crl,
+        &collateral.root_ca_crl,
+    ]
+    .iter()
+    .fold(PAYLOAD_TAG.to_string(), |payload, field| {
+        payload + "|" + &base64::encode(field)
+    })
+}
+
+/// Decodes the quote and collateral of a payload written by `encode_payload`.
+pub fn decode_payload(payload: &[u8]) -> Option<(Vec<u8>, Collateral)> {
+    let mut fields = payload.split(|x| *x == b'|');
+    if fields.next()? != PAYLOAD_TAG.as_bytes() {
+        return None;
+    }
+    let mut fields = fields.map(base64::decode);
+    let mut next = || fields.next().and_then(|f| f.ok());
+    let quote = next()?;
+    let collateral = Collateral {
+        tcb_info: next()?,
+        tcb_info_issuer_chain: next()?,
+        qe_identity: next()?,
+        qe_identity_issuer_chain: next()?,
+        pck_crl: next()?,
+        root_ca_crl: next()?,
+    };
+    Some((quote, collateral))
+}
+
+/// Verifies `quote` against `collateral`, with all certificates chaining up to `root_ca_der`.
+///
+/// `now` is in seconds since the unix epoch. Certificates on the revocation lists of the
+/// collateral are refused with `DcapError::Revoked`.
+pub fn verify(
+    quote: &[u8],
+    collateral: &Collateral,
+    root_ca_der: &[u8],
+    now: u64,
+) -> Result<Verified, DcapError> {
+    let quote = parse_quote(quote)?;
+
+    // the PCK certificate signs the QE report, which in turn commits to the attestation key
+    let pck_chain = pem_certificates(quote.pck_chain);
+    let pck = pck_chain.first().ok_or(DcapError::MalformedCertificate)?;
+    let pck_cert = verify_chain(&pck_chain, root_ca_der, now)?;
+
+    // the PCK CRL is checked with the key of the CA that actually signed the PCK certificate,
+    // not with whatever certificate comes second in the chain
+    let pck_ca = pck_chain[1..]
+        .iter()
+        .find(|ca| is_signed_by(pck, ca))
+        .ok_or(DcapError::UntrustedCertificate)?;
+    let crls = [
+        verified_crl(&collateral.root_ca_crl, root_ca_der, now)?,
+        verified_crl(&collateral.pck_crl, pck_ca, now)?,
+    ];
+    not_revoked(&pck_chain, &crls)?;
+
+    pck_cert
+        .verify_signature(
+            &webpki::ECDSA_P256_SHA256,
+            quote.qe_report_raw,
+            &der_signature(quote.qe_report_signature),
+        )
+        .map_err(|_| DcapError::InvalidQeReportSignature)?;
+
+    let key_hash = sha256(&[quote.attestation_key, quote.qe_auth_data].concat());
+    if quote.qe_report.report_data[..32] != key_hash[..]
+        || quote.qe_report.report_data[32..].iter().any(|b| *b != 0)
+    {
+        return Err(DcapError::AttestationKeyMismatch);
+    }
+
+    let attestation_key = [&[0x04][..], quote.attestation_key].concat();
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &attestation_key)
+        .verify(quote.signed, quote.isv_signature)
+        .map_err(|_| DcapError::InvalidQuoteSignature)?;
+
+    let (fmspc, pck_tcb) = sgx_extension(pck)?;
+    let tcb_info = signed_json(
+        &collateral.tcb_info,
+        &collateral.tcb_info_issuer_chain,
+        "tcbInfo",
+        root_ca_der,
+        &crls,
+        now,
+    )?;
+    let tcb_status = tcb_status(&tcb_info, &fmspc, &pck_tcb)?;
+
+    let qe_identity = signed_json(
+        &collateral.qe_identity,
+        &collateral.qe_identity_issuer_chain,
+        "enclaveIdentity",
+        root_ca_der,
+        &crls,
+        now,
+    )?;
+    let qe_tcb_status = qe_identity_status(&qe_identity, &quote.qe_report)?;
+
+    Ok(Verified {
+        isv_report: quote.isv_report,
+        tcb_status,
+        qe_tcb_status,
+        fmspc,
+    })
+}
+
+/// The FMSPC of the platform and the CA (`"platform"` or `"processor"`) that issued its PCK
+/// certificate, which select the collateral to fetch for `quote`.
+pub fn collateral_id(quote: &[u8]) -> Result<([u8; 6], &'static str), DcapError> {
+    let quote = parse_quote(quote)?;
+    let chain = pem_certificates(quote.pck_chain);
+    let pck = chain.first().ok_or(DcapError::MalformedCertificate)?;
+    let (fmspc, _) = sgx_extension(pck)?;
+    let platform_ca = b"PCK Platform CA";
+    if pck.windows(platform_ca.len()).any(|w| w == platform_ca) {
+        Ok((fmspc, "platform"))
+    } else {
+        Ok((fmspc, "processor"))
+    }
+}
+
+fn parse_quote(quote: &[u8]) -> Result<Quote<'_>, DcapError> {
+    let signed = quote
+        .get(..HEADER_SIZE + REPORT_BODY_SIZE)
+        .ok_or(DcapError::MalformedQuote)?;
+    let version = u16::from_le_bytes([quote[0], quote[1]]);
+    let key_type = u16::from_le_bytes([quote[2], quote[3]]);
+    if version != QUOTE_VERSION || key_type != ATTESTATION_KEY_TYPE_ECDSA_P256 {
+        return Err(DcapError::UnsupportedQuote);
+    }
+
+    let mut reader = Reader(&quote[HEADER_SIZE + REPORT_BODY_SIZE..]);
+    let signature_data_len = reader.u32()? as usize;
+    if reader.0.len() != signature_data_len {
+        return Err(DcapError::MalformedQuote);
+    }
+    let isv_signature = reader.take(SIGNATURE_SIZE)?;
+    let attestation_key = reader.take(ATTESTATION_KEY_SIZE)?;
+    let qe_report_raw = reader.take(REPORT_BODY_SIZE)?;
+    let qe_report_signature = reader.take(SIGNATURE_SIZE)?;
+    let qe_auth_data_len = reader.u16()? as usize;
+    let qe_auth_data = reader.take(qe_auth_data_len)?;
+    if reader.u16()? != CERT_DATA_TYPE_PCK_CHAIN {
+        return Err(DcapError::UnsupportedQuote);
+    }
+    let cert_data_len = reader.u32()? as usize;
+    let pck_chain = reader.take(cert_data_len)?;
+
+    Ok(Quote {
+        signed,
+        isv_report: ReportBody::parse(&signed[HEADER_SIZE..]).ok_or(DcapError::MalformedQuote)?,
+        isv_signature,
+        attestation_key,
+        qe_report_raw,
+        qe_report: ReportBody::parse(qe_report_raw).ok_or(DcapError::MalformedQuote)?,
+        qe_report_signature,
+        qe_auth_data,
+        pck_chain,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DcapError> {
+        if self.0.len() < len {
+            return Err(DcapError::MalformedQuote);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16, DcapError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DcapError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+/// Checks that the first certificate chains up to the root CA through the others.
+fn verify_chain<'a>(
+    chain: &'a [Vec<u8>],
+    root_ca_der: &[u8],
+    now: u64,
+) -> Result<webpki::EndEntityCert<'a>, DcapError> {
+    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(root_ca_der)
+        .map_err(|_| DcapError::UntrustedCertificate)?;
+    let leaf = chain.first().ok_or(DcapError::MalformedCertificate)?;
+    let cert = webpki::EndEntityCert::from(leaf).map_err(|_| DcapError::MalformedCertificate)?;
+    let intermediates: Vec<&[u8]> = chain[1..].iter().map(|c| c.as_slice()).collect();
+    // the PCK and TCB signing certificates have no extended key usage, which webpki
+    // accepts for server certificates
+    cert.verify_is_valid_tls_server_cert(
+        PCK_SIG_ALGS,
+        &webpki::TLSServerTrustAnchors(&[anchor]),
+        &intermediates,
+        webpki::Time::from_seconds_since_unix_epoch(now),
+    )
+    .map_err(|_| DcapError::UntrustedCertificate)?;
+    Ok(cert)
+}
+
+/// Checks the signature of a PCS JSON document and returns its signed body.
+///
+/// PCS signs the exact bytes of the `key` value, so they are cut out of the text instead of
+/// re-serializing the parsed JSON.
+fn signed_json(
+    document: &[u8],
+    issuer_chain: &[u8],
+    key: &str,
+    root_ca_der: &[u8],
+    crls: &[Crl],
+    now: u64,
+) -> Result<Value, DcapError> {
+    let text = std::str::from_utf8(document).map_err(|_| DcapError::MalformedCollateral)?;
+    let prefix = format!("{{\"{}\":", key);
+    let separator = ",\"signature\":\"";
+    if !text.starts_with(&prefix) {
+        return Err(DcapError::MalformedCollateral);
+    }
+    let sig_pos = text
+        .rfind(separator)
+        .ok_or(DcapError::MalformedCollateral)?;
+    let body = &text[prefix.len()..sig_pos];
+    let signature = text[sig_pos + separator.len()..]
+        .split('"')
+        .next()
+        .and_then(decode_hex)
+        .ok_or(DcapError::MalformedCollateral)?;
+    if signature.len() != SIGNATURE_SIZE {
+        return Err(DcapError::MalformedCollateral);
+    }
+
+    let chain = pem_certificates(issuer_chain);
+    let signer = verify_chain(&chain, root_ca_der, now)?;
+    not_revoked(&chain, crls)?;
+    signer
+        .verify_signature(
+            &webpki::ECDSA_P256_SHA256,
+            body.as_bytes(),
+            &der_signature(&signature),
+        )
+        .map_err(|_| DcapError::InvalidCollateralSignature)?;
+
+    let body: Value = serde_json::from_str(body).map_err(|_| DcapError::MalformedCollateral)?;
+    let next_update = body["nextUpdate"]
+        .as_str()
+        .and_then(parse_time)
+        .ok_or(DcapError::MalformedCollateral)?;
+    if next_update < now {
+        return Err(DcapError::CollateralExpired);
+    }
+    Ok(body)
+}
+
+/// The serial numbers a CA has revoked.
+struct Crl {
+    issuer: Vec<u8>,
+    revoked: Vec<Vec<u8>>,
+}
+
+/// Checks that `issuer_der` signed the revocation list `crl` and that it is not outdated.
+fn verified_crl(crl: &[u8], issuer_der: &[u8], now: u64) -> Result<Crl, DcapError> {
+    let malformed = || DcapError::MalformedCollateral;
+    let der = crl_der(crl).ok_or_else(malformed)?;
+    let (tbs, signature) = signed_der(&der).ok_or_else(malformed)?;
+    webpki::EndEntityCert::from(issuer_der)
+        .map_err(|_| DcapError::MalformedCertificate)?
+        .verify_signature(&webpki::ECDSA_P256_SHA256, tbs, signature)
+        .map_err(|_| DcapError::InvalidCollateralSignature)?;
+
+    // TBSCertList: version, signature, issuer, thisUpdate, nextUpdate, revokedCertificates
+    let (mut fields, _) = der_element(tbs, 0x30).ok_or_else(malformed)?;
+    if let Some((_, rest)) = der_element(fields, 0x02) {
+        fields = rest;
+    }
+    let (_, rest) = der_element(fields, 0x30).ok_or_else(malformed)?;
+    let (issuer, rest) = der_element(rest, 0x30).ok_or_else(malformed)?;
+    let (_, rest) = der_time(rest).ok_or_else(malformed)?;
+    let (next_update, rest) = der_time(rest).ok_or_else(malformed)?;
+    if next_update < now {
+        return Err(DcapError::CollateralExpired);
+    }
+
+    let mut revoked = Vec::new();
+    if let Some((mut entries, _)) = der_element(rest, 0x30) {
+        while !entries.is_empty() {
+            let (entry, rest) = der_element(entries, 0x30).ok_or_else(malformed)?;
+            entries = rest;
+            let (serial, _) = der_element(entry, 0x02).ok_or_else(malformed)?;
+            revoked.push(serial.to_vec());
+        }
+    }
+    Ok(Crl {
+        issuer: issuer.to_vec(),
+        revoked,
+    })
+}
+
+/// Refuses the chain if a certificate in it is on the revocation list of its issuer.
+fn not_revoked(chain: &[Vec<u8>], crls: &[Crl]) -> Result<(), DcapError> {
+    for cert in chain {
+        let (issuer, serial) = issuer_and_serial(cert).ok_or(DcapError::MalformedCertificate)?;
+        if crls
+            .iter()
+            .any(|crl| crl.issuer == issuer && crl.revoked.iter().any(|r| r == serial))
+        {
+            return Err(DcapError::Revoked);
+        }
+    }
+    Ok(())
+}
+
+fn is_signed_by(cert_der: &[u8], issuer_der: &[u8]) -> bool {
+    match (
+        signed_der(cert_der),
+        webpki::EndEntityCert::from(issuer_der),
+    ) {
+        (Some((tbs, signature)), Ok(issuer)) => issuer
+            .verify_signature(&webpki::ECDSA_P256_SHA256, tbs, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// The issuer name and the serial number of a certificate.
+fn issuer_and_serial(cert_der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (tbs, _) = signed_der(cert_der)?;
+    let (fields, _) = der_element(tbs, 0x30)?;
+    let (_, rest) = der_element(fields, 0xA0)?;
+    let (serial, rest) = der_element(rest, 0x02)?;
+    let (_, rest) = der_element(rest, 0x30)?;
+    let (issuer, _) = der_element(rest, 0x30)?;
+    Some((issuer, serial))
+}
+
+/// Splits a certificate or revocation list into the encoded signed part and the signature.
+fn signed_der(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (signed, _) = der_element(der, 0x30)?;
+    let (_, rest) = der_element(signed, 0x30)?;
+    let tbs = &signed[..signed.len() - rest.len()];
+    let (_, rest) = der_element(rest, 0x30)?;
+    let (signature, _) = der_element(rest, 0x03)?;
+    // no unused bits
+    match signature.split_first()? {
+        (0, signature) => Some((tbs, signature)),
+        _ => None,
+    }
+}
+
+/// The DER of a revocation list, which the PCS hands out as DER, PEM or hex encoded DER.
+fn crl_der(crl: &[u8]) -> Option<Vec<u8>> {
+    const BEGIN: &str = "-----BEGIN X509 CRL-----";
+    const END: &str = "-----END X509 CRL-----";
+    let end = crl.iter().rposition(|b| *b != 0)? + 1;
+    let crl = &crl[..end];
+    if crl.first() == Some(&0x30) {
+        return Some(crl.to_vec());
+    }
+    let text = std::str::from_utf8(crl).ok()?.trim();
+    match text.split(BEGIN).nth(1) {
+        Some(armored) => {
+            let b64: String = armored
+                .split(END)
+                .next()?
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            base64::decode(&b64).ok()
+        }
+        None => decode_hex(text),
+    }
+}
+
+/// Seconds since the unix epoch of the `UTCTime` or `GeneralizedTime` at the start of `der`,
+/// and the remainder.
+fn der_time(der: &[u8]) -> Option<(u64, &[u8])> {
+    let (time, rest, century) = match *der.first()? {
+        0x17 => {
+            let (time, rest) = der_element(der, 0x17)?;
+            // years 50 to 99 are 1950 to 1999, RFC 5280 4.1.2.5.1
+            let century = if time.get(..2)? < &b"50"[..] {
+                "20"
+            } else {
+                "19"
+            };
+            (time, rest, century)
+        }
+        0x18 => {
+            let (time, rest) = der_element(der, 0x18)?;
+            (time, rest, "")
+        }
+        _ => return None,
+    };
+    let time = century.to_string() + std::str::from_utf8(time).ok()?;
+    if time.len() != 15 || !time.is_ascii() || !time.ends_with('Z') {
+        return None;
+    }
+    let iso = format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        &time[..4],
+        &time[4..6],
+        &time[6..8],
+        &time[8..10],
+        &time[10..12],
+        &time[12..14]
+    );
+    Some((parse_time(&iso)?, rest))
+}
+
+/// The status of the first TCB level that the platform's PCK TCB meets.
+fn tcb_status(tcb_info: &Value, fmspc: &[u8; 6], pck_tcb: &PckTcb) -> Result<TcbStatus, DcapError> {
+    let expected_fmspc = tcb_info["fmspc"]
+        .as_str()
+        .and_then(decode_hex)
+        .ok_or(DcapError::MalformedCollateral)?;
+    if expected_fmspc != fmspc {
+        return Err(DcapError::FmspcMismatch);
+    }
+    let levels = tcb_info["tcbLevels"]
+        .as_array()
+        .ok_or(DcapError::MalformedCollateral)?;
+    for level in levels {
+        let tcb = &level["tcb"];
+        let mut meets = true;
+        for (i, svn) in pck_tcb.components.iter().enumerate() {
+            let required = tcb[format!("sgxtcbcomp{:02}svn", i + 1)]
+                .as_u64()
+                .ok_or(DcapError::MalformedCollateral)?;
+            meets &= u64::from(*svn) >= required;
+        }
+        let required_pcesvn = tcb["pcesvn"]
+            .as_u64()
+            .ok_or(DcapError::MalformedCollateral)?;
+        if meets && u64::from(pck_tcb.pcesvn) >= required_pcesvn {
+            return status(&level["tcbStatus"]);
+        }
+    }
+    Err(DcapError::TcbLevelNotFound)
+}
+
+fn qe_identity_status(identity: &Value, qe_report: &ReportBody) -> Result<TcbStatus, DcapError> {
+    let hex_field = |name: &str| {
+        identity[name]
+            .as_str()
+            .and_then(decode_hex)
+            .ok_or(DcapError::MalformedCollateral)
+    };
+    let masked = |value: &[u8], mask: &[u8]| -> Vec<u8> {
+        value.iter().zip(mask).map(|(v, m)| v & m).collect()
+    };
+
+    let misc_select = qe_report.misc_select.to_be_bytes();
+    let misc_select_mask = hex_field("miscselectMask")?;
+    let attributes_mask = hex_field("attributesMask")?;
+    let isv_prod_id = identity["isvprodid"]
+        .as_u64()
+        .ok_or(DcapError::MalformedCollateral)?;
+    if hex_field("mrsigner")? != qe_report.mr_signer
+        || isv_prod_id != u64::from(qe_report.isv_prod_id)
+        || masked(&hex_field("miscselect")?, &misc_select_mask)
+            != masked(&misc_select, &misc_select_mask)
+        || masked(&hex_field("attributes")?, &attributes_mask)
+            != masked(&qe_report.attributes, &attributes_mask)
+    {
+        return Err(DcapError::QeIdentityMismatch);
+    }
+
+    let levels = identity["tcbLevels"]
+        .as_array()
+        .ok_or(DcapError::MalformedCollateral)?;
+    for level in levels {
+        let isv_svn = level["tcb"]["isvsvn"]
+            .as_u64()
+            .ok_or(DcapError::MalformedCollateral)?;
+        if u64::from(qe_report.isv_svn) >= isv_svn {
+            return status(&level["tcbStatus"]);
+        }
+    }
+    Err(DcapError::TcbLevelNotFound)
+}
+
+fn status(value: &Value) -> Result<TcbStatus, DcapError> {
+    match value.as_str().ok_or(DcapError::MalformedCollateral)? {
+        "UpToDate" => Ok(TcbStatus::UpToDate),
+        "SWHardeningNeeded" => Ok(TcbStatus::SwHardeningNeeded),
+        "ConfigurationNeeded" => Ok(TcbStatus::ConfigurationNeeded),
+        "ConfigurationAndSWHardeningNeeded" => Ok(TcbStatus::ConfigurationAndSwHardeningNeeded),
+        "OutOfDate" => Ok(TcbStatus::OutOfDate),
+        "OutOfDateConfigurationNeeded" => Ok(TcbStatus::OutOfDateConfigurationNeeded),
+        "Revoked" => Err(DcapError::Revoked),
+        _ => Err(DcapError::MalformedCollateral),
+    }
+}
+
+struct PckTcb {
+    components: [u8; 16],
+    pcesvn: u16,
+}
+
+/// The FMSPC and the TCB of the platform from the SGX extension of the PCK certificate.
+fn sgx_extension(pck_der: &[u8]) -> Result<([u8; 6], PckTcb), DcapError> {
+    let malformed = || DcapError::MalformedCertificate;
+    let oid = [
+        &[0x06, SGX_EXTENSION_OID.len() as u8][..],
+        SGX_EXTENSION_OID,
+    ]
+    .concat();
+    let offset = pck_der
+        .windows(oid.len())
+        .position(|window| window == &oid[..])
+        .ok_or_else(malformed)?;
+    let (extension, _) = der_element(&pck_der[offset + oid.len()..], 0x04).ok_or_else(malformed)?;
+    let (mut entries, _) = der_element(extension, 0x30).ok_or_else(malformed)?;
+
+    let mut fmspc = None;
+    let mut tcb = None;
+    while !entries.is_empty() {
+        let (entry, rest) = der_element(entries, 0x30).ok_or_else(malformed)?;
+        entries = rest;
+        let (id, value) = sgx_entry(entry).ok_or_else(malformed)?;
+        match id {
+            SGX_EXTENSION_FMSPC => fmspc = Some(value),
+            SGX_EXTENSION_TCB => tcb = Some(value),
+            _ => (),
+        }
+    }
+
+    let (fmspc_bytes, _) = der_element(fmspc.ok_or_else(malformed)?, 0x04).ok_or_else(malformed)?;
+    if fmspc_bytes.len() != 6 {
+        return Err(malformed());
+    }
+    let mut pck_tcb = PckTcb {
+        components: [0u8; 16],
+        pcesvn: 0,
+    };
+    let (mut components, _) =
+        der_element(tcb.ok_or_else(malformed)?, 0x30).ok_or_else(malformed)?;
+    while !components.is_empty() {
+        let (component, rest) = der_element(components, 0x30).ok_or_else(malformed)?;
+        components = rest;
+        let (id, value) = sgx_entry(component).ok_or_else(malformed)?;
+        match id {
+            1..=16 => {
+                pck_tcb.components[id as usize - 1] = der_uint(value).ok_or_else(malformed)? as u8
+            }
+            SGX_TCB_PCESVN => pck_tcb.pcesvn = der_uint(value).ok_or_else(malformed)?,
+            _ => (),
+        }
+    }
+
+    let mut fmspc = [0u8; 6];
+    fmspc.copy_from_slice(fmspc_bytes);
+    Ok((fmspc, pck_tcb))
+}
+
+/// Splits `SEQUENCE { OID <SGX extension>.(...).id, value }` into the last OID arc and the value.
+fn sgx_entry(entry: &[u8]) -> Option<(u8, &[u8])> {
+    let (oid, value) = der_element(entry, 0x06)?;
+    if oid.len() <= SGX_EXTENSION_OID.len() || !oid.starts_with(SGX_EXTENSION_OID) {
+        return None;
+    }
+    Some((*oid.last()?, value))
+}
+
+/// Returns the value and the remainder of the DER element with `tag` at the start of `der`.
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    if *der.first()? != tag {
+        return None;
+    }
+    let (len, header) = match *der.get(1)? {
+        short if short < 0x80 => (short as usize, 2),
+        0x81 => (*der.get(2)? as usize, 3),
+        0x82 => ((*der.get(2)? as usize) << 8 | *der.get(3)? as usize, 4),
+        _ => return None,
+    };
+    let end = header + len;
+    Some((der.get(header..end)?, der.get(end..)?))
+}
+
+fn der_uint(der: &[u8]) -> Option<u16> {
+    let (value, _) = der_element(der, 0x02)?;
+    match value {
+        [b] if *b < 0x80 => Some(u16::from(*b)),
+        [0, b] => Some(u16::from(*b)),
+        [hi, lo] if *hi < 0x80 => Some(u16::from(*hi) << 8 | u16::from(*lo)),
+        [0, hi, lo] => Some(u16::from(*hi) << 8 | u16::from(*lo)),
+        _ => None,
+    }
+}
+
+/// Converts a raw `r || s` P-256 signature to the ASN.1 form webpki expects.
+fn der_signature(raw: &[u8]) -> Vec<u8> {
+    let integer = |bytes: &[u8]| {
+        let start = bytes
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(bytes.len() - 1);
+        let mut value = bytes[start..].to_vec();
+        if value[0] & 0x80 != 0 {
+            value.insert(0, 0);
+        }
+        [vec![0x02, value.len() as u8], value].concat()
+    };
+    let (r, s) = raw.split_at(raw.len() / 2);
+    let body = [integer(r), integer(s)].concat();
+    [vec![0x30, body.len() as u8], body].concat()
+}
+
+/// The DER certificates of a PEM chain. Anything outside the armor, like a trailing NUL, is
+/// ignored.
+pub fn pem_certificates(pem: &[u8]) -> Vec<Vec<u8>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = String::from_utf8_lossy(pem);
+    text.split(END)
+        .filter_map(|block| block.split(BEGIN).nth(1))
+        .filter_map(|b64| {
+            let b64: String = b64.chars().filter(|c| !c.is_whitespace()).collect();
+            base64::decode(&b64).ok()
+        })
+        .collect()
+}
+
+pub(crate) fn sha256(data: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, data).as_ref().to_vec()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Seconds since the unix epoch of a `YYYY-MM-DDThh:mm:ssZ` timestamp.
+pub(crate) fn parse_time(time: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| time.get(range)?.parse::<u64>().ok();
+    if time.len() < 20 || !time.ends_with('Z') {
+        return None;
+    }
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if year < 1970 || month == 0 || month > 12 || day == 0 || day > 31 {
+        return None;
+    }
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTE: &[u8] = include_bytes!("../tests/fixtures/dcap/quote.bin");
+    const ROOT_CA: &[u8] = include_bytes!("../tests/fixtures/dcap/root_ca.der");
+    const TCB_INFO: &[u8] = include_bytes!("../tests/fixtures/dcap/tcb_info.json");
+    const QE_IDENTITY: &[u8] = include_bytes!("../tests/fixtures/dcap/qe_identity.json");
+    const TCB_CHAIN: &[u8] = include_bytes!("../tests/fixtures/dcap/tcb_signing_chain.pem");
+    const PCK_CRL: &[u8] = include_bytes!("../tests/fixtures/dcap/pck_crl.pem");
+    const PCK_CRL_REVOKED: &[u8] = include_bytes!("../tests/fixtures/dcap/pck_crl_revoked.pem");
+    const ROOT_CA_CRL: &[u8] = include_bytes!("../tests/fixtures/dcap/root_ca_crl.der");
+
+    // 2021-01-01
+    const NOW: u64 = 1_609_459_200;
+
+    fn collateral() -> Collateral {
+        Collateral {
+            tcb_info: TCB_INFO.to_vec(),
+            tcb_info_issuer_chain: TCB_CHAIN.to_vec(),
+            qe_identity: QE_IDENTITY.to_vec(),
+            qe_identity_issuer_chain: TCB_CHAIN.to_vec(),
+            pck_crl: PCK_CRL.to_vec(),
+            root_ca_crl: ROOT_CA_CRL.to_vec(),
+        }
+    }
+
+    // the fixtures are issued by a test root CA, see tests/fixtures/dcap/generate.py
+    #[test]
+    fn generated_quote_verifies() {
+        let verified = verify(QUOTE, &collateral(), ROOT_CA, NOW).unwrap();
+        assert_eq!(verified.isv_report.mr_enclave, [0xAB; 32]);
+        assert_eq!(verified.isv_report.report_data[..32], [0x11; 32]);
+        assert_eq!(verified.fmspc, [0x00, 0x90, 0x6E, 0xA1, 0x00, 0x00]);
+        // the PCE SVN of the platform is below the one of the first level
+        assert_eq!(verified.tcb_status, TcbStatus::OutOfDate);
+        assert_eq!(verified.qe_tcb_status, TcbStatus::UpToDate);
+    }
+
+    #[test]
+    fn tampered_quote_is_rejected() {
+        let mut quote = QUOTE.to_vec();
+        // mr_enclave
+        quote[HEADER_SIZE + 64] ^= 1;
+        assert_eq!(
+            verify(&quote, &collateral(), ROOT_CA, NOW).unwrap_err(),
+            DcapError::InvalidQuoteSignature
+        );
+        assert_eq!(
+            verify(&QUOTE[..500], &collateral(), ROOT_CA, NOW).unwrap_err(),
+            DcapError::MalformedQuote
+        );
+    }
+
+    #[test]
+    fn unknown_root_is_rejected() {
+        let tcb_signing_cert = &pem_certificates(TCB_CHAIN)[0];
+        assert_eq!(
+            verify(QUOTE, &collateral(), tcb_signing_cert, NOW).unwrap_err(),
+            DcapError::UntrustedCertificate
+        );
+    }
+
+    #[test]
+    fn tampered_or_expired_collateral_is_rejected() {
+        let mut collateral = collateral();
+        collateral.tcb_info = String::from_utf8(TCB_INFO.to_vec())
+            .unwrap()
+            .replacen("OutOfDate", "UpToDate", 1)
+            .into_bytes();
+        assert_eq!(
+            verify(QUOTE, &collateral, ROOT_CA, NOW).unwrap_err(),
+            DcapError::InvalidCollateralSignature
+        );
+
+        // after the next update of the collateral (2049-12-01), but before the fixture
+        // certificates expire (2049-12-31)
+        let after_next_update = parse_time("2049-12-15T00:00:00Z").unwrap();
+        assert_eq!(
+            verify(QUOTE, &self::collateral(), ROOT_CA, after_next_update).unwrap_err(),
+            DcapError::CollateralExpired
+        );
+    }
+
+    #[test]
+    fn revoked_or_forged_crl_is_rejected() {
+        let mut collateral = collateral();
+        collateral.pck_crl = PCK_CRL_REVOKED.to_vec();
+        assert_eq!(
+            verify(QUOTE, &collateral, ROOT_CA, NOW).unwrap_err(),
+            DcapError::Revoked
+        );
+
+        // the root CA CRL is not signed by the PCK CA
+        collateral.pck_crl = ROOT_CA_CRL.to_vec();
+        assert_eq!(
+            verify(QUOTE, &collateral, ROOT_CA, NOW).unwrap_err(),
+            DcapError::InvalidCollateralSignature
+        );
+
+        collateral.pck_crl = Vec::new();
+        assert_eq!(
+            verify(QUOTE, &collateral, ROOT_CA, NOW).unwrap_err(),
+            DcapError::MalformedCollateral
+        );
+    }
+
+    #[test]
+    fn crl_encodings_are_accepted() {
+        let der = crl_der(ROOT_CA_CRL).unwrap();
+        let hex: String = der.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(crl_der(hex.as_bytes()), Some(der.clone()));
+        assert_eq!(crl_der(&[&der[..], &[0][..]].concat()), Some(der));
+        assert_eq!(crl_der(PCK_CRL).map(|der| der[0]), Some(0x30));
+    }
+
+    #[test]
+    fn collateral_id_is_read_from_pck_certificate() {
+        assert_eq!(
+            collateral_id(QUOTE),
+            Ok(([0x00, 0x90, 0x6E, 0xA1, 0x00, 0x00], "platform"))
+        );
+    }
+
+    #[test]
+    fn payload_roundtrip() {
+        let payload = encode_payload(QUOTE, &collateral());
+        assert_eq!(
+            decode_payload(payload.as_bytes()),
+            Some((QUOTE.to_vec(), collateral()))
+        );
+        assert_eq!(decode_payload(b"{\"id\":\"1\"}|c2ln|Y2VydA=="), None);
+    }
+
+    #[test]
+    fn time_is_parsed() {
+        assert_eq!(parse_time("2021-01-01T00:00:00Z"), Some(NOW));
+        assert_eq!(parse_time("2021-13-01T00:00:00Z"), None);
+    }
+}
@@ -0,0 +1,456 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Checks the attestation evidence of substraTEE workers outside of SGX.
+//!
+//! The RA certificate of an enclave carries the IAS attestation report, or a DCAP quote with its
+//! collateral, in a dedicated extension, see `ra_cert`. The report data of the attested quote is
+//! `sha256(certificate public key) || substratee_stf::enclave_keys_hash(..)`.
+
+use log::*;
+use serde_json::Value;
+
+pub mod attestation_policy;
+pub mod dcap;
+pub mod ra_cert;
+
+use attestation_policy::{AttestationPolicy, PolicyError, QuoteStatus};
+
+static IAS_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Offset of the report body within an `sgx_quote_t`
+const QUOTE_REPORT_BODY_OFFSET: usize = 48;
+const REPORT_BODY_SIZE: usize = 384;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Certificate(ra_cert::CertError),
+    MalformedPayload,
+    MalformedReport,
+    MalformedQuote,
+    KeyBindingMismatch,
+    InvalidSignerAttestation,
+    UntrustedReportSigner,
+    InvalidReportSignature,
+    Policy(PolicyError),
+    PublicKeyMismatch,
+    DcapNotTrusted,
+    Dcap(dcap::DcapError),
+}
+
+/// The attestation evidence embedded into an RA certificate.
+#[derive(Debug, Clone)]
+pub struct Evidence {
+    /// JSON attestation report as signed by IAS
+    pub report: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// DER encoded IAS report signing certificate
+    pub signing_cert: Vec<u8>,
+}
+
+/// The root certificates attestation evidence is checked against, DER encoded.
+#[derive(Debug, Clone)]
+pub struct RootCerts<'a> {
+    /// Root CA of the IAS report signing certificate
+    pub ias: &'a [u8],
+    /// Intel SGX root CA. DCAP evidence is refused without it.
+    pub dcap: Option<&'a [u8]>,
+}
+
+/// The fields of the attested enclave report we care about.
+#[derive(Debug, Clone)]
+pub struct QuoteBody {
+    pub attributes: [u8; 16],
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; 64],
+}
+
+/// The uncompressed prime256v1 public key of the certificate, without the `04` prefix.
+pub fn public_key(cert_der: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(ra_cert::parse(cert_der)
+        .map_err(Error::Certificate)?
+        .public_key)
+}
+
+pub fn evidence(cert_der: &[u8]) -> Result<Evidence, Error> {
+    let payload = payload(cert_der)?;
+
+    let mut iter = payload.split(|x| *x == 0x7C);
+    let report = iter.next().ok_or(Error::MalformedPayload)?.to_vec();
+    let signature = iter
+        .next()
+        .and_then(|s| base64::decode(s).ok())
+        .ok_or(Error::MalformedPayload)?;
+    let signing_cert = iter
+        .next()
+        .and_then(|c| base64::decode(c).ok())
+        .ok_or(Error::MalformedPayload)?;
+    Ok(Evidence {
+        report,
+        signature,
+        signing_cert,
+    })
+}
+
+/// Parses the quote of an IAS attestation report. The report's signature is not checked here.
+pub fn quote_body(report: &[u8]) -> Result<QuoteBody, Error> {
+    let report: Value = serde_json::from_slice(report).map_err(|_| Error::MalformedReport)?;
+    let quote = report["isvEnclaveQuoteBody"]
+        .as_str()
+        .and_then(|q| base64::decode(q).ok())
+        .ok_or(Error::MalformedReport)?;
+    report_body(&quote)
+}
+
+/// The attested report of the RA certificate, from either IAS or DCAP evidence. Neither the IAS
+/// signature nor the DCAP collateral is checked here.
+pub fn attested_body(cert_der: &[u8]) -> Result<QuoteBody, Error> {
+    match dcap::decode_payload(&payload(cert_der)?) {
+        Some((quote, _)) => report_body(&quote),
+        None => quote_body(&evidence(cert_der)?.report),
+    }
+}
+
+/// The `isvEnclaveQuoteStatus` of IAS evidence, or `None` for DCAP evidence, whose status only
+/// follows from its collateral. The report's signature is not checked here.
+pub fn quote_status(cert_der: &[u8]) -> Result<Option<String>, Error> {
+    ias_report_field(cert_der, "isvEnclaveQuoteStatus")
+}
+
+/// The UTC `timestamp` of IAS evidence, or `None` for DCAP evidence.
+pub fn report_timestamp(cert_der: &[u8]) -> Result<Option<String>, Error> {
+    ias_report_field(cert_der, "timestamp")
+}
+
+/// Checks `attestation`, the `chain_signer_attestation` of the registration, which the enclave
+/// created by signing the public key of its ed25519 chain signer with the key of `cert_der`.
+pub fn verify_chain_signer(
+    cert_der: &[u8],
+    signer: &[u8; 32],
+    attestation: &[u8; 64],
+) -> Result<(), Error> {
+    let mut public_key = vec![0x04];
+    public_key.extend(self::public_key(cert_der)?);
+    // `sgx_ec256_signature_t` holds x and y as little endian u32 words, least significant first
+    let mut signature = attestation.to_vec();
+    signature[..32].reverse();
+    signature[32..].reverse();
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ECDSA_P256_SHA256_FIXED, public_key)
+        .verify(signer, &signature)
+        .map_err(|_| Error::InvalidSignerAttestation)
+}
+
+/// Checks that the quote in `cert_der` commits to `keys_hash`, see
+/// `substratee_stf::enclave_keys_hash`.
+pub fn verify_key_binding(cert_der: &[u8], keys_hash: &[u8; 32]) -> Result<QuoteBody, Error> {
+    let quote = attested_body(cert_der)?;
+    if &quote.report_data[32..] != keys_hash {
+        debug!(
+            "report data {:x?} doesn't commit to {:x?}",
+            &quote.report_data[32..],
+            keys_hash
+        );
+        return Err(Error::KeyBindingMismatch);
+    }
+    Ok(quote)
+}
+
+/// Checks the attestation evidence of the RA certificate like the enclaves do for their peers:
+/// the IAS report signature up to `roots.ias`, or the DCAP quote and its collateral up to
+/// `roots.dcap`, the `policy`, and that the attested report data commits to the certificate's
+/// public key.
+///
+/// `now` is in seconds since the unix epoch.
+pub fn verify(
+    cert_der: &[u8],
+    roots: &RootCerts<'_>,
+    policy: &AttestationPolicy,
+    now: u64,
+) -> Result<QuoteBody, Error> {
+    let body = match dcap::decode_payload(&payload(cert_der)?) {
+        Some((quote, collateral)) => {
+            let root = roots.dcap.ok_or(Error::DcapNotTrusted)?;
+            let verified = dcap::verify(&quote, &collateral, root, now).map_err(Error::Dcap)?;
+            // the freshness of DCAP evidence is bounded by the validity of its collateral
+            for status in &[verified.tcb_status, verified.qe_tcb_status] {
+                policy
+                    .check_status(&status.quote_statuses())
+                    .map_err(Error::Policy)?;
+            }
+            report_body(&quote)?
+        }
+        None => verify_ias_report(&evidence(cert_der)?, roots.ias, policy, now)?,
+    };
+    policy
+        .check_debug(&body.attributes)
+        .map_err(Error::Policy)?;
+
+    let pub_k = public_key(cert_der)?;
+    if body.report_data[..32] != dcap::sha256(&pub_k)[..] {
+        return Err(Error::PublicKeyMismatch);
+    }
+    Ok(body)
+}
+
+fn verify_ias_report(
+    evidence: &Evidence,
+    root_ca_der: &[u8],
+    policy: &AttestationPolicy,
+    now: u64,
+) -> Result<QuoteBody, Error> {
+    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(root_ca_der)
+        .map_err(|_| Error::UntrustedReportSigner)?;
+    let cert = webpki::EndEntityCert::from(&evidence.signing_cert)
+        .map_err(|_| Error::UntrustedReportSigner)?;
+    cert.verify_is_valid_tls_server_cert(
+        IAS_SIG_ALGS,
+        &webpki::TLSServerTrustAnchors(&[anchor]),
+        &[],
+        webpki::Time::from_seconds_since_unix_epoch(now),
+    )
+    .map_err(|_| Error::UntrustedReportSigner)?;
+    cert.verify_signature(
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+        &evidence.report,
+        &evidence.signature,
+    )
+    .map_err(|_| Error::InvalidReportSignature)?;
+
+    let report: Value =
+        serde_json::from_slice(&evidence.report).map_err(|_| Error::MalformedReport)?;
+    let status = report["isvEnclaveQuoteStatus"]
+        .as_str()
+        .ok_or(Error::MalformedReport)?;
+    QuoteStatus::from_ias(status)
+        .and_then(|statuses| policy.check_status(&statuses))
+        .map_err(Error::Policy)?;
+    // IAS timestamps are UTC, but lack the zone designator
+    let timestamp = report["timestamp"]
+        .as_str()
+        .and_then(|t| t.get(..19))
+        .and_then(|t| dcap::parse_time(&format!("{}Z", t)))
+        .ok_or(Error::MalformedReport)?;
+    policy
+        .check_report_age(timestamp, now)
+        .map_err(Error::Policy)?;
+    quote_body(&evidence.report)
+}
+
+fn ias_report_field(cert_der: &[u8], field: &str) -> Result<Option<String>, Error> {
+    if dcap::decode_payload(&payload(cert_der)?).is_some() {
+        return Ok(None);
+    }
+    let report: Value =
+        serde_json::from_slice(&evidence(cert_der)?.report).map_err(|_| Error::MalformedReport)?;
+    report[field]
+        .as_str()
+        .map(|value| Some(value.to_string()))
+        .ok_or(Error::MalformedReport)
+}
+
+fn payload(cert_der: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(ra_cert::parse(cert_der)
+        .map_err(Error::Certificate)?
+        .evidence)
+}
+
+/// The report body of an EPID or DCAP quote, which share the layout up to it.
+fn report_body(quote: &[u8]) -> Result<QuoteBody, Error> {
+    let body = quote
+        .get(QUOTE_REPORT_BODY_OFFSET..QUOTE_REPORT_BODY_OFFSET + REPORT_BODY_SIZE)
+        .and_then(dcap::ReportBody::parse)
+        .ok_or(Error::MalformedQuote)?;
+    Ok(QuoteBody {
+        attributes: body.attributes,
+        mr_enclave: body.mr_enclave,
+        mr_signer: body.mr_signer,
+        isv_prod_id: body.isv_prod_id,
+        isv_svn: body.isv_svn,
+        report_data: body.report_data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use substratee_mock_ias::MockIas;
+
+    const NOW: u64 = 1_600_000_000;
+    const MR_ENCLAVE: [u8; 32] = [0xE1; 32];
+
+    fn ra_cert(pub_k: &[u8], payload: &[u8]) -> Vec<u8> {
+        ra_cert::test_cert(pub_k, &[(ra_cert::EVIDENCE_OID, payload)])
+    }
+
+    fn ias_evidence(quote_pub_k: &[u8], tamper: bool, flags: u8) -> Vec<u8> {
+        let mut quote = vec![0u8; QUOTE_REPORT_BODY_OFFSET + REPORT_BODY_SIZE];
+        quote[QUOTE_REPORT_BODY_OFFSET + 48] = flags;
+        quote[QUOTE_REPORT_BODY_OFFSET + 64..][..32].copy_from_slice(&MR_ENCLAVE);
+        quote[QUOTE_REPORT_BODY_OFFSET + 320..][..32].copy_from_slice(&dcap::sha256(quote_pub_k));
+        let (mut report, signature) = MockIas::default().report(&quote).unwrap();
+        if tamper {
+            report = report.replace("\"OK\"", "\"GROUP_REVOKED\"");
+        }
+        let signing_cert = dcap::pem_certificates(substratee_mock_ias::REPORT_SIGNING_CERT);
+        format!(
+            "{}|{}|{}",
+            report,
+            signature,
+            base64::encode(&signing_cert[0])
+        )
+        .into_bytes()
+    }
+
+    fn mock_roots(ias: &[u8]) -> RootCerts<'_> {
+        RootCerts { ias, dcap: None }
+    }
+
+    #[test]
+    fn ias_evidence_verifies() {
+        let policy = AttestationPolicy::default();
+        let pub_k = [0x42u8; 64];
+        let root = &dcap::pem_certificates(substratee_mock_ias::ROOT_CA)[0];
+        let cert = ra_cert(&pub_k, &ias_evidence(&pub_k, false, 0x05));
+        let body = verify(&cert, &mock_roots(root), &policy, NOW).unwrap();
+        assert_eq!(body.mr_enclave, MR_ENCLAVE);
+    }
+
+    #[test]
+    fn forged_or_misbound_ias_evidence_is_rejected() {
+        let policy = AttestationPolicy::default();
+        let pub_k = [0x42u8; 64];
+        let root = &dcap::pem_certificates(substratee_mock_ias::ROOT_CA)[0];
+
+        let tampered = ra_cert(&pub_k, &ias_evidence(&pub_k, true, 0x05));
+        assert_eq!(
+            verify(&tampered, &mock_roots(root), &policy, NOW).unwrap_err(),
+            Error::InvalidReportSignature
+        );
+
+        let other_key = ra_cert(&[0x43u8; 64], &ias_evidence(&pub_k, false, 0x05));
+        assert_eq!(
+            verify(&other_key, &mock_roots(root), &policy, NOW).unwrap_err(),
+            Error::PublicKeyMismatch
+        );
+
+        let intel_root = dcap::pem_certificates(include_bytes!(
+            "../../enclave/AttestationReportSigningCACert.pem"
+        ));
+        let cert = ra_cert(&pub_k, &ias_evidence(&pub_k, false, 0x05));
+        assert_eq!(
+            verify(&cert, &mock_roots(&intel_root[0]), &policy, NOW).unwrap_err(),
+            Error::UntrustedReportSigner
+        );
+    }
+
+    #[test]
+    fn policy_is_applied_to_ias_evidence() {
+        let pub_k = [0x42u8; 64];
+        let root = &dcap::pem_certificates(substratee_mock_ias::ROOT_CA)[0];
+        let debug = ra_cert(&pub_k, &ias_evidence(&pub_k, false, 0x07));
+        let policy = AttestationPolicy::default();
+        assert_eq!(
+            verify(&debug, &mock_roots(root), &policy, NOW).unwrap_err(),
+            Error::Policy(PolicyError::DebugEnclave)
+        );
+        let debug_ok = AttestationPolicy {
+            allow_debug: true,
+            ..Default::default()
+        };
+        assert!(verify(&debug, &mock_roots(root), &debug_ok, NOW).is_ok());
+
+        // the mock IAS stamps its reports with the current time
+        let later = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + policy.max_report_age
+            + 3600;
+        let cert = ra_cert(&pub_k, &ias_evidence(&pub_k, false, 0x05));
+        assert_eq!(
+            verify(&cert, &mock_roots(root), &policy, later).unwrap_err(),
+            Error::Policy(PolicyError::ReportTooOld)
+        );
+    }
+
+    #[test]
+    fn quote_status_is_read() {
+        let pub_k = [0x42u8; 64];
+        let cert = ra_cert(&pub_k, &ias_evidence(&pub_k, false, 0x05));
+        assert_eq!(quote_status(&cert), Ok(Some("OK".to_string())));
+        assert!(report_timestamp(&cert).unwrap().is_some());
+        let tampered = ra_cert(&pub_k, &ias_evidence(&pub_k, true, 0x05));
+        assert_eq!(
+            quote_status(&tampered),
+            Ok(Some("GROUP_REVOKED".to_string()))
+        );
+    }
+
+    #[test]
+    fn chain_signer_attestation_is_checked() {
+        use ring::rand::SystemRandom;
+        use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let cert = ra_cert(&key.public_key().as_ref()[1..], b"report|sig|cert");
+
+        let signer = [0x5Au8; 32];
+        let signature = key.sign(&rng, &signer).unwrap();
+        let mut attestation = [0u8; 64];
+        attestation.copy_from_slice(signature.as_ref());
+        attestation[..32].reverse();
+        attestation[32..].reverse();
+
+        assert_eq!(verify_chain_signer(&cert, &signer, &attestation), Ok(()));
+        assert_eq!(
+            verify_chain_signer(&cert, &[0x5Bu8; 32], &attestation),
+            Err(Error::InvalidSignerAttestation)
+        );
+    }
+
+    #[test]
+    fn truncated_cert_is_rejected() {
+        let cert = ra_cert(&[0x42u8; 64], b"report|sig|cert");
+        let truncated = &cert[..cert.len() - 1];
+        let malformed = Error::Certificate(ra_cert::CertError::Malformed);
+        assert_eq!(public_key(truncated), Err(malformed.clone()));
+        assert_eq!(evidence(truncated).unwrap_err(), malformed);
+    }
+
+    #[test]
+    fn short_quote_is_rejected() {
+        let report = format!(
+            "{{\"isvEnclaveQuoteBody\":\"{}\"}}",
+            base64::encode(&[0u8; 100][..])
+        );
+        assert_eq!(
+            quote_body(report.as_bytes()).unwrap_err(),
+            Error::MalformedQuote
+        );
+    }
+}
@@ -0,0 +1,213 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Verifies the registration of a substraTEE worker without SGX.
+//!
+//! Takes the RA certificate and chain signer attestation written by `substratee-worker dump-ra`,
+//! and the chain signer either from `substratee-worker signing-key` or from the worker's entry in
+//! the `EnclaveRegistry` of the node.
+
+use std::fs;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base58::ToBase58;
+use clap::{App, Arg, ArgMatches};
+
+use substratee_ra_verify::attestation_policy::AttestationPolicy;
+use substratee_ra_verify::{dcap, RootCerts};
+
+const IAS_ROOT_CA: &[u8] = include_bytes!("../../enclave/AttestationReportSigningCACert.pem");
+
+/// The fields of an `EnclaveRegistry` entry we check, which precede its url
+struct RegistryEntry {
+    pubkey: [u8; 32],
+    mr_enclave: [u8; 32],
+}
+
+impl RegistryEntry {
+    /// Reads the SCALE encoded `Enclave { pubkey, mr_enclave, timestamp, url }`.
+    fn decode(encoded: &[u8]) -> Option<RegistryEntry> {
+        if encoded.len() < 72 {
+            return None;
+        }
+        let mut entry = RegistryEntry {
+            pubkey: [0u8; 32],
+            mr_enclave: [0u8; 32],
+        };
+        entry.pubkey.copy_from_slice(&encoded[..32]);
+        entry.mr_enclave.copy_from_slice(&encoded[32..64]);
+        Some(entry)
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("substratee-ra-verify")
+        .about("Verifies the attestation of a substraTEE worker's registration")
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .takes_value(true)
+                .required(true)
+                .value_name("FILE")
+                .help("DER encoded RA certificate, e.g. ra_dump_cert.der"),
+        )
+        .arg(
+            Arg::with_name("signer-attn")
+                .long("signer-attn")
+                .takes_value(true)
+                .required(true)
+                .value_name("FILE")
+                .help("chain signer attestation, e.g. ra_dump_signer_attn.bin"),
+        )
+        .arg(
+            Arg::with_name("signer-key")
+                .long("signer-key")
+                .takes_value(true)
+                .value_name("FILE")
+                .required_unless("registry-entry")
+                .help("ed25519 public key of the chain signer, e.g. enclave-signing-pubkey.bin"),
+        )
+        .arg(
+            Arg::with_name("registry-entry")
+                .long("registry-entry")
+                .takes_value(true)
+                .value_name("HEX")
+                .conflicts_with("signer-key")
+                .help(
+                    "the worker's EnclaveRegistry storage value, as returned by state_getStorage",
+                ),
+        )
+        .arg(
+            Arg::with_name("ias-root-ca")
+                .long("ias-root-ca")
+                .takes_value(true)
+                .value_name("PEM FILE")
+                .help("root CA of the IAS report signing certificate. Default is Intel's"),
+        )
+        .arg(
+            Arg::with_name("dcap-root-ca")
+                .long("dcap-root-ca")
+                .takes_value(true)
+                .value_name("DER FILE")
+                .help("Intel SGX root CA, needed for DCAP evidence"),
+        )
+        .arg(
+            Arg::with_name("allow-debug")
+                .long("allow-debug")
+                .help("accept enclaves in debug mode"),
+        )
+        .get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("[-] {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches<'_>) -> Result<(), String> {
+    let cert = read(matches.value_of("cert").unwrap())?;
+    let attestation = read(matches.value_of("signer-attn").unwrap())?;
+    if attestation.len() != 64 {
+        return Err("the chain signer attestation must be 64 bytes".to_string());
+    }
+    let mut signer_attn = [0u8; 64];
+    signer_attn.copy_from_slice(&attestation);
+
+    let entry = match matches.value_of("registry-entry") {
+        Some(value) => Some(
+            hex::decode(value.trim_start_matches("0x"))
+                .ok()
+                .and_then(|encoded| RegistryEntry::decode(&encoded))
+                .ok_or_else(|| "malformed registry entry".to_string())?,
+        ),
+        None => None,
+    };
+    let signer = match (&entry, matches.value_of("signer-key")) {
+        (Some(entry), _) => entry.pubkey,
+        (None, Some(file)) => {
+            let key = read(file)?;
+            if key.len() != 32 {
+                return Err("the chain signer key must be 32 bytes".to_string());
+            }
+            let mut signer = [0u8; 32];
+            signer.copy_from_slice(&key);
+            signer
+        }
+        (None, None) => unreachable!("clap requires one of them"),
+    };
+
+    let ias_root = match matches.value_of("ias-root-ca") {
+        Some(file) => read(file)?,
+        None => IAS_ROOT_CA.to_vec(),
+    };
+    let ias_root = dcap::pem_certificates(&ias_root)
+        .into_iter()
+        .next()
+        .ok_or_else(|| "no certificate in the IAS root CA".to_string())?;
+    let dcap_root = match matches.value_of("dcap-root-ca") {
+        Some(file) => Some(read(file)?),
+        None => None,
+    };
+    let roots = RootCerts {
+        ias: &ias_root,
+        dcap: dcap_root.as_deref(),
+    };
+    let policy = AttestationPolicy {
+        allow_debug: matches.is_present("allow-debug"),
+        ..Default::default()
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let body = substratee_ra_verify::verify(&cert, &roots, &policy, now)
+        .map_err(|e| format!("attestation evidence rejected: {:?}", e))?;
+    substratee_ra_verify::verify_chain_signer(&cert, &signer, &signer_attn)
+        .map_err(|e| format!("chain signer rejected: {:?}", e))?;
+    if let Some(entry) = &entry {
+        if entry.mr_enclave != body.mr_enclave {
+            return Err(format!(
+                "the registry entry is for MRENCLAVE {}",
+                entry.mr_enclave.to_base58()
+            ));
+        }
+    }
+
+    let unknown = |field: Option<String>| field.unwrap_or_else(|| "n/a (DCAP)".to_string());
+    println!("[+] Attestation verified");
+    println!("   MRENCLAVE:    {}", body.mr_enclave.to_base58());
+    println!("   MRSIGNER:     {}", body.mr_signer.to_base58());
+    println!("   ISV SVN:      {}", body.isv_svn);
+    println!(
+        "   quote status: {}",
+        unknown(substratee_ra_verify::quote_status(&cert).map_err(|e| format!("{:?}", e))?)
+    );
+    println!(
+        "   report time:  {}",
+        unknown(substratee_ra_verify::report_timestamp(&cert).map_err(|e| format!("{:?}", e))?)
+    );
+    println!("   chain signer: {}", signer.to_base58());
+    Ok(())
+}
+
+fn read(file: &str) -> Result<Vec<u8>, String> {
+    fs::read(file).map_err(|e| format!("could not read '{}': {}", file, e))
+}
@@ -0,0 +1,265 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Parsing of the self-signed RA certificates of the enclave.
+//!
+//! The attestation evidence is carried in a dedicated, non-critical extension. Enclaves also put
+//! it into the Netscape comment, which is where the enclave registry of substraTEE-node looks for
+//! it, but verifiers only read the dedicated extension.
+//!
+//! This file is shared with the enclave, so it must only depend on `std` and `yasna`.
+
+use std::prelude::v1::*;
+
+use yasna::{ASN1Error, ASN1ErrorKind, ASN1Result, Tag};
+
+/// DER of the OID 2.25.186239885437333553600850733067613854919, derived from a UUID, of the
+/// attestation evidence extension. It does not fit yasna's `ObjectIdentifier`.
+pub const EVIDENCE_OID: &[u8] = &[
+    0x06, 0x14, 0x69, 0x82, 0x98, 0x9C, 0xBF, 0xD2, 0xBA, 0xC6, 0x9A, 0x91, 0xDF, 0x91, 0xDF, 0xA0,
+    0xD1, 0xDA, 0xA6, 0xB5, 0xB9, 0x47,
+];
+
+/// `AlgorithmIdentifier` of id-ecPublicKey on prime256v1
+const EC_P256_ALGORITHM: &[u8] = &[
+    0x30, 0x13, 0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01, 0x06, 0x08, 0x2A, 0x86, 0x48,
+    0xCE, 0x3D, 0x03, 0x01, 0x07,
+];
+
+/// BIT STRING header of an uncompressed prime256v1 point, including the `04` prefix
+const EC_P256_KEY_PREFIX: &[u8] = &[0x03, 0x42, 0x00, 0x04];
+
+const X509_V3: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CertError {
+    Malformed,
+    UnsupportedKey,
+    MissingEvidence,
+    DuplicateEvidence,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaCert {
+    /// Uncompressed prime256v1 public key, without the `04` prefix
+    pub public_key: Vec<u8>,
+    pub evidence: Vec<u8>,
+}
+
+/// Parses the DER of an RA certificate. The certificate's signature is not checked here, the TLS
+/// handshake already proves possession of its key.
+pub fn parse(cert_der: &[u8]) -> Result<RaCert, CertError> {
+    let (algorithm, key, extensions) =
+        yasna::parse_der(cert_der, read_certificate).map_err(|_| CertError::Malformed)?;
+
+    if algorithm != EC_P256_ALGORITHM || key.len() != 68 || !key.starts_with(EC_P256_KEY_PREFIX) {
+        return Err(CertError::UnsupportedKey);
+    }
+
+    let mut evidence = extensions
+        .into_iter()
+        .filter(|(oid, _)| oid.as_slice() == EVIDENCE_OID)
+        .map(|(_, value)| value);
+    let first = evidence.next().ok_or(CertError::MissingEvidence)?;
+    if evidence.next().is_some() {
+        return Err(CertError::DuplicateEvidence);
+    }
+    Ok(RaCert {
+        public_key: key[EC_P256_KEY_PREFIX.len()..].to_vec(),
+        evidence: first,
+    })
+}
+
+type Extension = (Vec<u8>, Vec<u8>);
+
+/// The raw public key algorithm, the raw public key and the extensions of a certificate.
+fn read_certificate(reader: yasna::BERReader) -> ASN1Result<(Vec<u8>, Vec<u8>, Vec<Extension>)> {
+    reader.read_sequence(|reader| {
+        let tbs = reader.next().read_sequence(|reader| {
+            let version = reader
+                .next()
+                .read_tagged(Tag::context(0), |reader| reader.read_u8())?;
+            if version != X509_V3 {
+                return Err(ASN1Error::new(ASN1ErrorKind::Invalid));
+            }
+            // serial number, signature algorithm, issuer, validity and subject
+            for _ in 0..5 {
+                reader.next().read_der()?;
+            }
+            let (algorithm, key) = reader.next().read_sequence(|reader| {
+                let algorithm = reader.next().read_der()?;
+                let key = reader.next().read_der()?;
+                Ok((algorithm, key))
+            })?;
+            let extensions = reader
+                .next()
+                .read_tagged(Tag::context(3), read_extensions)?;
+            Ok((algorithm, key, extensions))
+        })?;
+        // signature algorithm and signature
+        reader.next().read_der()?;
+        reader.next().read_der()?;
+        Ok(tbs)
+    })
+}
+
+/// The raw OID and the value of each extension.
+fn read_extensions(reader: yasna::BERReader) -> ASN1Result<Vec<Extension>> {
+    let mut extensions = Vec::new();
+    reader.read_sequence_of(|reader| {
+        let extension = reader.read_sequence(|reader| {
+            let oid = reader.next().read_der()?;
+            reader.read_optional(|reader| reader.read_bool())?;
+            let value = reader.next().read_bytes()?;
+            Ok((oid, value))
+        })?;
+        extensions.push(extension);
+        Ok(())
+    })?;
+    Ok(extensions)
+}
+
+/// A certificate with the structure of an RA certificate, with a dummy signature.
+#[cfg(test)]
+pub(crate) fn test_cert(public_key: &[u8], extensions: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let dummy_name = [0x30, 0x00];
+    yasna::construct_der(|writer| {
+        writer.write_sequence(|writer| {
+            writer.next().write_sequence(|writer| {
+                writer
+                    .next()
+                    .write_tagged(Tag::context(0), |writer| writer.write_u8(X509_V3));
+                writer.next().write_u8(1);
+                writer.next().write_der(&dummy_name);
+                writer.next().write_der(&dummy_name);
+                writer.next().write_der(&dummy_name);
+                writer.next().write_der(&dummy_name);
+                writer.next().write_sequence(|writer| {
+                    writer.next().write_der(EC_P256_ALGORITHM);
+                    let mut key = EC_P256_KEY_PREFIX.to_vec();
+                    key.extend_from_slice(public_key);
+                    // the BIT STRING header is part of `EC_P256_KEY_PREFIX`
+                    key[1] = (key.len() - 2) as u8;
+                    writer.next().write_der(&key);
+                });
+                writer.next().write_tagged(Tag::context(3), |writer| {
+                    writer.write_sequence(|writer| {
+                        for (oid, value) in extensions {
+                            writer.next().write_sequence(|writer| {
+                                writer.next().write_der(oid);
+                                writer.next().write_bytes(value);
+                            });
+                        }
+                    });
+                });
+            });
+            writer.next().write_der(&dummy_name);
+            writer.next().write_der(&[0x03, 0x01, 0x00]);
+        });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NS_COMMENT_OID: &[u8] = &[
+        0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x86, 0xF8, 0x42, 0x01, 0x0D,
+    ];
+
+    fn valid_cert() -> Vec<u8> {
+        test_cert(
+            &[0x42; 64],
+            &[(NS_COMMENT_OID, b"legacy"), (EVIDENCE_OID, b"evidence")],
+        )
+    }
+
+    /// xorshift64, so failures are reproducible
+    fn next(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn key_and_evidence_are_parsed() {
+        let cert = parse(&valid_cert()).unwrap();
+        assert_eq!(cert.public_key, vec![0x42; 64]);
+        assert_eq!(cert.evidence, b"evidence".to_vec());
+    }
+
+    #[test]
+    fn evidence_must_be_in_one_dedicated_extension() {
+        let legacy_only = test_cert(&[0x42; 64], &[(NS_COMMENT_OID, b"evidence")]);
+        assert_eq!(parse(&legacy_only), Err(CertError::MissingEvidence));
+
+        let twice = test_cert(
+            &[0x42; 64],
+            &[(EVIDENCE_OID, b"evidence"), (EVIDENCE_OID, b"other")],
+        );
+        assert_eq!(parse(&twice), Err(CertError::DuplicateEvidence));
+    }
+
+    #[test]
+    fn other_keys_are_rejected() {
+        let short_key = test_cert(&[0x42; 32], &[(EVIDENCE_OID, b"evidence")]);
+        assert_eq!(parse(&short_key), Err(CertError::UnsupportedKey));
+
+        let mut other_curve = valid_cert();
+        let algorithm = other_curve
+            .windows(EC_P256_ALGORITHM.len())
+            .position(|w| w == EC_P256_ALGORITHM)
+            .unwrap();
+        // prime256v1 becomes 1.2.840.10045.3.1.8
+        other_curve[algorithm + EC_P256_ALGORITHM.len() - 1] = 0x08;
+        assert_eq!(parse(&other_curve), Err(CertError::UnsupportedKey));
+    }
+
+    #[test]
+    fn truncated_or_extended_certs_are_rejected() {
+        let cert = valid_cert();
+        for len in 0..cert.len() {
+            assert_eq!(parse(&cert[..len]), Err(CertError::Malformed), "{}", len);
+        }
+        let mut trailing = cert.clone();
+        trailing.push(0);
+        assert_eq!(parse(&trailing), Err(CertError::Malformed));
+
+        // an outer length far beyond the input
+        let header = 2 + (cert[1] & 0x7F) as usize;
+        let mut inflated = vec![0x30, 0x84, 0xFF, 0xFF, 0xFF, 0xFF];
+        inflated.extend_from_slice(&cert[header..]);
+        assert_eq!(parse(&inflated), Err(CertError::Malformed));
+    }
+
+    #[test]
+    fn corrupted_certs_do_not_panic() {
+        let cert = valid_cert();
+        let mut state = 0x2545_F491_4F6C_DD1D;
+        for _ in 0..20_000 {
+            let mut corrupted = cert.clone();
+            for _ in 0..1 + next(&mut state) % 4 {
+                let pos = (next(&mut state) % corrupted.len() as u64) as usize;
+                corrupted[pos] = next(&mut state) as u8;
+            }
+            if let Ok(parsed) = parse(&corrupted) {
+                assert_eq!(parsed.public_key.len(), 64);
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+#!/usr/bin/env python3
+"""Generates the DCAP test fixtures.
+
+The certificates are issued by a throw-away test root CA, which stands in for the Intel SGX
+root CA. The layout of the quote, the PCK certificate extension and the collateral follows
+Intel's specification, such that the verifier runs the same code path as in production.
+
+Requires the `cryptography` package. Run from this directory.
+"""
+
+import datetime
+import hashlib
+import json
+import struct
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.asymmetric.utils import decode_dss_signature
+from cryptography.x509.oid import NameOID
+
+NOT_BEFORE = datetime.datetime(2020, 1, 1)
+NOT_AFTER = datetime.datetime(2049, 12, 31)
+ISSUE_DATE = "2020-06-01T00:00:00Z"
+NEXT_UPDATE = "2049-12-01T00:00:00Z"
+CRL_NEXT_UPDATE = datetime.datetime(2049, 12, 1)
+
+SGX_EXTENSION_OID = "1.2.840.113741.1.13.1"
+FMSPC = bytes.fromhex("00906ea10000")
+PCK_COMPONENTS = [2, 2, 2, 2, 255, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+PCK_PCESVN = 10
+QE_MRSIGNER = bytes(range(32))
+QE_ISVPRODID = 1
+QE_ISVSVN = 5
+ISV_MRENCLAVE = bytes([0xAB] * 32)
+ISV_MRSIGNER = bytes([0xCD] * 32)
+ISV_REPORT_DATA = bytes([0x11] * 32) + bytes([0x22] * 32)
+
+
+def name(cn):
+    return x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, cn)])
+
+
+def certificate(subject, subject_key, issuer, issuer_key, serial, ca, extensions=()):
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(name(subject))
+        .issuer_name(name(issuer))
+        .public_key(subject_key.public_key())
+        .serial_number(serial)
+        .not_valid_before(NOT_BEFORE)
+        .not_valid_after(NOT_AFTER)
+        .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+    )
+    for ext in extensions:
+        builder = builder.add_extension(ext, critical=False)
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def crl(issuer, issuer_key, revoked_serials):
+    builder = (
+        x509.CertificateRevocationListBuilder()
+        .issuer_name(name(issuer))
+        .last_update(NOT_BEFORE)
+        .next_update(CRL_NEXT_UPDATE)
+    )
+    for serial in revoked_serials:
+        builder = builder.add_revoked_certificate(
+            x509.RevokedCertificateBuilder().serial_number(serial).revocation_date(NOT_BEFORE).build()
+        )
+    return builder.sign(issuer_key, hashes.SHA256())
+
+
+def pem(*certs):
+    return b"".join(c.public_bytes(serialization.Encoding.PEM) for c in certs)
+
+
+def der_len(n):
+    if n < 0x80:
+        return bytes([n])
+    if n < 0x100:
+        return bytes([0x81, n])
+    return bytes([0x82, n >> 8, n & 0xFF])
+
+
+def tlv(tag, value):
+    return bytes([tag]) + der_len(len(value)) + value
+
+
+def oid(dotted):
+    parts = [int(p) for p in dotted.split(".")]
+    out = bytes([40 * parts[0] + parts[1]])
+    for p in parts[2:]:
+        enc = [p & 0x7F]
+        p >>= 7
+        while p:
+            enc.insert(0, 0x80 | (p & 0x7F))
+            p >>= 7
+        out += bytes(enc)
+    return tlv(0x06, out)
+
+
+def integer(n):
+    b = n.to_bytes((n.bit_length() + 8) // 8 or 1, "big")
+    return tlv(0x02, b)
+
+
+def sgx_extension():
+    tcb = [
+        tlv(0x30, oid("%s.2.%d" % (SGX_EXTENSION_OID, i + 1)) + integer(svn))
+        for i, svn in enumerate(PCK_COMPONENTS)
+    ]
+    tcb.append(tlv(0x30, oid(SGX_EXTENSION_OID + ".2.17") + integer(PCK_PCESVN)))
+    tcb.append(tlv(0x30, oid(SGX_EXTENSION_OID + ".2.18") + tlv(0x04, bytes(PCK_COMPONENTS))))
+    entries = [
+        tlv(0x30, oid(SGX_EXTENSION_OID + ".1") + tlv(0x04, bytes(16))),
+        tlv(0x30, oid(SGX_EXTENSION_OID + ".2") + tlv(0x30, b"".join(tcb))),
+        tlv(0x30, oid(SGX_EXTENSION_OID + ".3") + tlv(0x04, bytes(2))),
+        tlv(0x30, oid(SGX_EXTENSION_OID + ".4") + tlv(0x04, FMSPC)),
+    ]
+    value = tlv(0x30, b"".join(entries))
+    return x509.UnrecognizedExtension(x509.ObjectIdentifier(SGX_EXTENSION_OID), value)
+
+
+def raw_signature(key, message):
+    r, s = decode_dss_signature(key.sign(message, ec.ECDSA(hashes.SHA256())))
+    return r.to_bytes(32, "big") + s.to_bytes(32, "big")
+
+
+def raw_public(key):
+    return key.public_key().public_bytes(
+        serialization.Encoding.X962, serialization.PublicFormat.UncompressedPoint
+    )[1:]
+
+
+def report_body(mr_enclave, mr_signer, isv_prod_id, isv_svn, report_data, misc_select=0,
+                attributes=bytes([0x05] + [0] * 7 + [0x07] + [0] * 7)):
+    body = bytearray(384)
+    struct.pack_into("<I", body, 16, misc_select)
+    body[48:64] = attributes
+    body[64:96] = mr_enclave
+    body[128:160] = mr_signer
+    struct.pack_into("<HH", body, 256, isv_prod_id, isv_svn)
+    body[320:384] = report_data
+    return bytes(body)
+
+
+def signed_json(key_name, body, signing_key):
+    text = json.dumps(body, separators=(",", ":"))
+    signature = raw_signature(signing_key, text.encode()).hex()
+    return '{"%s":%s,"signature":"%s"}' % (key_name, text, signature)
+
+
+def main():
+    root_key = ec.generate_private_key(ec.SECP256R1())
+    platform_key = ec.generate_private_key(ec.SECP256R1())
+    pck_key = ec.generate_private_key(ec.SECP256R1())
+    tcb_key = ec.generate_private_key(ec.SECP256R1())
+    attestation_key = ec.generate_private_key(ec.SECP256R1())
+
+    root = certificate("Test SGX Root CA", root_key, "Test SGX Root CA", root_key, 1, True)
+    platform = certificate("Test SGX PCK Platform CA", platform_key, "Test SGX Root CA", root_key, 2, True)
+    pck = certificate("Test SGX PCK Certificate", pck_key, "Test SGX PCK Platform CA", platform_key, 3,
+                      False, [sgx_extension()])
+    tcb = certificate("Test SGX TCB Signing", tcb_key, "Test SGX Root CA", root_key, 4, False)
+
+    # quote v3, ECDSA-256-with-P-256
+    header = struct.pack("<HHIHH", 3, 2, 0, QE_ISVSVN, PCK_PCESVN) + bytes(16) + bytes(20)
+    isv_report = report_body(ISV_MRENCLAVE, ISV_MRSIGNER, 0, 1, ISV_REPORT_DATA)
+    qe_auth_data = bytes(range(32))
+    att_key = raw_public(attestation_key)
+    qe_report_data = hashlib.sha256(att_key + qe_auth_data).digest() + bytes(32)
+    qe_report = report_body(bytes(32), QE_MRSIGNER, QE_ISVPRODID, QE_ISVSVN, qe_report_data,
+                            attributes=bytes([0x11] + [0] * 7 + [0xE7] + [0] * 7))
+    cert_data = pem(pck, platform, root) + b"\0"
+
+    signature_data = (
+        raw_signature(attestation_key, header + isv_report)
+        + att_key
+        + qe_report
+        + raw_signature(pck_key, qe_report)
+        + struct.pack("<H", len(qe_auth_data)) + qe_auth_data
+        + struct.pack("<HI", 5, len(cert_data)) + cert_data
+    )
+    quote = header + isv_report + struct.pack("<I", len(signature_data)) + signature_data
+
+    tcb_levels = [
+        {"tcb": dict([("sgxtcbcomp%02dsvn" % (i + 1), svn) for i, svn in enumerate(PCK_COMPONENTS)]
+                     + [("pcesvn", PCK_PCESVN + 1)]),
+         "tcbDate": ISSUE_DATE, "tcbStatus": "UpToDate"},
+        {"tcb": dict([("sgxtcbcomp%02dsvn" % (i + 1), 0) for i in range(16)] + [("pcesvn", 5)]),
+         "tcbDate": ISSUE_DATE, "tcbStatus": "OutOfDate"},
+    ]
+    tcb_info = {
+        "version": 2, "issueDate": ISSUE_DATE, "nextUpdate": NEXT_UPDATE,
+        "fmspc": FMSPC.hex(), "pceId": "0000", "tcbType": 0, "tcbEvaluationDataNumber": 1,
+        "tcbLevels": tcb_levels,
+    }
+    qe_identity = {
+        "id": "QE", "version": 2, "issueDate": ISSUE_DATE, "nextUpdate": NEXT_UPDATE,
+        "tcbEvaluationDataNumber": 1, "miscselect": "00000000", "miscselectMask": "FFFFFFFF",
+        "attributes": "11000000000000000000000000000000",
+        "attributesMask": "FBFFFFFFFFFFFFFF0000000000000000",
+        "mrsigner": QE_MRSIGNER.hex().upper(), "isvprodid": QE_ISVPRODID,
+        "tcbLevels": [{"tcb": {"isvsvn": QE_ISVSVN}, "tcbDate": ISSUE_DATE, "tcbStatus": "UpToDate"}],
+    }
+
+    open("quote.bin", "wb").write(quote)
+    open("root_ca.der", "wb").write(root.public_bytes(serialization.Encoding.DER))
+    open("tcb_info.json", "w").write(signed_json("tcbInfo", tcb_info, tcb_key))
+    open("qe_identity.json", "w").write(signed_json("enclaveIdentity", qe_identity, tcb_key))
+    open("tcb_signing_chain.pem", "wb").write(pem(tcb, root))
+    # the PCS serves the root CA CRL in DER and the PCK CRL in PEM
+    open("root_ca_crl.der", "wb").write(
+        crl("Test SGX Root CA", root_key, [99]).public_bytes(serialization.Encoding.DER))
+    open("pck_crl.pem", "wb").write(
+        crl("Test SGX PCK Platform CA", platform_key, [98]).public_bytes(serialization.Encoding.PEM))
+    # a list on which the PCK certificate of the quote is revoked
+    open("pck_crl_revoked.pem", "wb").write(
+        crl("Test SGX PCK Platform CA", platform_key, [98, 3]).public_bytes(serialization.Encoding.PEM))
+
+
+if __name__ == "__main__":
+    main()
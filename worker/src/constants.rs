@@ -16,11 +16,37 @@
 */
 
 pub static ENCRYPTED_STATE_FILE: &str = "state.bin";
+pub static PREVIOUS_STATE_FILE_SUFFIX: &str = ".prev";
+pub static TEMPORARY_STATE_FILE_SUFFIX: &str = ".tmp";
 pub static SHARDS_PATH: &str = "./shards";
+pub static HISTORY_PATH: &str = "history";
+pub static DEFAULT_HISTORY_SIZE: usize = 10;
+pub static RESTORE_STATE_CONTEXT: &[u8] = b"substraTEE/restore-state";
+pub static PROVISIONING_POLICY_CONTEXT: &[u8] = b"substraTEE/provisioning-policy";
+pub static ATTESTATION_POLICY_CONTEXT: &[u8] = b"substraTEE/attestation-policy";
 pub static ENCLAVE_TOKEN: &str = "../bin/enclave.token";
 pub static ENCLAVE_FILE: &str = "../bin/enclave.signed.so";
 pub static SHIELDING_KEY_FILE: &str = "enclave-shielding-pubkey.json";
 pub static SIGNING_KEY_FILE: &str = "enclave-signing-pubkey.bin";
+pub static RA_CERT_FILE: &str = "ra_cert.der";
+pub static SHIELDING_KEY_ROTATION_FILE: &str = "shielding-key-rotation.bin";
+pub const DEFAULT_SHIELDING_KEY_GRACE_PERIOD: u64 = 3600;
+pub static SHARD_BUNDLE_FILE: &str = "shard_bundle.bin";
+// in seconds
+pub const DEFAULT_REATTEST_INTERVAL: u64 = 6 * 3600;
+pub const DEFAULT_REGISTRATION_RENEWAL: u64 = 7 * 24 * 3600;
+
+pub static PROVISIONING_AUDIT_FILE: &str = "provisioning_audit.log";
+// leaves enclave TCS for the other ecalls
+pub const MAX_PROVISIONING_SESSIONS: usize = 4;
+// sessions per peer address within the window
+pub const PROVISIONING_RATE_LIMIT: usize = 3;
+// in seconds
+pub const PROVISIONING_RATE_WINDOW: u64 = 60;
+pub const PROVISIONING_TIMEOUT: u64 = 30;
+
+pub static IAS_ADDRESS_ENV: &str = "SUBSTRATEE_IAS_ADDRESS";
+pub static DEFAULT_IAS_ADDRESS: &str = "api.trustedservices.intel.com:443";
 
 #[cfg(feature = "production")]
 pub static RA_SPID_FILE: &str = "../bin/spid_production.txt";
@@ -34,5 +60,8 @@ pub static RA_API_KEY_FILE: &str = "../bin/key.txt";
 
 // the maximum size of any extrinsic that the enclave will ever generate in B
 pub static EXTRINSIC_MAX_SIZE: usize = 4196;
+// the maximum size of the registration extrinsic in B, whose RA certificate carries the quote
+// and, with DCAP, the collateral and revocation lists to verify it
+pub static REGISTRATION_EXTRINSIC_MAX_SIZE: usize = 131_072;
 // the maximum size of a value that will be queried from the state in B
 pub static STATE_VALUE_MAX_SIZE: usize = 1024;
@@ -24,8 +24,13 @@ use sgx_crypto_helper::rsa3072::Rsa3072PubKey;
 use sgx_types::*;
 use sgx_urts::SgxEnclave;
 
-use crate::constants::{ENCLAVE_FILE, ENCLAVE_TOKEN, EXTRINSIC_MAX_SIZE, STATE_VALUE_MAX_SIZE};
-use codec::Encode;
+use crate::constants::{
+    ENCLAVE_FILE, ENCLAVE_TOKEN, EXTRINSIC_MAX_SIZE, REGISTRATION_EXTRINSIC_MAX_SIZE,
+    STATE_VALUE_MAX_SIZE,
+};
+use codec::{Decode, Encode};
+use substratee_ra_verify::attestation_policy::AttestationPolicy;
+use substratee_stf::ShieldingKeyRotationSigned;
 
 extern "C" {
     fn init(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
@@ -46,6 +51,41 @@ extern "C" {
         unchecked_extrinsic_size: u32,
     ) -> sgx_status_t;
 
+    fn restore_state(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        shard: *const u8,
+        shard_size: u32,
+        snapshot: *const u8,
+        snapshot_size: u32,
+        authorization: *const u8,
+        authorization_size: u32,
+        genesis_hash: *const u8,
+        genesis_hash_size: u32,
+        nonce: *const u32,
+        unchecked_extrinsic: *mut u8,
+        unchecked_extrinsic_size: u32,
+    ) -> sgx_status_t;
+
+    fn export_shard(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        shard: *const u8,
+        shard_size: u32,
+        target: *const u8,
+        target_size: u32,
+        sign_type: sgx_quote_sign_type_t,
+        path: *const u8,
+        path_size: u32,
+    ) -> sgx_status_t;
+
+    fn import_shard(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        path: *const u8,
+        path_size: u32,
+    ) -> sgx_status_t;
+
     fn get_state(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
@@ -64,6 +104,13 @@ extern "C" {
         pubkey_size: u32,
     ) -> sgx_status_t;
 
+    fn get_x25519_encryption_pubkey(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        pubkey: *mut u8,
+        pubkey_size: u32,
+    ) -> sgx_status_t;
+
     fn get_ecc_signing_pubkey(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
@@ -92,6 +139,36 @@ extern "C" {
 
     fn dump_ra_to_disk(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
 
+    fn refresh_attestation(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+
+    fn rotate_state_key(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
+
+    fn rotate_shielding_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        grace_period: u64,
+        rotation: *mut u8,
+        rotation_size: u32,
+    ) -> sgx_status_t;
+
+    fn set_attestation_policy(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        policy: *const u8,
+        policy_size: u32,
+        authorization: *const u8,
+        authorization_size: u32,
+    ) -> sgx_status_t;
+
+    fn set_provisioning_policy(
+        eid: sgx_enclave_id_t,
+        retval: *mut sgx_status_t,
+        policy: *const u8,
+        policy_size: u32,
+        authorization: *const u8,
+        authorization_size: u32,
+    ) -> sgx_status_t;
+
     fn test_main_entrance(eid: sgx_enclave_id_t, retval: *mut sgx_status_t) -> sgx_status_t;
 }
 
@@ -217,6 +294,21 @@ pub fn enclave_shielding_key(eid: sgx_enclave_id_t) -> SgxResult<Vec<u8>> {
     Ok(pubkey)
 }
 
+pub fn enclave_x25519_shielding_key(eid: sgx_enclave_id_t) -> SgxResult<[u8; 32]> {
+    let pubkey_size = 32;
+    let mut pubkey = [0u8; 32];
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result =
+        unsafe { get_x25519_encryption_pubkey(eid, &mut status, pubkey.as_mut_ptr(), pubkey_size) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(pubkey)
+}
+
 pub fn enclave_query_state(
     eid: sgx_enclave_id_t,
     cyphertext: Vec<u8>,
@@ -274,6 +366,118 @@ pub fn enclave_dump_ra(eid: sgx_enclave_id_t) -> SgxResult<()> {
     Ok(())
 }
 
+/// Replaces the cached RA certificate of the enclave with one from a fresh attestation, and the
+/// certificate in `RA_CERT_FILE` with it.
+pub fn enclave_refresh_attestation(eid: sgx_enclave_id_t) -> SgxResult<()> {
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { refresh_attestation(eid, &mut status) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
+/// Re-encrypts all local shards under a new state key. Must not run concurrently with a worker.
+pub fn enclave_rotate_state_key(eid: sgx_enclave_id_t) -> SgxResult<()> {
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { rotate_state_key(eid, &mut status) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
+/// Replaces the shielding keys. The old ones keep decrypting for `grace_period` seconds.
+pub fn enclave_rotate_shielding_key(
+    eid: sgx_enclave_id_t,
+    grace_period: u64,
+) -> SgxResult<ShieldingKeyRotationSigned> {
+    let rotation_size = 512;
+    let mut rotation = vec![0u8; rotation_size as usize];
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        rotate_shielding_key(
+            eid,
+            &mut status,
+            grace_period,
+            rotation.as_mut_ptr(),
+            rotation_size,
+        )
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    ShieldingKeyRotationSigned::decode(&mut rotation.as_slice())
+        .map_err(|_| sgx_status_t::SGX_ERROR_UNEXPECTED)
+}
+
+/// Seals the policy the enclave checks the attestation of its MU-RA peers against.
+pub fn enclave_set_attestation_policy(
+    eid: sgx_enclave_id_t,
+    version: u64,
+    policy: &AttestationPolicy,
+    authorization: &[u8],
+) -> SgxResult<()> {
+    let policy = (version, policy).encode();
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        set_attestation_policy(
+            eid,
+            &mut status,
+            policy.as_ptr(),
+            policy.len() as u32,
+            authorization.as_ptr(),
+            authorization.len() as u32,
+        )
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
+/// Seals the MRENCLAVE and MRSIGNER values, besides its own MRENCLAVE, that the enclave
+/// provisions keys to and accepts keys from.
+pub fn enclave_set_provisioning_policy(
+    eid: sgx_enclave_id_t,
+    version: u64,
+    mr_enclaves: &[[u8; 32]],
+    mr_signers: &[[u8; 32]],
+    authorization: &[u8],
+) -> SgxResult<()> {
+    let policy = (version, mr_enclaves.to_vec(), mr_signers.to_vec()).encode();
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        set_provisioning_policy(
+            eid,
+            &mut status,
+            policy.as_ptr(),
+            policy.len() as u32,
+            authorization.as_ptr(),
+            authorization.len() as u32,
+        )
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
 pub fn enclave_execute_stf(
     eid: sgx_enclave_id_t,
     cyphertext: Vec<u8>,
@@ -311,13 +515,96 @@ pub fn enclave_execute_stf(
     Ok(unchecked_extrinsic)
 }
 
+/// Restores a state snapshot and returns the extrinsic that confirms it on chain.
+/// `authorization` is the operator's signature of the restore.
+pub fn enclave_restore_state(
+    eid: sgx_enclave_id_t,
+    shard: Vec<u8>,
+    snapshot: Vec<u8>,
+    authorization: Vec<u8>,
+    genesis_hash: Vec<u8>,
+    nonce: u32,
+) -> SgxResult<Vec<u8>> {
+    let unchecked_extrinsic_size = EXTRINSIC_MAX_SIZE;
+    let mut unchecked_extrinsic: Vec<u8> = vec![0u8; unchecked_extrinsic_size as usize];
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        restore_state(
+            eid,
+            &mut status,
+            shard.as_ptr(),
+            shard.len() as u32,
+            snapshot.as_ptr(),
+            snapshot.len() as u32,
+            authorization.as_ptr(),
+            authorization.len() as u32,
+            genesis_hash.as_ptr(),
+            genesis_hash.len() as u32,
+            &nonce,
+            unchecked_extrinsic.as_mut_ptr(),
+            unchecked_extrinsic_size as u32,
+        )
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(unchecked_extrinsic)
+}
+
+/// Writes a bundle of `shard` to `path` that can only be imported by the enclave owning the
+/// shielding key `target_pubkey` (json, as written by the `shielding-key` command)
+pub fn enclave_export_shard(
+    eid: sgx_enclave_id_t,
+    shard: Vec<u8>,
+    target: Vec<u8>,
+    sign_type: sgx_quote_sign_type_t,
+    path: &str,
+) -> SgxResult<()> {
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        export_shard(
+            eid,
+            &mut status,
+            shard.as_ptr(),
+            shard.len() as u32,
+            target.as_ptr(),
+            target.len() as u32,
+            sign_type,
+            path.as_ptr(),
+            path.len() as u32,
+        )
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
+pub fn enclave_import_shard(eid: sgx_enclave_id_t, path: &str) -> SgxResult<()> {
+    let mut status = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe { import_shard(eid, &mut status, path.as_ptr(), path.len() as u32) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(status);
+    }
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result);
+    }
+    Ok(())
+}
+
 pub fn enclave_perform_ra(
     eid: sgx_enclave_id_t,
     genesis_hash: Vec<u8>,
     nonce: u32,
     url: Vec<u8>,
 ) -> SgxResult<Vec<u8>> {
-    let unchecked_extrinsic_size = EXTRINSIC_MAX_SIZE;
+    let unchecked_extrinsic_size = REGISTRATION_EXTRINSIC_MAX_SIZE;
     let mut unchecked_extrinsic: Vec<u8> = vec![0u8; unchecked_extrinsic_size as usize];
     let mut status = sgx_status_t::SGX_SUCCESS;
     let result = unsafe {
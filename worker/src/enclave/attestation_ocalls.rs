@@ -16,6 +16,7 @@
 
 */
 
+use std::env;
 use std::net::{SocketAddr, TcpStream};
 use std::os::unix::io::IntoRawFd;
 use std::str;
@@ -23,6 +24,8 @@ use std::str;
 use log::*;
 use sgx_types::*;
 
+use crate::constants::{DEFAULT_IAS_ADDRESS, IAS_ADDRESS_ENV};
+
 #[no_mangle]
 pub extern "C" fn ocall_sgx_init_quote(
     ret_ti: *mut sgx_target_info_t,
@@ -45,11 +48,23 @@ pub fn lookup_ipv4(host: &str, port: u16) -> SocketAddr {
     unreachable!("Cannot lookup address");
 }
 
+/// Connects to `$SUBSTRATEE_IAS_ADDRESS` (`host:port`) instead of Intel's IAS if set, e.g. to
+/// `substratee-mock-ias` for enclaves built with the `mock-ra` feature.
 #[no_mangle]
 pub extern "C" fn ocall_get_ias_socket(ret_fd: *mut c_int) -> sgx_status_t {
     debug!("    Entering ocall_get_ias_socket");
-    let port = 443;
-    let hostname = "api.trustedservices.intel.com";
+    let address = env::var(IAS_ADDRESS_ENV).unwrap_or_else(|_| DEFAULT_IAS_ADDRESS.to_string());
+    let (hostname, port) = match address.rfind(':') {
+        Some(pos) => (&address[..pos], address[pos + 1..].parse::<u16>()),
+        None => (address.as_str(), Ok(443)),
+    };
+    let port = match port {
+        Ok(p) => p,
+        Err(_) => {
+            error!("    Invalid port in {}={}", IAS_ADDRESS_ENV, address);
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
     let addr = lookup_ipv4(hostname, port);
     let sock = TcpStream::connect(&addr).expect("[-] Connect tls server failed!");
 
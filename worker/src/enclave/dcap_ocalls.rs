@@ -0,0 +1,221 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! DCAP quote generation through the quoting library and collateral retrieval through the
+//! quote provider library, which is configured in `/etc/sgx_default_qcnl.conf`.
+//!
+//! Without the `dcap` feature the ocalls exist, but fail, as the enclave never calls them.
+
+#[cfg(not(feature = "dcap"))]
+use sgx_types::*;
+
+#[cfg(not(feature = "dcap"))]
+#[no_mangle]
+pub extern "C" fn ocall_get_qe_target_info(_ret_ti: *mut sgx_target_info_t) -> sgx_status_t {
+    sgx_status_t::SGX_ERROR_FEATURE_NOT_SUPPORTED
+}
+
+#[cfg(not(feature = "dcap"))]
+#[no_mangle]
+pub extern "C" fn ocall_get_dcap_quote(
+    _p_report: *const sgx_report_t,
+    _p_quote: *mut u8,
+    _maxlen: u32,
+    _p_quote_len: *mut u32,
+) -> sgx_status_t {
+    sgx_status_t::SGX_ERROR_FEATURE_NOT_SUPPORTED
+}
+
+#[cfg(not(feature = "dcap"))]
+#[no_mangle]
+pub extern "C" fn ocall_get_dcap_collateral(
+    _p_quote: *const u8,
+    _quote_len: u32,
+    _p_collateral: *mut u8,
+    _maxlen: u32,
+    _p_collateral_len: *mut u32,
+) -> sgx_status_t {
+    sgx_status_t::SGX_ERROR_FEATURE_NOT_SUPPORTED
+}
+
+#[cfg(feature = "dcap")]
+mod dcap {
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+    use std::{ptr, slice};
+
+    use codec::Encode;
+    use log::*;
+    use sgx_types::*;
+    use substratee_ra_verify::dcap;
+
+    /// `quote3_error_t`
+    type Quote3Error = u32;
+    const SGX_QL_SUCCESS: Quote3Error = 0;
+
+    /// `sgx_ql_qve_collateral_t` of DCAP 1.x
+    #[repr(C)]
+    struct QveCollateral {
+        version: u32,
+        pck_crl_issuer_chain: *const c_char,
+        pck_crl_issuer_chain_size: u32,
+        root_ca_crl: *const c_char,
+        root_ca_crl_size: u32,
+        pck_crl: *const c_char,
+        pck_crl_size: u32,
+        tcb_info_issuer_chain: *const c_char,
+        tcb_info_issuer_chain_size: u32,
+        tcb_info: *const c_char,
+        tcb_info_size: u32,
+        qe_identity_issuer_chain: *const c_char,
+        qe_identity_issuer_chain_size: u32,
+        qe_identity: *const c_char,
+        qe_identity_size: u32,
+    }
+
+    #[link(name = "sgx_dcap_ql")]
+    extern "C" {
+        fn sgx_qe_get_target_info(p_qe_target_info: *mut sgx_target_info_t) -> Quote3Error;
+        fn sgx_qe_get_quote_size(p_quote_size: *mut u32) -> Quote3Error;
+        fn sgx_qe_get_quote(
+            p_app_report: *const sgx_report_t,
+            quote_size: u32,
+            p_quote: *mut u8,
+        ) -> Quote3Error;
+    }
+
+    #[link(name = "dcap_quoteprov")]
+    extern "C" {
+        fn sgx_ql_get_quote_verification_collateral(
+            fmspc: *const u8,
+            fmspc_size: u16,
+            pck_ca: *const c_char,
+            pp_quote_collateral: *mut *mut QveCollateral,
+        ) -> Quote3Error;
+        fn sgx_ql_free_quote_verification_collateral(
+            p_quote_collateral: *const QveCollateral,
+        ) -> Quote3Error;
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ocall_get_qe_target_info(ret_ti: *mut sgx_target_info_t) -> sgx_status_t {
+        debug!("    Entering ocall_get_qe_target_info");
+        let ret = unsafe { sgx_qe_get_target_info(ret_ti) };
+        if ret != SGX_QL_SUCCESS {
+            error!("    sgx_qe_get_target_info failed. {:#x}", ret);
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+        sgx_status_t::SGX_SUCCESS
+    }
+
+    #[no_mangle]
+    pub extern "C" fn ocall_get_dcap_quote(
+        p_report: *const sgx_report_t,
+        p_quote: *mut u8,
+        maxlen: u32,
+        p_quote_len: *mut u32,
+    ) -> sgx_status_t {
+        debug!("    Entering ocall_get_dcap_quote");
+        let mut quote_len: u32 = 0;
+        let ret = unsafe { sgx_qe_get_quote_size(&mut quote_len as *mut u32) };
+        if ret != SGX_QL_SUCCESS {
+            error!("    sgx_qe_get_quote_size failed. {:#x}", ret);
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+        if quote_len > maxlen {
+            error!(
+                "    Quote size {} exceeds the buffer of {}",
+                quote_len, maxlen
+            );
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+
+        let ret = unsafe { sgx_qe_get_quote(p_report, quote_len, p_quote) };
+        if ret != SGX_QL_SUCCESS {
+            error!("    sgx_qe_get_quote failed. {:#x}", ret);
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+        unsafe {
+            *p_quote_len = quote_len;
+        }
+        sgx_status_t::SGX_SUCCESS
+    }
+
+    /// Writes the SCALE encoded `(tcb_info, tcb_info_issuer_chain, qe_identity,
+    /// qe_identity_issuer_chain, pck_crl, root_ca_crl)` for the platform of the quote.
+    #[no_mangle]
+    pub unsafe extern "C" fn ocall_get_dcap_collateral(
+        p_quote: *const u8,
+        quote_len: u32,
+        p_collateral: *mut u8,
+        maxlen: u32,
+        p_collateral_len: *mut u32,
+    ) -> sgx_status_t {
+        debug!("    Entering ocall_get_dcap_collateral");
+        let quote = slice::from_raw_parts(p_quote, quote_len as usize);
+        let (fmspc, pck_ca) = match dcap::collateral_id(quote) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("    Can't read the PCK certificate of the quote: {:?}", e);
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+        };
+        let pck_ca = CString::new(pck_ca).unwrap();
+
+        let mut collateral: *mut QveCollateral = ptr::null_mut();
+        let ret = sgx_ql_get_quote_verification_collateral(
+            fmspc.as_ptr(),
+            fmspc.len() as u16,
+            pck_ca.as_ptr(),
+            &mut collateral as *mut *mut QveCollateral,
+        );
+        if ret != SGX_QL_SUCCESS || collateral.is_null() {
+            error!(
+                "    sgx_ql_get_quote_verification_collateral failed. {:#x}",
+                ret
+            );
+            return sgx_status_t::SGX_ERROR_UNEXPECTED;
+        }
+
+        let c = &*collateral;
+        let field = |p: *const c_char, size: u32| -> Vec<u8> {
+            slice::from_raw_parts(p as *const u8, size as usize).to_vec()
+        };
+        let encoded = (
+            field(c.tcb_info, c.tcb_info_size),
+            field(c.tcb_info_issuer_chain, c.tcb_info_issuer_chain_size),
+            field(c.qe_identity, c.qe_identity_size),
+            field(c.qe_identity_issuer_chain, c.qe_identity_issuer_chain_size),
+            field(c.pck_crl, c.pck_crl_size),
+            field(c.root_ca_crl, c.root_ca_crl_size),
+        )
+            .encode();
+        sgx_ql_free_quote_verification_collateral(collateral);
+
+        if encoded.len() > maxlen as usize {
+            error!(
+                "    Collateral size {} exceeds the buffer of {}",
+                encoded.len(),
+                maxlen
+            );
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+        slice::from_raw_parts_mut(p_collateral, encoded.len()).copy_from_slice(&encoded);
+        *p_collateral_len = encoded.len() as u32;
+        sgx_status_t::SGX_SUCCESS
+    }
+}
@@ -1,3 +1,5 @@
 pub mod api;
 pub mod attestation_ocalls;
+pub mod dcap_ocalls;
+pub mod state_ocalls;
 pub mod tls_ra;
@@ -0,0 +1,151 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::slice;
+use std::str;
+
+use base58::ToBase58;
+use codec::Encode;
+use log::*;
+use my_node_runtime::substratee_registry::ShardIdentifier;
+use sgx_types::*;
+
+use crate::constants::{PREVIOUS_STATE_FILE_SUFFIX, TEMPORARY_STATE_FILE_SUFFIX};
+use crate::history;
+
+/// Replaces the file at `path` such that a crash leaves either the old or the new version
+/// behind, never a truncated one. The replaced version is kept as `<path>.prev`.
+///
+/// # Safety
+///
+/// FFI are always unsafe
+#[no_mangle]
+pub unsafe extern "C" fn ocall_write_state(
+    path: *const u8,
+    path_size: u32,
+    enc_state: *const u8,
+    enc_state_size: u32,
+) -> sgx_status_t {
+    debug!("    Entering ocall_write_state");
+    let path = match str::from_utf8(slice::from_raw_parts(path, path_size as usize)) {
+        Ok(p) => p,
+        Err(_) => return sgx_status_t::SGX_ERROR_INVALID_PARAMETER,
+    };
+    let state = slice::from_raw_parts(enc_state, enc_state_size as usize);
+
+    match write_atomic(path, state) {
+        Ok(()) => sgx_status_t::SGX_SUCCESS,
+        Err(e) => {
+            error!("[-] Writing state to '{}' failed: {}", path, e);
+            sgx_status_t::SGX_ERROR_UNEXPECTED
+        }
+    }
+}
+
+fn write_atomic(path: &str, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp = format!("{}{}", path, TEMPORARY_STATE_FILE_SUFFIX);
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+
+    // the current version stays in place until the rename replaces it
+    if Path::new(path).exists() {
+        let prev = format!("{}{}", path, PREVIOUS_STATE_FILE_SUFFIX);
+        if Path::new(&prev).exists() {
+            fs::remove_file(&prev)?;
+        }
+        fs::hard_link(path, &prev)?;
+    }
+    fs::rename(&tmp, path)?;
+
+    // persist the link and the rename
+    if let Some(dir) = Path::new(path).parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Looks up the block number of the snapshot of `shard` with `state_hash` in the history.
+/// Unknown states are reported as block 0.
+///
+/// # Safety
+///
+/// FFI are always unsafe
+#[no_mangle]
+pub unsafe extern "C" fn ocall_state_block_number(
+    shard: *const u8,
+    shard_size: u32,
+    state_hash: *const u8,
+    hash_size: u32,
+    block_number: *mut u64,
+) -> sgx_status_t {
+    debug!("    Entering ocall_state_block_number");
+    if shard_size != 32 {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    let shard = ShardIdentifier::from_slice(slice::from_raw_parts(shard, shard_size as usize));
+    let state_hash = slice::from_raw_parts(state_hash, hash_size as usize);
+
+    *block_number = history::list(&shard)
+        .into_iter()
+        .rev()
+        .find(|s| s.state_hash == state_hash)
+        .map(|s| s.block_number)
+        .unwrap_or(0);
+    sgx_status_t::SGX_SUCCESS
+}
+
+/// The enclave fell back to the previous version of the state of `shard`, because the
+/// current one is not the state it has recorded latest.
+///
+/// # Safety
+///
+/// FFI are always unsafe
+#[no_mangle]
+pub unsafe extern "C" fn ocall_state_recovered(
+    shard: *const u8,
+    shard_size: u32,
+    state_hash: *const u8,
+    hash_size: u32,
+) -> sgx_status_t {
+    debug!("    Entering ocall_state_recovered");
+    if shard_size != 32 {
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
+    let shard = ShardIdentifier::from_slice(slice::from_raw_parts(shard, shard_size as usize));
+    let state_hash = slice::from_raw_parts(state_hash, hash_size as usize);
+
+    let block = match history::list(&shard)
+        .into_iter()
+        .rev()
+        .find(|s| s.state_hash == state_hash)
+    {
+        Some(s) => format!("block {}", s.block_number),
+        None => "unknown block".to_string(),
+    };
+    warn!(
+        "[!] Enclave recovered the previous state 0x{} ({}) of shard {}: \
+         the current state is not the one the enclave recorded latest",
+        hex::encode(state_hash),
+        block,
+        shard.encode().to_base58()
+    );
+    sgx_status_t::SGX_SUCCESS
+}
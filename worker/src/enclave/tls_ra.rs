@@ -14,12 +14,30 @@
     limitations under the License.
 
 */
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use sgx_types::*;
 
+use base58::ToBase58;
+use codec::{Decode, Encode};
 use log::*;
+use my_node_runtime::substratee_registry::ShardIdentifier;
+use primitives::crypto::{AccountId32, Ss58Codec};
+use substratee_stf::MAX_PROVISIONED_SHARDS;
+
+use crate::constants::{
+    MAX_PROVISIONING_SESSIONS, PROVISIONING_AUDIT_FILE, PROVISIONING_RATE_LIMIT,
+    PROVISIONING_RATE_WINDOW, PROVISIONING_TIMEOUT,
+};
 
 extern "C" {
     fn run_key_provisioning_server(
@@ -27,62 +45,276 @@ extern "C" {
         retval: *mut sgx_status_t,
         socket_fd: c_int,
         sign_type: sgx_quote_sign_type_t,
+        state_transfer: u32,
+        record: *mut u8,
+        record_size: u32,
     ) -> sgx_status_t;
     fn request_key_provisioning(
         eid: sgx_enclave_id_t,
         retval: *mut sgx_status_t,
         socket_fd: c_int,
         sign_type: sgx_quote_sign_type_t,
+        state_transfer: u32,
+        shards: *const u8,
+        shards_size: u32,
+        provisioned: *mut u8,
+        provisioned_size: u32,
     ) -> sgx_status_t;
 }
 
+/// How the encrypted state travels between the provisioning worker and the provisioned one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StateTransfer {
+    /// in chunks over the MU-RA session itself
+    Stream,
+    /// through IPFS, which needs a daemon on both workers
+    Ipfs,
+}
+
+impl StateTransfer {
+    /// The capability bit of the enclave's provisioning protocol
+    fn capability(self) -> u32 {
+        match self {
+            StateTransfer::Ipfs => 1,
+            StateTransfer::Stream => 2,
+        }
+    }
+}
+
+impl Default for StateTransfer {
+    fn default() -> Self {
+        StateTransfer::Stream
+    }
+}
+
+impl FromStr for StateTransfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(StateTransfer::Stream),
+            "ipfs" => Ok(StateTransfer::Ipfs),
+            _ => Err(format!("unknown state transfer '{}'", s)),
+        }
+    }
+}
+
+/// Serves key provisioning on `addr`, one thread and enclave session per connection.
+///
+/// Each peer address may open `PROVISIONING_RATE_LIMIT` sessions per
+/// `PROVISIONING_RATE_WINDOW`, and at most `MAX_PROVISIONING_SESSIONS` run at once, such that
+/// provisioning doesn't take all TCS of the enclave. A peer that stalls for
+/// `PROVISIONING_TIMEOUT` is disconnected.
 pub fn enclave_run_key_provisioning_server(
     eid: sgx_enclave_id_t,
     sign_type: sgx_quote_sign_type_t,
     addr: &str,
+    state_transfer: StateTransfer,
 ) {
     info!("Starting MU-RA-Server on: {}", addr);
     let listener = TcpListener::bind(addr).unwrap();
-    loop {
-        match listener.accept() {
-            Ok((socket, addr)) => {
-                info!(
-                    "[MU-RA-Server] a worker at {} is requesting key provisiong",
-                    addr
-                );
-                let mut retval = sgx_status_t::SGX_SUCCESS;
-                let result = unsafe {
-                    run_key_provisioning_server(eid, &mut retval, socket.as_raw_fd(), sign_type)
-                };
-                match result {
-                    sgx_status_t::SGX_SUCCESS => {
-                        debug!("[MU-RA-Server] ECALL success!");
-                    }
-                    _ => {
-                        error!("[MU-RA-Server] ECALL Enclave Failed {}!", result.as_str());
-                    }
+    let sessions = Arc::new(AtomicUsize::new(0));
+    let mut rate_limit = RateLimit::new(
+        PROVISIONING_RATE_LIMIT,
+        Duration::from_secs(PROVISIONING_RATE_WINDOW),
+    );
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("couldn't get client: {:?}", e);
+                continue;
+            }
+        };
+        let peer = match socket.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                error!("[MU-RA-Server] unknown peer address: {:?}", e);
+                continue;
+            }
+        };
+        if sessions.load(Ordering::SeqCst) >= MAX_PROVISIONING_SESSIONS {
+            warn!("[MU-RA-Server] too many sessions, disconnecting {}", peer);
+            continue;
+        }
+        let timeout = Some(Duration::from_secs(PROVISIONING_TIMEOUT));
+        if let Err(e) = socket
+            .set_read_timeout(timeout)
+            .and_then(|_| socket.set_write_timeout(timeout))
+        {
+            error!("[MU-RA-Server] could not set the timeouts: {:?}", e);
+            continue;
+        }
+        // last, such that only accepted connections count against the peer's limit
+        if !rate_limit.admit(peer.ip(), Instant::now()) {
+            warn!(
+                "[MU-RA-Server] {} exceeds the rate limit, disconnecting",
+                peer
+            );
+            continue;
+        }
+
+        info!(
+            "[MU-RA-Server] a worker at {} is requesting key provisiong",
+            peer
+        );
+        sessions.fetch_add(1, Ordering::SeqCst);
+        let sessions = sessions.clone();
+        thread::spawn(move || {
+            serve_session(eid, sign_type, state_transfer, &socket, peer);
+            sessions.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn serve_session(
+    eid: sgx_enclave_id_t,
+    sign_type: sgx_quote_sign_type_t,
+    state_transfer: StateTransfer,
+    socket: &TcpStream,
+    peer: SocketAddr,
+) {
+    // (mr_enclave, signer, shards)
+    let mut record = vec![0u8; 32 + 32 + 4 + MAX_PROVISIONED_SHARDS * 32];
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        run_key_provisioning_server(
+            eid,
+            &mut retval,
+            socket.as_raw_fd(),
+            sign_type,
+            state_transfer.capability(),
+            record.as_mut_ptr(),
+            record.len() as u32,
+        )
+    };
+    if result != sgx_status_t::SGX_SUCCESS || retval != sgx_status_t::SGX_SUCCESS {
+        error!(
+            "[MU-RA-Server] provisioning {} failed: {} {}",
+            peer,
+            result.as_str(),
+            retval.as_str()
+        );
+        return;
+    }
+    match Decode::decode(&mut record.as_slice()) {
+        Ok(record) => audit(peer, record),
+        Err(_) => error!(
+            "[MU-RA-Server] could not decode the audit record of {}",
+            peer
+        ),
+    }
+}
+
+/// Appends who has been provisioned with which shards to the audit log. The peer is named by its
+/// MRENCLAVE and the chain signer its RA certificate commits to.
+fn audit(
+    peer: SocketAddr,
+    (mr_enclave, signer, shards): ([u8; 32], [u8; 32], Vec<ShardIdentifier>),
+) {
+    let shards: Vec<String> = shards.iter().map(|s| s.encode().to_base58()).collect();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let entry = format!(
+        "{} peer={} mrenclave={} signer={} shards={}",
+        now,
+        peer,
+        mr_enclave.to_base58(),
+        AccountId32::from(signer).to_ss58check(),
+        shards.join(",")
+    );
+    info!("[MU-RA-Server] provisioned {}", entry);
+
+    let appended = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(PROVISIONING_AUDIT_FILE)
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = appended {
+        error!("could not write '{}': {}", PROVISIONING_AUDIT_FILE, e);
+    }
+}
+
+/// Admits at most `max` sessions per peer within any `window`
+struct RateLimit {
+    max: usize,
+    window: Duration,
+    sessions: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl RateLimit {
+    fn new(max: usize, window: Duration) -> Self {
+        RateLimit {
+            max,
+            window,
+            sessions: HashMap::new(),
+        }
+    }
+
+    fn admit(&mut self, peer: IpAddr, now: Instant) -> bool {
+        let window = self.window;
+        for started in self.sessions.values_mut() {
+            while let Some(t) = started.front() {
+                if now.duration_since(*t) < window {
+                    break;
                 }
+                started.pop_front();
             }
-            Err(e) => error!("couldn't get client: {:?}", e),
         }
+        self.sessions.retain(|_, started| !started.is_empty());
+
+        let started = self.sessions.entry(peer).or_insert_with(VecDeque::new);
+        if started.len() >= self.max {
+            return false;
+        }
+        started.push_back(now);
+        true
     }
 }
 
+/// Requests the shielding key and the state keys and states of `shards` from the worker at
+/// `addr`. The provisioning worker only hands out shards it serves itself.
+///
+/// Returns the shards whose state has been installed, with the block number of each state.
 pub fn enclave_request_key_provisioning(
     eid: sgx_enclave_id_t,
     sign_type: sgx_quote_sign_type_t,
     addr: &str,
-) -> SgxResult<()> {
+    shards: &[ShardIdentifier],
+    state_transfer: StateTransfer,
+) -> SgxResult<Vec<(ShardIdentifier, u64)>> {
     info!("[MU-RA-Client] Requesting key provisioning from {}", addr);
-    let socket = TcpStream::connect(addr).unwrap();
+    let socket = TcpStream::connect(addr).map_err(|e| {
+        error!("[MU-RA-Client] Could not connect to {}: {}", addr, e);
+        sgx_status_t::SGX_ERROR_UNEXPECTED
+    })?;
+    let shards_encoded = shards.encode();
+    // (shard, block number) per shard, plus the length prefix
+    let mut provisioned = vec![0u8; shards.len() * 40 + 8];
     let mut status = sgx_status_t::SGX_SUCCESS;
-    let result =
-        unsafe { request_key_provisioning(eid, &mut status, socket.as_raw_fd(), sign_type) };
+    let result = unsafe {
+        request_key_provisioning(
+            eid,
+            &mut status,
+            socket.as_raw_fd(),
+            sign_type,
+            state_transfer.capability(),
+            shards_encoded.as_ptr(),
+            shards_encoded.len() as u32,
+            provisioned.as_mut_ptr(),
+            provisioned.len() as u32,
+        )
+    };
     if status != sgx_status_t::SGX_SUCCESS {
         return Err(status);
     }
     if result != sgx_status_t::SGX_SUCCESS {
         return Err(result);
     }
-    Ok(())
+    Decode::decode(&mut provisioned.as_slice()).map_err(|_| {
+        error!("[MU-RA-Client] Could not decode the provisioned shards");
+        sgx_status_t::SGX_ERROR_UNEXPECTED
+    })
 }
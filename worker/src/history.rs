@@ -0,0 +1,190 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Encrypted snapshots of the state of each shard, tagged with the block number at which the
+//! state hash has been confirmed.
+//!
+//! Snapshots live in `<SHARDS_PATH>/<shard>/history/<block>_<state hash>.bin`. They are
+//! verbatim copies of the sealed state, so they can only be restored by the enclave, and only
+//! as long as the state key hasn't been rotated since.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+
+use base58::ToBase58;
+use codec::Encode;
+use log::*;
+use my_node_runtime::substratee_registry::ShardIdentifier;
+use sha2::{Digest, Sha256};
+
+use crate::constants::{ENCRYPTED_STATE_FILE, HISTORY_PATH, SHARDS_PATH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub block_number: u64,
+    pub state_hash: Vec<u8>,
+    pub path: PathBuf,
+}
+
+/// States the enclave has written, whose confirmation hasn't been seen yet. They are matched
+/// to their confirmation by the state hash, as other confirmations of the enclave, e.g. of a
+/// restored state, may come in between.
+pub struct Unconfirmed {
+    keep: usize,
+    /// (shard, state hash, state), oldest first
+    states: VecDeque<(ShardIdentifier, Vec<u8>, Vec<u8>)>,
+}
+
+impl Unconfirmed {
+    pub fn new(keep: usize) -> Unconfirmed {
+        Unconfirmed {
+            keep,
+            states: VecDeque::new(),
+        }
+    }
+
+    /// Remembers the state of `shard` the enclave has just written
+    pub fn push(&mut self, shard: &ShardIdentifier) {
+        if self.keep == 0 {
+            return;
+        }
+        match fs::read(state_path(shard)) {
+            Ok(state) => {
+                let state_hash = Sha256::digest(&state).to_vec();
+                self.states.push_back((*shard, state_hash, state))
+            }
+            Err(e) => error!("could not read state for snapshot: {}", e),
+        }
+    }
+
+    /// Snapshots the state of `shard` with `state_hash`, which has been confirmed in
+    /// `block_number`. The states of `shard` written before it won't be confirmed anymore.
+    pub fn confirm(&mut self, shard: &ShardIdentifier, state_hash: &[u8], block_number: u64) {
+        let confirmed = match self
+            .states
+            .iter()
+            .position(|(s, h, _)| s == shard && h.as_slice() == state_hash)
+        {
+            Some(confirmed) => confirmed,
+            None => {
+                debug!(
+                    "no unconfirmed state 0x{} of shard {} to snapshot",
+                    hex::encode(state_hash),
+                    shard.encode().to_base58()
+                );
+                return;
+            }
+        };
+        store(shard, block_number, &self.states[confirmed].2, self.keep);
+
+        let mut i = 0;
+        self.states.retain(|(s, _, _)| {
+            let superseded = s == shard && i <= confirmed;
+            i += 1;
+            !superseded
+        });
+    }
+}
+
+/// Copies the current state of `shard` into its history and drops the oldest snapshots
+/// beyond `keep`.
+pub fn snapshot(shard: &ShardIdentifier, block_number: u64, keep: usize) {
+    if keep == 0 {
+        return;
+    }
+    match fs::read(state_path(shard)) {
+        Ok(state) => store(shard, block_number, &state, keep),
+        Err(e) => error!("could not read state for snapshot: {}", e),
+    }
+}
+
+fn store(shard: &ShardIdentifier, block_number: u64, state: &[u8], keep: usize) {
+    let state_hash = Sha256::digest(&state).to_vec();
+
+    let dir = history_path(shard);
+    let path = dir.join(format!(
+        "{:010}_{}.bin",
+        block_number,
+        hex::encode(&state_hash)
+    ));
+    if let Err(e) = fs::create_dir_all(&dir).and_then(|_| fs::write(&path, state)) {
+        error!("could not write snapshot {}: {}", path.display(), e);
+        return;
+    }
+    info!(
+        "snapshot of shard {} at block {} written",
+        shard.encode().to_base58(),
+        block_number
+    );
+
+    let snapshots = list(shard);
+    if snapshots.len() > keep {
+        for old in snapshots[..snapshots.len() - keep].iter() {
+            debug!("dropping snapshot {}", old.path.display());
+            if let Err(e) = fs::remove_file(&old.path) {
+                warn!("could not remove snapshot {}: {}", old.path.display(), e);
+            }
+        }
+    }
+}
+
+/// All snapshots of `shard`, oldest first
+pub fn list(shard: &ShardIdentifier) -> Vec<Snapshot> {
+    let entries = match fs::read_dir(history_path(shard)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut snapshots: Vec<Snapshot> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| parse(entry.path()))
+        .collect();
+    snapshots.sort_by_key(|s| s.block_number);
+    snapshots
+}
+
+/// The latest snapshot of `shard` taken at or before `block_number`
+pub fn find(shard: &ShardIdentifier, block_number: u64) -> Option<Snapshot> {
+    list(shard)
+        .into_iter()
+        .filter(|s| s.block_number <= block_number)
+        .last()
+}
+
+fn parse(path: PathBuf) -> Option<Snapshot> {
+    let stem = path.file_stem()?.to_str()?.to_string();
+    let mut parts = stem.splitn(2, '_');
+    let block_number = parts.next()?.parse().ok()?;
+    let state_hash = hex::decode(parts.next()?).ok()?;
+    Some(Snapshot {
+        block_number,
+        state_hash,
+        path,
+    })
+}
+
+fn state_path(shard: &ShardIdentifier) -> PathBuf {
+    PathBuf::from(SHARDS_PATH)
+        .join(shard.encode().to_base58())
+        .join(ENCRYPTED_STATE_FILE)
+}
+
+fn history_path(shard: &ShardIdentifier) -> PathBuf {
+    PathBuf::from(SHARDS_PATH)
+        .join(shard.encode().to_base58())
+        .join(HISTORY_PATH)
+}
@@ -108,10 +108,22 @@ pub unsafe extern "C" fn ocall_read_ipfs(
     let state = slice::from_raw_parts_mut(enc_state, enc_state_size as usize);
     let _cid = slice::from_raw_parts(cid, cid_size as usize);
 
-    let mut cid = [0; 46];
+    let mut cid: Cid = [0; 46];
+    if _cid.len() != cid.len() {
+        error!("invalid IPFS CID of {} bytes", _cid.len());
+        return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+    }
     cid.clone_from_slice(_cid);
 
     let res = read_from_ipfs(cid);
+    if res.len() != state.len() {
+        error!(
+            "IPFS returned {} bytes instead of the expected {}",
+            res.len(),
+            state.len()
+        );
+        return sgx_status_t::SGX_ERROR_UNEXPECTED;
+    }
     state.clone_from_slice(&res);
 
     sgx_status_t::SGX_SUCCESS
@@ -20,23 +20,26 @@ use std::io::Write;
 use std::path::Path;
 use std::str;
 use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 
 use sgx_types::*;
 
 use base58::{FromBase58, ToBase58};
-use clap::{load_yaml, App};
+use clap::{load_yaml, App, ArgMatches};
 use codec::{Decode, Encode};
 use keyring::AccountKeyring;
+use lazy_static::lazy_static;
 use log::*;
 use my_node_runtime::{
-    substratee_registry::{Request, ShardIdentifier},
+    substratee_registry::{Enclave, Request, ShardIdentifier},
     Event, Hash, UncheckedExtrinsic,
 };
 use primitive_types::U256;
 use primitives::{
     crypto::{AccountId32, Ss58Codec},
-    sr25519, Pair,
+    ed25519, sr25519, Pair,
 };
 use substrate_api_client::{
     extrinsic::xt_primitives::GenericAddress,
@@ -45,21 +48,41 @@ use substrate_api_client::{
 };
 
 use enclave::api::{
-    enclave_dump_ra, enclave_execute_stf, enclave_init, enclave_perform_ra, enclave_shielding_key,
-    enclave_signing_key, mrenclave,
+    enclave_dump_ra, enclave_execute_stf, enclave_export_shard, enclave_import_shard, enclave_init,
+    enclave_perform_ra, enclave_restore_state, enclave_rotate_shielding_key,
+    enclave_rotate_state_key, enclave_set_attestation_policy, enclave_set_provisioning_policy,
+    enclave_shielding_key, enclave_signing_key, mrenclave,
 };
-use enclave::tls_ra::{enclave_request_key_provisioning, enclave_run_key_provisioning_server};
+use enclave::tls_ra::{
+    enclave_request_key_provisioning, enclave_run_key_provisioning_server, StateTransfer,
+};
+use sha2::{Digest, Sha256};
 use std::slice;
-use substratee_node_calls::{get_worker_for_shard, get_worker_info};
+use substratee_node_calls::{
+    get_block_number, get_latest_state_hash, get_worker_amount, get_worker_for_shard,
+    get_worker_info, AccountId,
+};
+use substratee_ra_verify::attestation_policy::{
+    AttestationPolicy, QuoteStatus, DEFAULT_MAX_REPORT_AGE,
+};
+use substratee_stf::{EnclaveCredentials, ShardBundle};
 use substratee_worker_api::Api as WorkerApi;
 use ws_server::start_ws_server;
 
 mod constants;
 mod enclave;
+mod history;
 mod ipfs;
+mod reattestation;
 mod tests;
 mod ws_server;
 
+lazy_static! {
+    /// Held from reading the nonce of the enclave's account until the extrinsic signed with it
+    /// has been finalized. The main loop and the re-attestation thread both send such extrinsics.
+    static ref ENCLAVE_NONCE_LOCK: Mutex<()> = Mutex::new(());
+}
+
 fn main() {
     // Setup logging
     env_logger::init();
@@ -81,44 +104,43 @@ fn main() {
 
     if let Some(_matches) = matches.subcommand_matches("run") {
         println!("*** Starting substraTEE-worker");
-        let shard: ShardIdentifier = match _matches.value_of("shard") {
-            Some(value) => {
-                let shard_vec = value.from_base58().unwrap();
-                let mut shard = [0u8; 32];
-                shard.copy_from_slice(&shard_vec[..]);
-                shard.into()
-            }
-            _ => {
-                let enclave = enclave_init().unwrap();
-                let mrenclave = mrenclave(enclave.geteid()).unwrap();
-                info!(
-                    "no shard specified. using mrenclave as id: {}",
-                    mrenclave.to_base58()
-                );
-                ShardIdentifier::from_slice(&mrenclave[..])
-            }
+        let shard = get_shard(_matches);
+        let history_size = match _matches.value_of("history-size") {
+            Some(n) => n.parse().expect("history size must be a number"),
+            None => constants::DEFAULT_HISTORY_SIZE,
+        };
+        let seconds = |arg: &str, default: u64| match _matches.value_of(arg) {
+            Some(secs) => Duration::from_secs(
+                secs.parse()
+                    .unwrap_or_else(|_| panic!("{} must be a number of seconds", arg)),
+            ),
+            None => Duration::from_secs(default),
+        };
+        let schedule = reattestation::Schedule {
+            interval: seconds("reattest-interval", constants::DEFAULT_REATTEST_INTERVAL),
+            renewal: seconds(
+                "registration-renewal",
+                constants::DEFAULT_REGISTRATION_RENEWAL,
+            ),
+        };
+        let state_transfer = match _matches.value_of("state-transfer") {
+            Some(mode) => mode.parse().unwrap(),
+            None => StateTransfer::default(),
         };
-        worker(&n_url, w_ip, w_port, mu_ra_port, &shard);
+        worker(
+            &n_url,
+            w_ip,
+            w_port,
+            mu_ra_port,
+            &shard,
+            history_size,
+            schedule,
+            state_transfer,
+        );
     } else if matches.is_present("shielding-key") {
         info!("*** Get the public key from the TEE\n");
         let enclave = enclave_init().unwrap();
-        let pubkey = enclave_shielding_key(enclave.geteid()).unwrap();
-        let file = File::create(constants::SHIELDING_KEY_FILE).unwrap();
-        match serde_json::to_writer(file, &pubkey) {
-            Err(x) => {
-                error!(
-                    "[-] Failed to write '{}'. {}",
-                    constants::SHIELDING_KEY_FILE,
-                    x
-                );
-            }
-            _ => {
-                println!(
-                    "[+] File '{}' written successfully",
-                    constants::SHIELDING_KEY_FILE
-                );
-            }
-        }
+        write_shielding_key(enclave.geteid());
         return;
     } else if matches.is_present("signing-key") {
         info!("*** Get the signing key from the TEE\n");
@@ -177,6 +199,222 @@ fn main() {
                 init_shard(&shard);
             }
         };
+    } else if let Some(_matches) = matches.subcommand_matches("rotate-state-key") {
+        println!("*** Rotating the state key of all shards");
+        let enclave = enclave_init().unwrap();
+        enclave_rotate_state_key(enclave.geteid()).unwrap();
+        println!("[+] State key rotated");
+
+        if _matches.is_present("serve") {
+            // the operators of the peers fetch the new key with `request-keys`
+            let ra_url = format!("{}:{}", w_ip, mu_ra_port);
+            println!("*** Serving the new key on {}, stop with Ctrl-C", ra_url);
+            enclave_run_key_provisioning_server(
+                enclave.geteid(),
+                sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
+                &ra_url,
+                StateTransfer::default(),
+            );
+        }
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("request-keys") {
+        let peer = _matches.value_of("peer").unwrap();
+        let shards = local_shards();
+        println!(
+            "*** Fetching the keys of {} shard(s) from {}",
+            shards.len(),
+            peer
+        );
+        let enclave = enclave_init().unwrap();
+        match enclave_request_key_provisioning(
+            enclave.geteid(),
+            sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
+            peer,
+            &shards,
+            StateTransfer::default(),
+        ) {
+            Ok(provisioned) => println!("[+] Fetched {} shard(s)", provisioned.len()),
+            Err(e) => error!("[-] Key provisioning failed: {}", e.as_str()),
+        }
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("rotate-shielding-key") {
+        let grace_period = match _matches.value_of("grace-period") {
+            Some(secs) => secs
+                .parse()
+                .expect("grace period must be a number of seconds"),
+            None => constants::DEFAULT_SHIELDING_KEY_GRACE_PERIOD,
+        };
+        println!("*** Rotating the shielding keys");
+        let enclave = enclave_init().unwrap();
+        let eid = enclave.geteid();
+        let rotation = enclave_rotate_shielding_key(eid, grace_period).unwrap();
+        println!(
+            "[+] Shielding keys rotated. The old keys are accepted until {}",
+            rotation.rotation.grace_period_end
+        );
+        fs::write(constants::SHIELDING_KEY_ROTATION_FILE, rotation.encode()).unwrap();
+        println!(
+            "[+] Signed rotation statement written to '{}'",
+            constants::SHIELDING_KEY_ROTATION_FILE
+        );
+        write_shielding_key(eid);
+
+        // re-register such that the attestation report on chain commits to the new keys
+        let api = Api::new(format!("ws://{}", n_url)).set_signer(AccountKeyring::Alice.pair());
+        register_enclave(&api, eid, &format!("{}:{}", w_ip, w_port));
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("set-attestation-policy") {
+        let accepted_statuses = _matches
+            .values_of("accept-status")
+            .map(|values| {
+                values
+                    .flat_map(|s| QuoteStatus::from_ias(s).unwrap())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let policy = AttestationPolicy {
+            accepted_statuses,
+            max_report_age: _matches
+                .value_of("max-report-age")
+                .map(|secs| {
+                    secs.parse()
+                        .expect("report age must be a number of seconds")
+                })
+                .unwrap_or(DEFAULT_MAX_REPORT_AGE),
+            allow_debug: _matches.is_present("allow-debug"),
+        };
+        let version: u64 = _matches
+            .value_of("policy-version")
+            .unwrap()
+            .parse()
+            .expect("policy version must be a number");
+        let enclave = enclave_init().unwrap();
+        let authorization = match _matches.value_of("authorization") {
+            Some(sig) => hex::decode(sig.trim_start_matches("0x"))
+                .expect("authorization must be a hex encoded signature"),
+            None => {
+                let mut own = [0u8; 32];
+                own.copy_from_slice(&mrenclave(enclave.geteid()).unwrap());
+                let payload = (
+                    constants::ATTESTATION_POLICY_CONTEXT.to_vec(),
+                    own,
+                    version,
+                    &policy,
+                )
+                    .encode();
+                println!(
+                    "[!] The policy must be authorized by the operator. Sign this payload with the \
+                     operator key and pass the signature with --authorization:\n0x{}",
+                    hex::encode(payload)
+                );
+                enclave.destroy();
+                return;
+            }
+        };
+        enclave_set_attestation_policy(enclave.geteid(), version, &policy, &authorization).unwrap();
+        println!(
+            "[+] Attestation policy version {} set: {:?}",
+            version, policy
+        );
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("set-provisioning-policy") {
+        let measurements = |name: &str| -> Vec<[u8; 32]> {
+            _matches
+                .values_of(name)
+                .map(|values| values.map(decode_measurement).collect())
+                .unwrap_or_default()
+        };
+        let version: u64 = _matches
+            .value_of("policy-version")
+            .unwrap()
+            .parse()
+            .expect("policy version must be a number");
+        let mr_enclaves = measurements("mrenclave");
+        let mr_signers = measurements("mrsigner");
+        let enclave = enclave_init().unwrap();
+        let authorization = match _matches.value_of("authorization") {
+            Some(sig) => hex::decode(sig.trim_start_matches("0x"))
+                .expect("authorization must be a hex encoded signature"),
+            None => {
+                let mut own = [0u8; 32];
+                own.copy_from_slice(&mrenclave(enclave.geteid()).unwrap());
+                let payload = (
+                    constants::PROVISIONING_POLICY_CONTEXT.to_vec(),
+                    own,
+                    (version, &mr_enclaves, &mr_signers),
+                )
+                    .encode();
+                println!(
+                    "[!] The policy must be authorized by the operator. Sign this payload with the \
+                     operator key and pass the signature with --authorization:\n0x{}",
+                    hex::encode(payload)
+                );
+                enclave.destroy();
+                return;
+            }
+        };
+        enclave_set_provisioning_policy(
+            enclave.geteid(),
+            version,
+            &mr_enclaves,
+            &mr_signers,
+            &authorization,
+        )
+        .unwrap();
+        println!(
+            "[+] Provisioning policy version {} allows our MRENCLAVE, {} other MRENCLAVE(s) and {} MRSIGNER(s)",
+            version,
+            mr_enclaves.len(),
+            mr_signers.len()
+        );
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("export-shard") {
+        let shard = get_shard(_matches);
+        let target = _matches.value_of("target").unwrap();
+        let out = _matches
+            .value_of("out")
+            .unwrap_or(constants::SHARD_BUNDLE_FILE);
+        println!(
+            "*** Exporting shard {} for worker {}",
+            shard.encode().to_base58(),
+            target
+        );
+        let credentials = registered_credentials(&n_url, target);
+        let enclave = enclave_init().unwrap();
+        enclave_export_shard(
+            enclave.geteid(),
+            shard.encode(),
+            credentials.encode(),
+            sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
+            out,
+        )
+        .unwrap();
+        println!("[+] Bundle '{}' written successfully", out);
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("import-shard") {
+        let bundle = _matches.value_of("bundle").unwrap();
+        println!("*** Importing shard bundle '{}'", bundle);
+        ensure_bundle_signer_registered(&n_url, bundle);
+        let enclave = enclave_init().unwrap();
+        enclave_import_shard(enclave.geteid(), bundle).unwrap();
+        println!("[+] Shard imported successfully");
+        enclave.destroy();
+    } else if let Some(_matches) = matches.subcommand_matches("state-history") {
+        let shard = get_shard(_matches);
+        match _matches.value_of("restore") {
+            Some(block) => restore_snapshot(
+                &n_url,
+                &shard,
+                block.parse().expect("block number must be a number"),
+                _matches.value_of("authorization"),
+            ),
+            None => {
+                println!("snapshots of shard {}:", shard.encode().to_base58());
+                for s in history::list(&shard) {
+                    println!("{:>10}  0x{}", s.block_number, hex::encode(&s.state_hash));
+                }
+            }
+        }
     } else if let Some(_matches) = matches.subcommand_matches("test") {
         if _matches.is_present("provisioning-server") {
             println!("*** Running Enclave MU-RA TLS server\n");
@@ -185,16 +423,20 @@ fn main() {
                 enclave.geteid(),
                 sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
                 &format!("localhost:{}", mu_ra_port),
+                StateTransfer::default(),
             );
             println!("[+] Done!");
             enclave.destroy();
         } else if _matches.is_present("provisioning-client") {
             println!("*** Running Enclave MU-RA TLS server\n");
             let enclave = enclave_init().unwrap();
+            let shard = ShardIdentifier::from_slice(&mrenclave(enclave.geteid()).unwrap()[..]);
             enclave_request_key_provisioning(
                 enclave.geteid(),
                 sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
                 &format!("localhost:{}", mu_ra_port),
+                &[shard],
+                StateTransfer::default(),
             )
             .unwrap();
             println!("[+] Done!");
@@ -207,7 +449,69 @@ fn main() {
     }
 }
 
-fn worker(node_url: &str, w_ip: &str, w_port: &str, mu_ra_port: &str, shard: &ShardIdentifier) {
+fn write_shielding_key(eid: sgx_enclave_id_t) {
+    let pubkey = enclave_shielding_key(eid).unwrap();
+    let file = File::create(constants::SHIELDING_KEY_FILE).unwrap();
+    match serde_json::to_writer(file, &pubkey) {
+        Err(x) => {
+            error!(
+                "[-] Failed to write '{}'. {}",
+                constants::SHIELDING_KEY_FILE,
+                x
+            );
+        }
+        _ => {
+            println!(
+                "[+] File '{}' written successfully",
+                constants::SHIELDING_KEY_FILE
+            );
+        }
+    }
+}
+
+fn decode_measurement(value: &str) -> [u8; 32] {
+    let bytes = value
+        .from_base58()
+        .expect("measurement has to be base58 encoded");
+    if bytes.len() != 32 {
+        panic!("measurement must be 32 bytes");
+    }
+    let mut measurement = [0u8; 32];
+    measurement.copy_from_slice(&bytes);
+    measurement
+}
+
+fn get_shard(matches: &ArgMatches<'_>) -> ShardIdentifier {
+    match matches.value_of("shard") {
+        Some(value) => {
+            let shard_vec = value.from_base58().unwrap();
+            let mut shard = [0u8; 32];
+            shard.copy_from_slice(&shard_vec[..]);
+            shard.into()
+        }
+        _ => {
+            let enclave = enclave_init().unwrap();
+            let mrenclave = mrenclave(enclave.geteid()).unwrap();
+            info!(
+                "no shard specified. using mrenclave as id: {}",
+                mrenclave.to_base58()
+            );
+            ShardIdentifier::from_slice(&mrenclave[..])
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn worker(
+    node_url: &str,
+    w_ip: &str,
+    w_port: &str,
+    mu_ra_port: &str,
+    shard: &ShardIdentifier,
+    history_size: usize,
+    schedule: reattestation::Schedule,
+    state_transfer: StateTransfer,
+) {
     info!("starting worker on shard {}", shard.encode().to_base58());
     // ------------------------------------------------------------------------
     // check for required files
@@ -226,55 +530,20 @@ fn worker(node_url: &str, w_ip: &str, w_port: &str, mu_ra_port: &str, shard: &Sh
     let w_url = format!("{}:{}", w_ip, w_port);
     start_ws_server(eid, w_url.clone(), mu_ra_port.to_string());
 
-    // ------------------------------------------------------------------------
-    // let new workers call us for key provisioning
-    let eid = enclave.geteid();
-    let ra_url = format!("{}:{}", w_ip, mu_ra_port);
-    thread::spawn(move || {
-        enclave_run_key_provisioning_server(
-            eid,
-            sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
-            &ra_url,
-        )
-    });
-
     // ------------------------------------------------------------------------
     // start the substrate-api-client to communicate with the node
     let api = Api::new(format!("ws://{}", node_url)).set_signer(AccountKeyring::Alice.pair());
-    let genesis_hash = api.genesis_hash.as_bytes().to_vec();
-
     let tee_account_id = get_enclave_signing_key(eid);
-    ensure_account_has_funds(&api, &tee_account_id);
-
-    // ------------------------------------------------------------------------
-    // perform a remote attestation and get an unchecked extrinsic back
-
-    // get enclaves's account nonce
-    let nonce = hexstr_to_u256(
-        api.get_storage("System", "AccountNonce", Some(tee_account_id.encode()))
-            .unwrap(),
-    )
-    .unwrap()
-    .low_u32();
-    info!("Enclave nonce = {:?}", nonce);
-
-    let uxt = enclave_perform_ra(eid, genesis_hash, nonce, w_url.as_bytes().to_vec()).unwrap();
-    let ue = UncheckedExtrinsic::decode(&mut uxt.as_slice()).unwrap();
-    let mut _xthex = hex::encode(ue.encode());
-    _xthex.insert_str(0, "0x");
 
-    // send the extrinsic and wait for confirmation
-    println!("[>] Register the enclave (send the extrinsic)");
-    let tx_hash = api.send_extrinsic(_xthex).unwrap();
-    println!("[<] Extrinsic got finalized. Hash: {:?}\n", tx_hash);
-
-    // browse enclave registry
+    // browse enclave registry. Provisioning replaces our keys, so we can only attest and
+    // register ourselves afterwards
     match get_worker_for_shard(&api, shard) {
         Some(w) => {
             let master_worker = get_worker_info(&api, w);
             if master_worker.pubkey == tee_account_id {
                 info!("the most recently active worker is myself");
                 ensure_shard_initialized(shard);
+                report_state_version(&api, shard);
             } else {
                 let _url = String::from_utf8_lossy(&master_worker.url[..]).to_string();
                 let _w_api = WorkerApi::new(_url.clone());
@@ -282,12 +551,20 @@ fn worker(node_url: &str, w_ip: &str, w_port: &str, mu_ra_port: &str, shard: &Sh
                 let mura_url = format!("{}:{}", _url_split[0], _w_api.get_mu_ra_port().unwrap());
 
                 info!("Requesting key provisioning from worker at {}", mura_url);
-                enclave_request_key_provisioning(
+                let provisioned = enclave_request_key_provisioning(
                     eid,
                     sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
                     &mura_url,
+                    &[*shard],
+                    state_transfer,
                 )
                 .unwrap();
+                for (shard, block_number) in provisioned {
+                    // the peer's history can't tell the block of states it never snapshotted
+                    if block_number > 0 {
+                        history::snapshot(&shard, block_number, history_size);
+                    }
+                }
                 debug!("key provisioning successfully performed");
             }
         }
@@ -299,6 +576,21 @@ fn worker(node_url: &str, w_ip: &str, w_port: &str, mu_ra_port: &str, shard: &Sh
             ensure_shard_initialized(shard);
         }
     }
+    register_enclave(&api, eid, &w_url);
+    reattestation::start(eid, node_url.to_string(), w_url, schedule);
+
+    // ------------------------------------------------------------------------
+    // let new workers call us for key provisioning
+    let eid = enclave.geteid();
+    let ra_url = format!("{}:{}", w_ip, mu_ra_port);
+    thread::spawn(move || {
+        enclave_run_key_provisioning_server(
+            eid,
+            sgx_quote_sign_type_t::SGX_UNLINKABLE_SIGNATURE,
+            &ra_url,
+            state_transfer,
+        )
+    });
 
     // ------------------------------------------------------------------------
     // subscribe to events and react on firing
@@ -314,16 +606,75 @@ fn worker(node_url: &str, w_ip: &str, w_port: &str, mu_ra_port: &str, shard: &Sh
 
     println!("[+] Subscribed to events. waiting...");
 
+    // the subscription keeps the first client busy
+    let events_api = Api::<sr25519::Pair>::new(format!("ws://{}", node_url));
+    let mut unconfirmed = history::Unconfirmed::new(history_size);
     loop {
         let msg = receiver.recv().unwrap();
         if let Ok(events) = parse_events(msg.clone()) {
-            handle_events(eid, node_url, events, sender.clone())
+            handle_events(
+                eid,
+                &events_api,
+                node_url,
+                events,
+                sender.clone(),
+                &mut unconfirmed,
+            )
         } else {
             println!("[-] Unable to parse received message!")
         }
     }
 }
 
+/// Performs a remote attestation and registers the enclave with its current keys on chain
+fn register_enclave(api: &Api<sr25519::Pair>, eid: sgx_enclave_id_t, w_url: &str) {
+    try_register_enclave(api, eid, w_url).unwrap();
+}
+
+/// Registers the enclave with its RA certificate, which the enclave takes from its cache unless
+/// it is outdated or its keys changed. Only reading the nonce, signing and sending hold
+/// `ENCLAVE_NONCE_LOCK`, as long as the certificate is cached.
+fn try_register_enclave(
+    api: &Api<sr25519::Pair>,
+    eid: sgx_enclave_id_t,
+    w_url: &str,
+) -> Result<(), String> {
+    let genesis_hash = api.genesis_hash.as_bytes().to_vec();
+
+    let tee_account_id = get_enclave_signing_key(eid);
+    ensure_account_has_funds(api, &tee_account_id);
+
+    // ------------------------------------------------------------------------
+    // perform a remote attestation and get an unchecked extrinsic back
+
+    let _nonce = ENCLAVE_NONCE_LOCK.lock().unwrap();
+    // get enclaves's account nonce
+    let nonce = hexstr_to_u256(
+        api.get_storage("System", "AccountNonce", Some(tee_account_id.encode()))
+            .unwrap(),
+    )
+    .unwrap()
+    .low_u32();
+    info!("Enclave nonce = {:?}", nonce);
+
+    let uxt = enclave_perform_ra(eid, genesis_hash, nonce, w_url.as_bytes().to_vec())
+        .map_err(|status| format!("attestation failed: {}", status))?;
+    let ue = UncheckedExtrinsic::decode(&mut uxt.as_slice()).unwrap();
+    let mut _xthex = hex::encode(ue.encode());
+    _xthex.insert_str(0, "0x");
+    send_registration(api, _xthex)
+}
+
+fn send_registration(api: &Api<sr25519::Pair>, xthex: String) -> Result<(), String> {
+    // send the extrinsic and wait for confirmation
+    println!("[>] Register the enclave (send the extrinsic)");
+    let tx_hash = api
+        .send_extrinsic(xthex)
+        .map_err(|e| format!("sending the registration failed: {:?}", e))?;
+    println!("[<] Extrinsic got finalized. Hash: {:?}\n", tx_hash);
+    Ok(())
+}
+
 type Events = Vec<system::EventRecord<Event, Hash>>;
 
 fn parse_events(event: String) -> Result<Events, String> {
@@ -332,7 +683,14 @@ fn parse_events(event: String) -> Result<Events, String> {
     Events::decode(&mut _er_enc).map_err(|_| "Decoding Events Failed".to_string())
 }
 
-fn handle_events(eid: u64, node_url: &str, events: Events, _sender: Sender<String>) {
+fn handle_events(
+    eid: u64,
+    api: &Api<sr25519::Pair>,
+    node_url: &str,
+    events: Events,
+    _sender: Sender<String>,
+    unconfirmed: &mut history::Unconfirmed,
+) {
     for evr in &events {
         debug!("Decoded: phase = {:?}, event = {:?}", evr.phase, evr.event);
         match &evr.event {
@@ -375,6 +733,7 @@ fn handle_events(eid: u64, node_url: &str, events: Events, _sender: Sender<Strin
                             hex::encode(request.cyphertext.clone())
                         );
                         process_request(eid, request.clone(), node_url);
+                        unconfirmed.push(&request.shard);
                     }
                     my_node_runtime::substratee_registry::RawEvent::CallConfirmed(
                         sender,
@@ -385,6 +744,19 @@ fn handle_events(eid: u64, node_url: &str, events: Events, _sender: Sender<Strin
                         debug!("    Payload: {:?}", hex::encode(payload));
                         println!();
                     }
+                    my_node_runtime::substratee_registry::RawEvent::UpdatedIpfsHash(
+                        shard,
+                        worker_index,
+                        state_hash,
+                    ) => {
+                        println!("[+] Received UpdatedIpfsHash event");
+                        debug!("    Shard:        {}", shard.encode().to_base58());
+                        debug!("    Worker index: {}", worker_index);
+                        debug!("    State hash:   {}", hex::encode(state_hash));
+                        println!();
+                        // events are delivered as their block is imported
+                        unconfirmed.confirm(shard, state_hash, get_block_number(api));
+                    }
                     _ => {
                         info!("Ignoring unsupported substratee_registry event");
                     }
@@ -412,6 +784,7 @@ pub fn process_request(eid: sgx_enclave_id_t, request: Request, node_url: &str)
         tee_accountid.to_ss58check()
     );
 
+    let _nonce = ENCLAVE_NONCE_LOCK.lock().unwrap();
     let result_str = _api
         .get_storage("System", "AccountNonce", Some(tee_accountid.encode()))
         .unwrap();
@@ -438,6 +811,142 @@ pub fn process_request(eid: sgx_enclave_id_t, request: Request, node_url: &str)
     debug!("[<] Request Extrinsic got finalized");
 }
 
+/// The first registry entry that `is_wanted`
+fn find_registered_enclave<F>(node_url: &str, is_wanted: F) -> Option<Enclave<AccountId, Vec<u8>>>
+where
+    F: Fn(&Enclave<AccountId, Vec<u8>>) -> bool,
+{
+    let api = Api::new(format!("ws://{}", node_url)).set_signer(AccountKeyring::Alice.pair());
+    (1..=get_worker_amount(&api))
+        .map(|index| get_worker_info(&api, index))
+        .find(is_wanted)
+}
+
+/// The keys and the RA certificate of the worker registered at `worker_url`. Its enclave checks
+/// the attestation and that the certificate binds these keys, the registry entry tells the chain
+/// signer.
+fn registered_credentials(node_url: &str, worker_url: &str) -> EnclaveCredentials {
+    let registered = find_registered_enclave(node_url, |e| e.url == worker_url.as_bytes())
+        .unwrap_or_else(|| panic!("worker {} is not registered on chain", worker_url));
+    let signer: &[u8; 32] = registered.pubkey.as_ref();
+
+    let w_api = WorkerApi::new(worker_url.to_string());
+    let rsa_pubkey = w_api
+        .get_rsa_pubkey()
+        .expect("could not get the RSA shielding key of the target");
+    EnclaveCredentials {
+        ra_cert: w_api
+            .get_ra_cert()
+            .expect("could not get the RA certificate of the target"),
+        signer: ed25519::Public::from_raw(*signer),
+        rsa_pubkey_json: serde_json::to_vec(&rsa_pubkey).unwrap(),
+        x25519_pubkey: w_api
+            .get_x25519_pubkey()
+            .expect("could not get the X25519 shielding key of the target"),
+    }
+}
+
+/// Refuses bundles that haven't been signed by an enclave registered on chain. The enclave checks
+/// the attestation of the signer itself.
+fn ensure_bundle_signer_registered(node_url: &str, path: &str) {
+    let bytes = fs::read(path).unwrap_or_else(|e| panic!("could not read '{}': {}", path, e));
+    let bundle = ShardBundle::decode(&mut bytes.as_slice())
+        .unwrap_or_else(|_| panic!("'{}' is not a shard bundle", path));
+    let signer = bundle.signer.signer.0;
+    let registered = find_registered_enclave(node_url, |e| {
+        AsRef::<[u8; 32]>::as_ref(&e.pubkey) == &signer
+    });
+    if registered.is_none() {
+        panic!(
+            "bundle '{}' has not been signed by a registered enclave",
+            path
+        );
+    }
+}
+
+/// Restores the latest snapshot of `shard` taken at or before `block_number` and confirms the
+/// restored state on chain.
+///
+/// The enclave requires the operator's signature of the restore. Without `authorization`, this
+/// prints the payload to sign instead.
+fn restore_snapshot(
+    node_url: &str,
+    shard: &ShardIdentifier,
+    block_number: u64,
+    authorization: Option<&str>,
+) {
+    let snapshot = history::find(shard, block_number).unwrap_or_else(|| {
+        panic!(
+            "no snapshot of shard {} at or before block {}",
+            shard.encode().to_base58(),
+            block_number
+        )
+    });
+    println!(
+        "*** Restoring snapshot of block {} with state hash 0x{}",
+        snapshot.block_number,
+        hex::encode(&snapshot.state_hash)
+    );
+    let authorization = match authorization {
+        Some(sig) => hex::decode(sig.trim_start_matches("0x"))
+            .expect("authorization must be a hex encoded signature"),
+        None => {
+            let mut state_hash = [0u8; 32];
+            state_hash.copy_from_slice(&snapshot.state_hash);
+            let payload = (constants::RESTORE_STATE_CONTEXT.to_vec(), shard, state_hash).encode();
+            println!(
+                "[!] The restore must be authorized by the operator. Sign this payload with the \
+                 operator key and pass the signature with --authorization:\n0x{}",
+                hex::encode(payload)
+            );
+            return;
+        }
+    };
+
+    let enclave = enclave_init().unwrap();
+    let eid = enclave.geteid();
+    let api = Api::new(format!("ws://{}", node_url)).set_signer(AccountKeyring::Alice.pair());
+    let tee_account_id = get_enclave_signing_key(eid);
+    let nonce = hexstr_to_u256(
+        api.get_storage("System", "AccountNonce", Some(tee_account_id.encode()))
+            .unwrap(),
+    )
+    .unwrap()
+    .low_u32();
+
+    let uxt = enclave_restore_state(
+        eid,
+        shard.encode(),
+        fs::read(&snapshot.path).unwrap(),
+        authorization,
+        api.genesis_hash.as_bytes().to_vec(),
+        nonce,
+    )
+    .unwrap();
+    let ue = UncheckedExtrinsic::decode(&mut uxt.as_slice()).unwrap();
+    let mut _xthex = hex::encode(ue.encode());
+    _xthex.insert_str(0, "0x");
+
+    println!("[>] Confirm the restored state (send the extrinsic)");
+    let tx_hash = api.send_extrinsic(_xthex).unwrap();
+    println!("[<] Extrinsic got finalized. Hash: {:?}\n", tx_hash);
+    enclave.destroy();
+}
+
+/// All shards that have been initialized on this worker
+pub fn local_shards() -> Vec<ShardIdentifier> {
+    let entries = match fs::read_dir(constants::SHARDS_PATH) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| name.from_base58().ok())
+        .filter(|shard| shard.len() == 32)
+        .map(|shard| ShardIdentifier::from_slice(&shard[..]))
+        .collect()
+}
+
 fn init_shard(shard: &ShardIdentifier) {
     let path = format!("{}/{}", constants::SHARDS_PATH, shard.encode().to_base58());
     println!("initializing shard at {}", path);
@@ -525,6 +1034,45 @@ fn ensure_shard_initialized(shard: &ShardIdentifier) {
     debug!("state file is present for shard {}", shardenc);
 }
 
+/// Tells the operator which local version of the state matches the hash of the latest
+/// `CallConfirmed`. The enclave falls back to the previous version if the current one is corrupt.
+fn report_state_version(api: &Api<sr25519::Pair>, shard: &ShardIdentifier) {
+    let confirmed = match get_latest_state_hash(api, shard) {
+        Some(h) => h,
+        None => return,
+    };
+    info!("latest confirmed state hash: 0x{}", hex::encode(&confirmed));
+
+    let path = format!(
+        "{}/{}/{}",
+        constants::SHARDS_PATH,
+        shard.encode().to_base58(),
+        constants::ENCRYPTED_STATE_FILE
+    );
+    let prev = format!("{}{}", path, constants::PREVIOUS_STATE_FILE_SUFFIX);
+    let hash_of = |p: &str| {
+        fs::read(p)
+            .ok()
+            .map(|bytes| Sha256::digest(&bytes).to_vec())
+    };
+
+    if hash_of(&path) == Some(confirmed.clone()) {
+        println!("[+] State '{}' matches the latest CallConfirmed", path);
+    } else if hash_of(&prev) == Some(confirmed) {
+        println!(
+            "[!] State '{}' does not match the latest CallConfirmed, but its previous version '{}' does. \
+             The enclave will recover the previous version if it is the one it recorded latest",
+            path, prev
+        );
+    } else {
+        println!(
+            "[!] Neither '{}' nor '{}' matches the latest CallConfirmed. \
+             The state is either ahead of the chain or outdated",
+            path, prev
+        );
+    }
+}
+
 pub fn check_files() {
     debug!("*** Check files");
     let files = vec![
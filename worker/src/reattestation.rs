@@ -0,0 +1,108 @@
+/*
+    Copyright 2019 Supercomputing Systems AG
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+
+*/
+
+//! Keeps the attestation of the enclave fresh while the worker runs.
+//!
+//! The enclave is attested anew every `Schedule::interval`, which replaces the cached RA
+//! certificate and key used for MU-RA and the certificate served to clients. The on-chain
+//! registration is renewed with the fresh report when the IAS quote status changed, e.g. after a
+//! TCB recovery, or once it is older than `Schedule::renewal`. Whether it is due is read from the
+//! fresh certificate, and the registration reuses it instead of attesting again.
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use keyring::AccountKeyring;
+use log::*;
+use sgx_types::*;
+use substrate_api_client::Api;
+
+use crate::constants::RA_CERT_FILE;
+use crate::enclave::api::enclave_refresh_attestation;
+use crate::try_register_enclave;
+
+/// How often a due registration is attempted before waiting for the next re-attestation.
+const REGISTRATION_ATTEMPTS: u32 = 3;
+const REGISTRATION_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+pub struct Schedule {
+    pub interval: Duration,
+    pub renewal: Duration,
+}
+
+/// Re-attests in the background, after the enclave has been registered once.
+pub fn start(eid: sgx_enclave_id_t, node_url: String, w_url: String, schedule: Schedule) {
+    if schedule.interval == Duration::from_secs(0) {
+        info!("re-attestation is disabled");
+        return;
+    }
+    thread::Builder::new()
+        .name("reattestation".to_owned())
+        .spawn(move || {
+            let api =
+                Api::new(format!("ws://{}", node_url)).set_signer(AccountKeyring::Alice.pair());
+            let mut registered_status = quote_status();
+            let mut registered_at = Instant::now();
+            loop {
+                thread::sleep(schedule.interval);
+                if let Err(status) = enclave_refresh_attestation(eid) {
+                    error!("re-attestation failed, retrying later: {}", status);
+                    continue;
+                }
+                let status = quote_status();
+                if status != registered_status {
+                    info!(
+                        "quote status changed from {:?} to {:?}",
+                        registered_status, status
+                    );
+                } else if registered_at.elapsed() < schedule.renewal {
+                    debug!("registration is recent and the quote status unchanged");
+                    continue;
+                }
+                for attempt in 1..=REGISTRATION_ATTEMPTS {
+                    match try_register_enclave(&api, eid, &w_url) {
+                        Ok(()) => {
+                            registered_status = status;
+                            registered_at = Instant::now();
+                            break;
+                        }
+                        Err(e) => {
+                            error!(
+                                "renewing the registration failed (attempt {}/{}): {}",
+                                attempt, REGISTRATION_ATTEMPTS, e
+                            );
+                            if attempt < REGISTRATION_ATTEMPTS {
+                                thread::sleep(REGISTRATION_RETRY_DELAY);
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .unwrap();
+}
+
+/// The quote status of the RA certificate the enclave wrote last.
+fn quote_status() -> Option<String> {
+    let cert = fs::read(RA_CERT_FILE)
+        .map_err(|e| error!("could not read '{}': {}", RA_CERT_FILE, e))
+        .ok()?;
+    substratee_ra_verify::quote_status(&cert)
+        .map_err(|e| error!("could not read the quote status: {:?}", e))
+        .ok()?
+}
@@ -30,7 +30,7 @@ use crate::{ensure_account_has_funds, get_enclave_signing_key};
 use substrate_api_client::utils::hexstr_to_u256;
 use substrate_api_client::Api;
 use substratee_stf::{
-    ShardIdentifier, TrustedCall, TrustedCallSigned, TrustedGetter, TrustedGetterSigned,
+    envelope, ShardIdentifier, TrustedCall, TrustedCallSigned, TrustedGetter, TrustedGetterSigned,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -88,7 +88,7 @@ pub fn test_trusted_call_signed(
 
 pub fn test_trusted_getter_signed(who: AccountKeyring) -> TrustedGetterSigned {
     let getter = TrustedGetter::free_balance(who.public());
-    getter.sign(&who.pair())
+    getter.sign(&who.pair(), envelope::x25519_public(&[1u8; 32]))
 }
 
 pub fn setup(eid: sgx_enclave_id_t, who: AccountKeyring) -> (Api<sr25519::Pair>, u32) {
@@ -15,6 +15,7 @@
 
 */
 
+use std::fs;
 use std::str;
 use std::thread;
 
@@ -27,7 +28,10 @@ use substratee_stf::ShardIdentifier;
 use substratee_worker_api::requests::*;
 use ws::{listen, CloseCode, Handler, Message, Result, Sender};
 
-use crate::enclave::api::{enclave_query_state, enclave_shielding_key};
+use crate::constants::{RA_CERT_FILE, SHIELDING_KEY_ROTATION_FILE};
+use crate::enclave::api::{
+    enclave_query_state, enclave_shielding_key, enclave_x25519_shielding_key,
+};
 
 pub fn start_ws_server(eid: sgx_enclave_id_t, addr: String, mu_ra_port: String) {
     // Server WebSocket handler
@@ -46,6 +50,9 @@ pub fn start_ws_server(eid: sgx_enclave_id_t, addr: String, mu_ra_port: String)
 
             let answer = match args[0] {
                 MSG_GET_PUB_KEY_WORKER => get_worker_pub_key(self.eid),
+                MSG_GET_X25519_PUB_KEY_WORKER => get_worker_x25519_pub_key(self.eid),
+                MSG_GET_SHIELDING_KEY_ROTATION => get_shielding_key_rotation(),
+                MSG_GET_RA_CERT => get_ra_cert(),
                 MSG_GET_MU_RA_PORT => Message::text(self.mu_ra_port.clone()),
                 MSG_GET_STF_STATE => handle_get_stf_state_msg(self.eid, args[1], args[2]),
                 _ => Message::text("[WS Server]: unrecognized msg pattern"),
@@ -96,3 +103,34 @@ fn get_worker_pub_key(eid: sgx_enclave_id_t) -> Message {
     let rsa_pubkey_json = serde_json::to_string(&rsa_pubkey).unwrap();
     Message::text(rsa_pubkey_json)
 }
+
+fn get_worker_x25519_pub_key(eid: sgx_enclave_id_t) -> Message {
+    match enclave_x25519_shielding_key(eid) {
+        Ok(pubkey) => {
+            debug!("     [WS Server] X25519 pubkey {:?}\n", pubkey);
+            Message::text(hex::encode(pubkey))
+        }
+        Err(e) => {
+            error!("could not get X25519 pubkey: {}", e.as_str());
+            Message::text(e.as_str())
+        }
+    }
+}
+
+fn get_shielding_key_rotation() -> Message {
+    match fs::read(SHIELDING_KEY_ROTATION_FILE) {
+        Ok(rotation) => Message::text(hex::encode(rotation)),
+        // no rotation so far
+        Err(_) => Message::text(""),
+    }
+}
+
+fn get_ra_cert() -> Message {
+    match fs::read(RA_CERT_FILE) {
+        Ok(cert) => Message::text(hex::encode(cert)),
+        Err(e) => {
+            error!("could not read '{}': {}", RA_CERT_FILE, e);
+            Message::text("")
+        }
+    }
+}
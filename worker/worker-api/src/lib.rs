@@ -22,11 +22,13 @@ use sgx_crypto_helper::rsa3072::Rsa3072PubKey;
 
 use codec::{Decode, Encode};
 use log::*;
+use rand::RngCore;
 use ws::connect;
 
 use client::WsClient;
 use requests::*;
-use substratee_stf::{ShardIdentifier, TrustedGetterSigned};
+use substratee_stf::envelope::{self, X25519Key};
+use substratee_stf::{ShardIdentifier, ShieldingKeyRotationSigned, TrustedGetterSigned};
 
 pub mod client;
 pub mod requests;
@@ -56,12 +58,65 @@ impl Api {
         Ok(rsa_pubkey)
     }
 
+    pub fn get_x25519_pubkey(&self) -> Result<[u8; 32], ()> {
+        let keystr = Self::get(&self, MSG_GET_X25519_PUB_KEY_WORKER)?;
+
+        let key_vec = hex::decode(&keystr).map_err(|_| ())?;
+        if key_vec.len() != 32 {
+            error!("[-] [WorkerApi]: invalid X25519 public key: {}", keystr);
+            return Err(());
+        }
+        let mut pubkey = [0u8; 32];
+        pubkey.copy_from_slice(&key_vec);
+        info!("[+] Got X25519 public key of enclave");
+        debug!("  enclave X25519 pubkey = {}", keystr);
+        Ok(pubkey)
+    }
+
+    /// The DER encoded RA certificate the worker's enclave registered with
+    pub fn get_ra_cert(&self) -> Result<Vec<u8>, ()> {
+        let res = Self::get(&self, MSG_GET_RA_CERT)?;
+        if res.is_empty() {
+            error!("[-] [WorkerApi]: worker has no RA certificate");
+            return Err(());
+        }
+        hex::decode(&res).map_err(|_| ())
+    }
+
+    /// The latest shielding key rotation of the worker's enclave, if any
+    pub fn get_shielding_key_rotation(&self) -> Result<Option<ShieldingKeyRotationSigned>, ()> {
+        let res = Self::get(&self, MSG_GET_SHIELDING_KEY_ROTATION)?;
+        if res.is_empty() {
+            return Ok(None);
+        }
+        let bytes = hex::decode(&res).map_err(|_| ())?;
+        let rotation = ShieldingKeyRotationSigned::decode(&mut bytes.as_slice()).map_err(|_| ())?;
+        if !rotation.verify_signature() {
+            error!("[-] [WorkerApi]: shielding key rotation has a bad signature");
+            return Err(());
+        }
+        Ok(Some(rotation))
+    }
+
+    /// Queries the state and decrypts the result with the secret belonging to the
+    /// `response_key` of `getter`. The getter itself is encrypted to the enclave's X25519
+    /// shielding key, so the worker host can't tell who queries what.
     pub fn get_stf_state(
         &self,
         getter: TrustedGetterSigned,
+        response_secret: &X25519Key,
         shard: &ShardIdentifier,
     ) -> Result<Vec<u8>, ()> {
-        let getter_str = hex::encode(getter.encode());
+        let shielding_key = self.get_x25519_pubkey()?;
+        let mut ephemeral_secret = [0u8; 32];
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut ephemeral_secret);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let getter_encrypted =
+            envelope::encrypt_x25519(&getter.encode(), &shielding_key, ephemeral_secret, nonce)
+                .map_err(|e| error!("[-] [WorkerApi]: could not encrypt getter: {:?}", e))?;
+
+        let getter_str = hex::encode(getter_encrypted);
         let shard_str = hex::encode(shard.encode());
         let request = format!("{}::{}::{}", MSG_GET_STF_STATE, getter_str, shard_str);
         match Self::get(&self, &request) {
@@ -70,7 +125,9 @@ impl Api {
                 let value_slice = hex::decode(&res).unwrap();
                 let value: Option<Vec<u8>> = Decode::decode(&mut &value_slice[..]).unwrap();
                 match value {
-                    Some(val) => Ok(val),
+                    Some(val) => envelope::decrypt_x25519(&val, response_secret).map_err(|e| {
+                        error!("[-] [WorkerApi]: could not decrypt getter result: {:?}", e);
+                    }),
                     None => Err(()),
                 }
             }
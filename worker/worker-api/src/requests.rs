@@ -16,5 +16,8 @@
 */
 
 pub const MSG_GET_PUB_KEY_WORKER: &str = "get_pub_key_worker";
+pub const MSG_GET_X25519_PUB_KEY_WORKER: &str = "get_x25519_pub_key_worker";
+pub const MSG_GET_RA_CERT: &str = "get_ra_cert";
+pub const MSG_GET_SHIELDING_KEY_ROTATION: &str = "get_shielding_key_rotation";
 pub const MSG_GET_MU_RA_PORT: &str = "get_mu_ra_port";
 pub const MSG_GET_STF_STATE: &str = "get_stf_state";